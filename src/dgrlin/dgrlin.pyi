@@ -0,0 +1,221 @@
+# Type stubs for the dgrlin extension module.
+# Keep in step with the #[pyfunction]s in src/lib.rs and the classes in src/python.rs;
+# python::tests::test_stubs_cover_module fails when something is missing here.
+
+from typing import Any, Callable, Iterator, Literal, TypedDict, final
+import asyncio
+
+
+class Diagnostic(TypedDict):
+    line: int
+    message: str
+
+class FileResult(TypedDict):
+    source: str
+    output: str
+    status: Literal["compiled", "decompiled", "skipped", "failed", "cancelled"]
+    errors: list[Diagnostic]
+    warnings: list[Diagnostic]
+
+class MarkupIssue(TypedDict):
+    severity: Literal["warning", "error"]
+    offset: int
+    message: str
+
+class PakEntry(TypedDict):
+    path: str
+    offset: int
+    size: int
+    kind: Literal["lin", "pak", "data"]
+
+class Overflow(TypedDict):
+    script: str
+    op_index: int
+    text_id: int
+    format: int
+    width: int
+    max_width: int
+    lines: int
+    max_lines: int
+    text: str
+
+class SourceMapping(TypedDict):
+    op_index: int
+    opcode: str
+    start: int
+    end: int
+    line: int
+    column: int
+
+class PatchHunk(TypedDict, total=False):
+    kind: Literal["ops", "text"]
+    start: int
+    end: int
+    ops: list[str]
+    op_index: int
+    old: str
+    new: str
+
+class PatchOverlap(TypedDict):
+    dropped_patch: str
+    dropped: PatchHunk
+    applied_patch: str
+    applied: PatchHunk
+
+Progress = Callable[[int, int, FileResult], object]
+
+
+class DgrlinError(RuntimeError):
+    file: str | None
+    offset: int | None
+    line: int | None
+    column: int | None
+
+class LinFormatError(DgrlinError): ...
+class TruncatedFileError(LinFormatError): ...
+
+class UnknownOpcodeError(LinFormatError):
+    opcode: int
+
+class ScriptSyntaxError(DgrlinError):
+    diagnostics: list[tuple[int, str]]
+
+
+@final
+class CancelToken:
+    def __init__(self) -> None: ...
+    def cancel(self) -> None: ...
+    @property
+    def cancelled(self) -> bool: ...
+
+
+@final
+class Instruction:
+    def __init__(self, name: str, args: bytes = ..., text_id: int | None = None) -> None: ...
+    @property
+    def name(self) -> str: ...
+    @property
+    def code(self) -> int: ...
+    @property
+    def args(self) -> bytes: ...
+    @args.setter
+    def args(self, args: bytes) -> None: ...
+    @property
+    def text_id(self) -> int | None: ...
+    @text_id.setter
+    def text_id(self, text_id: int) -> None: ...
+    def to_bytes(self) -> bytes: ...
+    def __eq__(self, other: object) -> bool: ...
+
+
+@final
+class StringTable:
+    def append(self, text: str) -> None: ...
+    def __len__(self) -> int: ...
+    def __getitem__(self, idx: int) -> str: ...
+    def __setitem__(self, idx: int, text: str) -> None: ...
+    def __iter__(self) -> Iterator[str]: ...
+    def __eq__(self, other: object) -> bool: ...
+
+
+@final
+class LinScript:
+    def __init__(self, instructions: list[Instruction] = ..., strings: list[str] = ...) -> None: ...
+    @staticmethod
+    def from_bytes(data: bytes) -> LinScript: ...
+    @staticmethod
+    def recover(data: bytes) -> tuple[LinScript, list[str]]: ...
+    @staticmethod
+    def open(filename: str) -> LinScript: ...
+    @staticmethod
+    def from_source(source: str) -> LinScript: ...
+    def to_bytes(self) -> bytes: ...
+    def to_source(self, annotate_speakers: bool = False) -> str: ...
+    @property
+    def instructions(self) -> list[Instruction]: ...
+    @instructions.setter
+    def instructions(self, instructions: list[Instruction]) -> None: ...
+    @property
+    def strings(self) -> StringTable: ...
+    @strings.setter
+    def strings(self, strings: list[str]) -> None: ...
+    def text_for(self, instruction: Instruction) -> str | None: ...
+    def append(self, instruction: Instruction) -> None: ...
+    def __len__(self) -> int: ...
+    def __getitem__(self, idx: int) -> Instruction: ...
+    def __setitem__(self, idx: int, instruction: Instruction) -> None: ...
+    def __delitem__(self, idx: int) -> None: ...
+    def __iter__(self) -> Iterator[Instruction]: ...
+    def __eq__(self, other: object) -> bool: ...
+
+
+def compile(filename: str, output_folder: str, source_map: bool = False) -> None: ...
+def decompile(
+    filename: str,
+    output_folder: str,
+    annotate_speakers: bool = False,
+    source_map: bool = False,
+    listing: bool = False,
+    lenient: bool = False,
+) -> None: ...
+def locate(filename: str, offset: int) -> SourceMapping | None: ...
+def lin_listing(filename: str) -> str: ...
+
+def compile_folder(
+    folder: str,
+    output_folder: str,
+    force: bool = False,
+    jobs: int | None = None,
+    progress: Progress | None = None,
+    cancel: CancelToken | None = None,
+) -> list[FileResult]: ...
+def compile_folder_async(
+    folder: str,
+    output_folder: str,
+    force: bool = False,
+    jobs: int | None = None,
+    progress: Progress | None = None,
+    cancel: CancelToken | None = None,
+) -> asyncio.Future[list[FileResult]]: ...
+def decompile_folder(
+    folder: str,
+    output_folder: str,
+    annotate_speakers: bool = False,
+    jobs: int | None = None,
+    progress: Progress | None = None,
+    cancel: CancelToken | None = None,
+) -> list[FileResult]: ...
+def decompile_folder_async(
+    folder: str,
+    output_folder: str,
+    annotate_speakers: bool = False,
+    jobs: int | None = None,
+    progress: Progress | None = None,
+    cancel: CancelToken | None = None,
+) -> asyncio.Future[list[FileResult]]: ...
+
+def list_pak(filename: str) -> list[PakEntry]: ...
+def extract_pak(filename: str, output_folder: str) -> list[str]: ...
+def compile_pak(folder: str, output_file: str) -> None: ...
+
+def export_json(filename: str, output_folder: str, styled_spans: bool = False) -> None: ...
+def export_cfg(filename: str, output_folder: str, format: Literal["dot", "mermaid"] = "dot") -> None: ...
+def export_call_graph(folder: str, output_folder: str) -> list[str]: ...
+def export_flag_report(folder: str, output_folder: str) -> None: ...
+def flag_report(folder: str) -> dict[str, Any]: ...
+def asset_report(path: str, manifest: str | None = None) -> dict[str, Any]: ...
+def simulate(
+    filename: str,
+    flags: dict[str, int] | None = None,
+    choices: list[int] | None = None,
+    character: int | None = None,
+    object: int | None = None,
+) -> dict[str, Any]: ...
+def check_overflow(path: str, config: str | None = None) -> list[Overflow]: ...
+def validate_markup(text: str) -> list[MarkupIssue]: ...
+def tokenize_markup(text: str) -> list[dict[str, Any]]: ...
+def format_script(filename: str, check: bool = False) -> bool: ...
+def diff_scripts(a: str, b: str) -> dict[str, Any]: ...
+def merge_scripts(base: str, ours: str, theirs: str, output: str | None = None) -> int: ...
+def create_patch(base: str, modded: str, output: str) -> None: ...
+def apply_patches(base: str, patches: list[str], output: str) -> list[PatchOverlap]: ...
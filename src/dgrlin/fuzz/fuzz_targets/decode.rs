@@ -0,0 +1,41 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use dgrlin::assets::asset_of;
+use dgrlin::cfg::ControlFlowGraph;
+use dgrlin::compiler::encode;
+use dgrlin::decompiler::{decode, decode_lenient, write_script, DecompileOptions};
+use dgrlin::flags::conditions;
+use dgrlin::listing::listing;
+use dgrlin::script::LinScript;
+use dgrlin::simulator::Simulator;
+
+// Any bytes, read as a .lin every way the tools read one. None of them may panic,
+// and whatever decodes has to come back the same after compiling it again.
+fuzz_target!(|data: &[u8]| {
+    let _ = listing(data);
+
+    // Damaged files get everywhere too, Raw bytes and all
+    analyse(&decode_lenient(data).script);
+
+    if let Ok(script) = decode(data) {
+        analyse(&script);
+        assert_eq!(decode(&encode(&script)).unwrap(), script);
+    }
+});
+
+
+// Everything that reads a decoded script
+fn analyse(script: &LinScript) {
+    let options = DecompileOptions { annotate_speakers: true, ..Default::default() };
+    let _ = write_script(&mut std::io::sink(), script, &options);
+    let _ = script.to_json_with(true);
+
+    let cfg = ControlFlowGraph::build(script);
+    let _ = (cfg.to_dot(script), cfg.to_mermaid(script));
+    let _ = Simulator::new(script).run();
+    for op in &script.ops {
+        let _ = (conditions(op), asset_of(op));
+    }
+}
@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use dgrlin::cfg::ControlFlowGraph;
+use dgrlin::compiler::{encode, markup_warnings, parse_with_diagnostics};
+use dgrlin::formatter::format_source;
+use dgrlin::lsp::diagnostics;
+use dgrlin::simulator::Simulator;
+
+// Any text, read as a .txt by the compiler and the language server. Errors are fine, panics aren't.
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let (parsed, _) = parse_with_diagnostics(source);
+    let _ = markup_warnings(&parsed);
+    let _ = encode(&parsed.script);
+    let _ = format_source(source);
+
+    // What's left of a script with errors in it is still shown in the editor
+    let cfg = ControlFlowGraph::build(&parsed.script);
+    let _ = cfg.to_dot(&parsed.script);
+    let _ = Simulator::new(&parsed.script).run();
+
+    let _ = diagnostics(source);
+});
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Seeds the fuzzing corpora from the sample scripts: the .lin's in src/data for `decode`,
+# and what they decompile to for `parse`. The inputs in regressions/ go in both.
+# Run after `cargo build` in src/dgrlin, then `cargo fuzz run decode` (or parse) from here.
+set -e
+cd "$(dirname "$0")"
+
+mkdir -p corpus/decode corpus/parse
+cp ../../data/*.lin regressions/* corpus/decode/
+cp regressions/* corpus/parse/
+
+for lin in corpus/decode/*.lin; do
+    ../target/debug/dgrlin decompile "$lin" corpus/parse --lenient
+done
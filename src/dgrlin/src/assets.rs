@@ -0,0 +1,270 @@
+use eyre::Context;
+use log;
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+use crate::opcode::Opcode;
+use crate::project::Project;
+
+
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetKind {
+    Sprite,
+    Bgm,
+    Sfx,
+    Voice,
+    Movie,
+    Background,
+    Map,
+}
+
+impl fmt::Display for AssetKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AssetKind::Sprite     => "sprite",
+            AssetKind::Bgm        => "bgm",
+            AssetKind::Sfx        => "sfx",
+            AssetKind::Voice      => "voice",
+            AssetKind::Movie      => "movie",
+            AssetKind::Background => "background",
+            AssetKind::Map        => "map",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AssetKind {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> eyre::Result<Self> {
+        Ok(match value {
+            "sprite"     => AssetKind::Sprite,
+            "bgm"        => AssetKind::Bgm,
+            "sfx"        => AssetKind::Sfx,
+            "voice"      => AssetKind::Voice,
+            "movie"      => AssetKind::Movie,
+            "background" => AssetKind::Background,
+            "map"        => AssetKind::Map,
+            other        => eyre::bail!("Unknown asset kind '{}'", other),
+        })
+    }
+}
+
+
+/// An external file a script points at.
+/// Sprites are `character:sprite`, voices are `character:chapter:clip`,
+/// everything else is a single number.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
+pub struct AssetRef {
+    pub kind: AssetKind,
+    pub id: String,
+}
+
+impl fmt::Display for AssetRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.id)
+    }
+}
+
+
+/// The asset an opcode uses, if any.
+/// 255 (or 0xFFFF) in the id slot means "stop"/"none" and isn't an asset.
+pub fn asset_of(op: &Opcode) -> Option<AssetRef> {
+    let (kind, id) = match (op.name.as_str(), op.args()) {
+        // Sprite(object, character, sprite, state, type)
+        ("Sprite", [_, character, sprite, _, _]) => { (AssetKind::Sprite, format!("{}:{}", character, sprite)) }
+        // Voice(character, chapter, id, id, volume)
+        ("Voice", [255, ..]) => { return None; }
+        ("Voice", [character, chapter, high, low, _]) => {
+            (AssetKind::Voice, format!("{}:{}:{}", character, chapter, u16::from_be_bytes([*high, *low])))
+        }
+        ("Music", [255, ..]) => { return None; }
+        ("Music", [id, _, _]) => { (AssetKind::Bgm, id.to_string()) }
+        ("Sound", [255, 255, _]) => { return None; }
+        ("Sound", [high, low, _]) | ("SoundB", [high, low]) => {
+            (AssetKind::Sfx, u16::from_be_bytes([*high, *low]).to_string())
+        }
+        ("Movie", [id, _]) => { (AssetKind::Movie, id.to_string()) }
+        ("ShowBackground", [high, low, _]) => { (AssetKind::Background, u16::from_be_bytes([*high, *low]).to_string()) }
+        ("LoadMap", [255, ..]) => { return None; }
+        ("LoadMap", [room, _, _]) => { (AssetKind::Map, room.to_string()) }
+        _ => { return None; }
+    };
+
+    Some(AssetRef { kind, id })
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct AssetUse {
+    pub script: String,
+    pub op_index: usize,
+    pub opcode: String,
+}
+
+
+/// The ids a modded game actually has, one `kind id` per line:
+///
+/// ```text
+/// # Custom sprites
+/// sprite 0:40
+/// voice 0:1:512
+/// bgm 30
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Manifest {
+    pub assets: HashSet<AssetRef>,
+}
+
+impl Manifest {
+    pub fn parse(source: &str) -> eyre::Result<Self> {
+        let mut assets: HashSet<AssetRef> = HashSet::new();
+
+        for (line_idx, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(char::is_whitespace) {
+                Some((kind, id)) => {
+                    let kind = kind.parse().wrap_err(format!("Manifest line {}", line_idx + 1))?;
+                    assets.insert(AssetRef { kind, id: id.trim().to_string() });
+                }
+                None => { eyre::bail!("Manifest line {} should be '<kind> <id>'", line_idx + 1) }
+            }
+        }
+
+        Ok(Manifest { assets })
+    }
+
+
+    pub fn load(filename: &str) -> eyre::Result<Self> {
+        let source = read_to_string(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+        Manifest::parse(&source)
+    }
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct AssetReport {
+    /// Every asset, keyed as `kind id`, with where it's used.
+    pub assets: BTreeMap<String, Vec<AssetUse>>,
+    /// Assets that aren't in the manifest. Empty when there is no manifest.
+    pub missing: Vec<String>,
+}
+
+impl AssetReport {
+    pub fn build(project: &Project, manifest: Option<&Manifest>) -> Self {
+        let mut assets: BTreeMap<AssetRef, Vec<AssetUse>> = BTreeMap::new();
+
+        for (name, script) in &project.scripts {
+            for (op_index, op) in script.ops.iter().enumerate() {
+                if let Some(asset) = asset_of(op) {
+                    assets.entry(asset).or_default().push(AssetUse {
+                        script: name.clone(),
+                        op_index,
+                        opcode: op.name.clone(),
+                    });
+                }
+            }
+        }
+
+        let missing = match manifest {
+            Some(manifest) => {
+                assets
+                    .keys()
+                    .filter(|asset| !manifest.assets.contains(*asset))
+                    .map(|asset| asset.to_string())
+                    .collect()
+            }
+            None => { Vec::new() }
+        };
+
+        AssetReport {
+            assets: assets.into_iter().map(|(asset, uses)| (asset.to_string(), uses)).collect(),
+            missing,
+        }
+    }
+
+
+    pub fn to_json(&self) -> eyre::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+
+/// Builds the asset report for a .lin file or a folder of them.
+pub fn asset_report(path: &str, manifest: Option<&str>) -> eyre::Result<AssetReport> {
+    let project = Project::load_path(path)?;
+    let manifest = match manifest {
+        Some(filename) => { Some(Manifest::load(filename)?) }
+        None => { None }
+    };
+
+    let report = AssetReport::build(&project, manifest.as_ref());
+    log::info!("found {} assets, {} missing", report.assets.len(), report.missing.len());
+
+    Ok(report)
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::opcode::Opcode;
+    use crate::project::Project;
+    use crate::script::LinScript;
+
+    use super::{asset_of, AssetKind, AssetRef, AssetReport, Manifest};
+
+    fn op(line: &str) -> Opcode {
+        Opcode::try_from_string(line.to_string(), 0).0.unwrap()
+    }
+
+    #[test]
+    fn test_asset_of() {
+        assert_that!(asset_of(&op("Voice(0, 1, 1, 3, 100)")), equal_to(Some(AssetRef {
+            kind: AssetKind::Voice, id: "0:1:259".to_string()
+        })));
+        assert_that!(asset_of(&op("Sound(0, 124, 100)")), equal_to(Some(AssetRef {
+            kind: AssetKind::Sfx, id: "124".to_string()
+        })));
+        assert_that!(asset_of(&op("Music(255, 180, 0)")), equal_to(None));
+        assert_that!(asset_of(&op("WaitInput()")), equal_to(None));
+    }
+
+    #[test]
+    fn test_report_against_manifest() {
+        let mut project = Project::default();
+        project.scripts.insert("e01_001_000".to_string(), LinScript {
+            ops: vec![
+                op("Sprite(1, 1, 14, 0, 0)"),
+                op("Music(17, 100, 0)"),
+                op("Sprite(1, 1, 14, 0, 0)"),
+            ],
+            text_entries: Vec::new(),
+        });
+
+        let manifest = Manifest::parse("# from the base game\nbgm 17\n").unwrap();
+        let report = AssetReport::build(&project, Some(&manifest));
+
+        assert_that!(report.assets["sprite 1:14"].len(), equal_to(2));
+        assert_that!(report.missing, equal_to(vec!["sprite 1:14".to_string()]));
+    }
+
+    #[test]
+    fn test_manifest_rejects_unknown_kind() {
+        assert_that!(Manifest::parse("portrait 3").is_err(), is(true));
+    }
+}
@@ -0,0 +1,461 @@
+use eyre::Context;
+use log;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::compiler::{self, Diagnostic};
+use crate::decompiler::{self, DecompileOptions};
+
+
+/// Kept in the output folder, so each output tree remembers what it was built from.
+pub const CACHE_FILE: &str = ".dgrlin-cache.json";
+
+
+/// Stops a batch between files. Clones share the same flag, so one can be handed
+/// to the batch and the other kept to cancel it from another thread.
+#[derive(Default, Debug, Clone)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) || self.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+
+
+    /// A token of its own that also counts as cancelled once this one is,
+    /// so a batch can be stopped from inside without cancelling the token it was handed.
+    pub fn child(&self) -> CancelToken {
+        CancelToken { flag: Arc::default(), parent: Some(Box::new(self.clone())) }
+    }
+}
+
+
+#[derive(Default, Debug, Clone)]
+pub struct BatchOptions {
+    /// Rebuild everything, whatever the cache says.
+    pub force: bool,
+    /// How many files to compile at once. None uses every core.
+    pub jobs: Option<usize>,
+    /// Files not started by the time this is cancelled come back as `Status::Cancelled`.
+    pub cancel: CancelToken,
+}
+
+
+/// Called as each file finishes, with how many are done and how many there are in all.
+/// Runs on the worker threads, one call at a time.
+pub type Progress<'a> = &'a (dyn Fn(usize, usize, &FileResult) + Sync);
+
+
+/// What the last build made of each source, by path relative to the source folder.
+/// A different compiler version throws the whole cache away.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Cache {
+    pub version: String,
+    pub files: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct CacheEntry {
+    pub hash: String,
+    /// Handed back when the file is skipped, so they keep showing until they're fixed.
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Cache {
+    /// An empty cache when there isn't one yet, or it can't be read.
+    pub fn load(output_folder: &Path) -> Self {
+        let cache: Cache = read_to_string(output_folder.join(CACHE_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+
+        if cache.version != env!("CARGO_PKG_VERSION") {
+            return Cache { version: env!("CARGO_PKG_VERSION").to_string(), files: BTreeMap::new() };
+        }
+        cache
+    }
+
+
+    /// Forgets sources that are gone from `source_folder`.
+    pub fn prune(&mut self, source_folder: &Path) {
+        self.files.retain(|source, _| source_folder.join(source).is_file());
+    }
+
+
+    pub fn save(&self, output_folder: &Path) -> eyre::Result<()> {
+        fs::write(output_folder.join(CACHE_FILE), serde_json::to_string_pretty(self)?)
+            .wrap_err("Could not write the build cache")
+    }
+}
+
+
+pub fn source_hash(source: &str) -> String {
+    content_hash(source.as_bytes())
+}
+
+
+/// 64-bit FNV-1a. Only there to notice edits, and unlike `DefaultHasher` it won't change between Rust releases.
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Compiled,
+    Decompiled,
+    /// Unchanged since the last build.
+    Skipped,
+    Failed,
+    /// Never started, because the batch was cancelled.
+    Cancelled,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct FileResult {
+    /// Relative to the source folder.
+    pub source: String,
+    pub output: String,
+    pub status: Status,
+    /// What stopped it compiling.
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+
+/// Compiles every .txt under `source_folder` into the same tree under `output_folder`,
+/// several at a time, skipping the ones whose source hasn't changed since the last build.
+/// One broken script doesn't stop the rest. Results come back sorted by path.
+pub fn compile_tree(source_folder: &str, output_folder: &str, options: &BatchOptions) -> eyre::Result<Vec<FileResult>> {
+    compile_tree_with_progress(source_folder, output_folder, options, &|_, _, _| {})
+}
+
+
+pub fn compile_tree_with_progress(source_folder: &str, output_folder: &str, options: &BatchOptions, progress: Progress) -> eyre::Result<Vec<FileResult>> {
+    let source_folder = Path::new(source_folder);
+    let output_folder = Path::new(output_folder);
+
+    let sources = find_files(source_folder, "txt")?;
+
+    fs::create_dir_all(output_folder).wrap_err("Output Directory not found")?;
+    let cache = Mutex::new(Cache::load(output_folder));
+
+    log::info!("compiling {} scripts", sources.len());
+
+    let results = run(&sources, (source_folder, output_folder, "lin"), options, progress, |result, path| {
+        compile_one(result, path, &cache, options.force);
+    });
+
+    let mut cache = cache.into_inner().unwrap();
+    cache.prune(source_folder);
+    cache.save(output_folder)?;
+    Ok(results)
+}
+
+
+/// Decompiles every .lin under `source_folder` into the same tree of .txt's under `output_folder`,
+/// the same way `compile_tree` goes the other way. There's no cache, since .lin's rarely change.
+pub fn decompile_tree(source_folder: &str, output_folder: &str, options: &BatchOptions, decompile: &DecompileOptions, progress: Progress) -> eyre::Result<Vec<FileResult>> {
+    let source_folder = Path::new(source_folder);
+    let output_folder = Path::new(output_folder);
+
+    let sources = find_files(source_folder, "lin")?;
+
+    fs::create_dir_all(output_folder).wrap_err("Output Directory not found")?;
+
+    log::info!("decompiling {} scripts", sources.len());
+
+    Ok(run(&sources, (source_folder, output_folder, "txt"), options, progress, |result, path| {
+        decompile_one(result, path, decompile);
+    }))
+}
+
+
+// Hands the files out to `options.jobs` threads, each mirrored from the source folder into the
+// output folder with the new extension. Once cancelled, the rest are skipped. Results come back sorted by path.
+fn run<W>(files: &[PathBuf], (source_folder, output_folder, extension): (&Path, &Path, &str), options: &BatchOptions, progress: Progress, work: W) -> Vec<FileResult>
+where
+    W: Fn(&mut FileResult, &Path) + Sync,
+{
+    let jobs = options.jobs
+        .unwrap_or_else(|| thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1))
+        .clamp(1, files.len().max(1));
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<FileResult>> = Mutex::new(Vec::new());
+
+    log::info!("running on {} threads", jobs);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let path = match files.get(idx) {
+                        Some(path) => { path }
+                        None => { break; }
+                    };
+
+                    let mut result = FileResult::new(source_folder, output_folder, path, extension);
+                    if options.cancel.is_cancelled() {
+                        result.status = Status::Cancelled;
+                    } else {
+                        work(&mut result, path);
+                    }
+
+                    // Held through the callback, so it's never called twice at once
+                    let mut results = results.lock().unwrap();
+                    progress(results.len() + 1, files.len(), &result);
+                    results.push(result);
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+    results
+}
+
+
+impl FileResult {
+    fn new(source_folder: &Path, output_folder: &Path, path: &Path, extension: &str) -> Self {
+        let relative = path.strip_prefix(source_folder).unwrap_or(path);
+        let output = output_folder.join(relative).with_extension(extension);
+
+        FileResult {
+            source: relative.to_string_lossy().replace('\\', "/"),
+            output: output.to_string_lossy().to_string(),
+            status: Status::Failed,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+
+fn compile_one(result: &mut FileResult, path: &Path, cache: &Mutex<Cache>, force: bool) {
+    let output = PathBuf::from(&result.output);
+
+    let source = match read_to_string(path) {
+        Ok(source) => { source }
+        Err(err) => {
+            result.errors.push(Diagnostic::new(0, format!("could not be opened: {}", err)));
+            return;
+        }
+    };
+    let hash = source_hash(&source);
+
+    let cached = cache.lock().unwrap().files.get(&result.source).filter(|entry| entry.hash == hash).cloned();
+    if let Some(entry) = cached
+        && !force
+        && output.exists()
+    {
+        result.warnings = entry.warnings;
+        result.status = Status::Skipped;
+        return;
+    }
+
+    let (parsed, errors) = compiler::parse_with_diagnostics(&source);
+    result.warnings = compiler::markup_warnings(&parsed);
+
+    if !errors.is_empty() {
+        log::info!("{} did not compile", result.source);
+        result.errors = errors;
+        cache.lock().unwrap().files.remove(&result.source);
+        return;
+    }
+
+    let written = output.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&output, compiler::encode(&parsed.script)));
+    if let Err(err) = written {
+        result.errors.push(Diagnostic::new(0, format!("could not be written: {}", err)));
+        return;
+    }
+
+    cache.lock().unwrap().files.insert(result.source.clone(), CacheEntry { hash, warnings: result.warnings.clone() });
+    result.status = Status::Compiled;
+}
+
+
+fn decompile_one(result: &mut FileResult, path: &Path, options: &DecompileOptions) {
+    let script = match decompiler::read_lin(&path.to_string_lossy()) {
+        Ok(script) => { script }
+        Err(err) => {
+            result.errors.push(Diagnostic::new(0, err.root_cause().to_string()));
+            return;
+        }
+    };
+
+    let mut source: Vec<u8> = Vec::new();
+    if let Err(err) = decompiler::write_script(&mut source, &script, options) {
+        result.errors.push(Diagnostic::new(0, err.to_string()));
+        return;
+    }
+
+    let output = Path::new(&result.output);
+    let written = output.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(output, source));
+    if let Err(err) = written {
+        result.errors.push(Diagnostic::new(0, format!("could not be written: {}", err)));
+        return;
+    }
+
+    result.status = Status::Decompiled;
+}
+
+
+// Every file under `folder` with the extension, sorted.
+fn find_files(folder: &Path, extension: &str) -> eyre::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    find_sources(folder, extension, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+
+fn find_sources(folder: &Path, extension: &str, sources: &mut Vec<PathBuf>) -> eyre::Result<()> {
+    let entries = fs::read_dir(folder).wrap_err(format!("Folder \"{}\" could not be opened.", folder.display()))?;
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_sources(&path, extension, sources)?;
+        }
+        else if path.extension().is_some_and(|found| found == extension) {
+            sources.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use std::fs;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::decompiler::DecompileOptions;
+
+    use super::{compile_tree, decompile_tree, BatchOptions, Cache, CancelToken, Status};
+
+    #[test]
+    fn test_compile_tree_skips_unchanged() {
+        let root = std::env::temp_dir().join(format!("dgrlin-batch-{}", std::process::id()));
+        let (source, output) = (root.join("src"), root.join("out"));
+        fs::create_dir_all(source.join("ch1")).unwrap();
+        fs::write(source.join("ch1/e01_001_000.txt"), "Text(\"Hello\")\nWaitInput()\n").unwrap();
+        fs::write(source.join("e01_002_000.txt"), "IfFlagCheck()\nWaitInput()\n").unwrap();
+
+        let (source, output) = (source.to_str().unwrap(), output.to_str().unwrap());
+        let first = compile_tree(source, output, &BatchOptions::default()).unwrap();
+
+        assert_that!(first[0].source.as_str(), equal_to("ch1/e01_001_000.txt"));
+        assert_that!(first[0].status, equal_to(Status::Compiled));
+        assert_that!(first[1].status, equal_to(Status::Failed));
+        assert_that!(first[1].errors[0].line, equal_to(1));
+        assert_that!(fs::metadata(&first[0].output).is_ok(), is(true));
+
+        let second = compile_tree(source, output, &BatchOptions { force: false, jobs: Some(1), ..Default::default() }).unwrap();
+        assert_that!(second[0].status, equal_to(Status::Skipped));
+        assert_that!(second[1].status, equal_to(Status::Failed));
+
+        let forced = compile_tree(source, output, &BatchOptions { force: true, jobs: None, ..Default::default() }).unwrap();
+        assert_that!(forced[0].status, equal_to(Status::Compiled));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_cache_keeps_warnings_and_forgets_deleted() {
+        let root = std::env::temp_dir().join(format!("dgrlin-batch-cache-{}", std::process::id()));
+        let (source, output) = (root.join("src"), root.join("out"));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("e01_001_000.txt"), "Text(\"<CLT 4>Never closed\")\n").unwrap();
+        fs::write(source.join("e01_002_000.txt"), "WaitInput()\n").unwrap();
+
+        let (source_folder, output_folder) = (source.to_str().unwrap(), output.to_str().unwrap());
+        let first = compile_tree(source_folder, output_folder, &BatchOptions::default()).unwrap();
+        assert_that!(first[0].warnings.is_empty(), is(false));
+
+        fs::remove_file(source.join("e01_002_000.txt")).unwrap();
+        let second = compile_tree(source_folder, output_folder, &BatchOptions::default()).unwrap();
+        assert_that!(second[0].status, equal_to(Status::Skipped));
+        assert_that!(second[0].warnings.clone(), equal_to(first[0].warnings.clone()));
+
+        let cache = Cache::load(&output);
+        assert_that!(cache.files.keys().collect::<Vec<_>>(), equal_to(vec!["e01_001_000.txt"]));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_decompile_tree_progress_and_cancel() {
+        let root = std::env::temp_dir().join(format!("dgrlin-batch-cancel-{}", std::process::id()));
+        let (source, output) = (root.join("lin"), root.join("txt"));
+        fs::create_dir_all(&source).unwrap();
+        for name in ["e01_100_000.lin", "e01_101_000.lin", "e01_102_000.lin"] {
+            fs::copy(format!("{}/../data/{}", env!("CARGO_MANIFEST_DIR"), name), source.join(name)).unwrap();
+        }
+
+        // Cancelled by the first file's progress, so the other two never start
+        let options = BatchOptions { jobs: Some(1), ..Default::default() };
+        let calls = AtomicUsize::new(0);
+        let (source, output) = (source.to_str().unwrap(), output.to_str().unwrap());
+        let results = decompile_tree(source, output, &options, &DecompileOptions::default(), &|done, total, _| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            assert_that!(total, equal_to(3));
+            if done == 1 {
+                options.cancel.cancel();
+            }
+        }).unwrap();
+
+        assert_that!(calls.load(Ordering::Relaxed), equal_to(3));
+        let statuses: Vec<Status> = results.iter().map(|result| result.status).collect();
+        assert_that!(statuses, equal_to(vec![Status::Decompiled, Status::Cancelled, Status::Cancelled]));
+        assert_that!(fs::metadata(&results[0].output).is_ok(), is(true));
+        assert_that!(fs::metadata(&results[1].output).is_ok(), is(false));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_child_cancel_token() {
+        let parent = CancelToken::default();
+        let child = parent.child();
+
+        child.cancel();
+        assert_that!((parent.is_cancelled(), child.is_cancelled()), equal_to((false, true)));
+
+        let child = parent.child();
+        parent.cancel();
+        assert_that!(child.is_cancelled(), is(true));
+    }
+}
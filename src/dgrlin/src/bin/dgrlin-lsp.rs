@@ -0,0 +1,34 @@
+use std::io::{self, BufReader};
+
+use dgrlin::lsp::{parse_error, read_message, write_message, Incoming, Server};
+
+
+// Talks LSP over stdin/stdout. Logging goes to stderr, which editors show as the server's output.
+fn main() -> eyre::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let mut reader = BufReader::new(io::stdin().lock());
+    let mut writer = io::stdout().lock();
+    let mut server = Server::default();
+
+    while let Some(incoming) = read_message(&mut reader)? {
+        let replies = match incoming {
+            Incoming::Message(message) => { server.handle(&message) }
+            // One bad message from the editor shouldn't take the server down with it
+            Incoming::Malformed(problem) => {
+                log::warn!("could not read message: {}", problem);
+                vec![parse_error(&problem)]
+            }
+        };
+
+        for reply in replies {
+            write_message(&mut writer, &reply)?;
+        }
+
+        if server.exited {
+            break;
+        }
+    }
+
+    Ok(())
+}
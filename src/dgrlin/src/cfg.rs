@@ -0,0 +1,390 @@
+use eyre::Context;
+use log;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use crate::decompiler::{output_path, read_lin};
+use crate::opcode::Opcode;
+use crate::script::LinScript;
+
+
+/// A run of opcodes that always execute together, `ops[start..end]`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    /// IfFlagCheck passed, so the guarded opcode runs.
+    FlagPass,
+    /// IfFlagCheck failed, so the guarded opcode is skipped.
+    FlagFail,
+    /// The investigated character/object matched this section.
+    Match,
+    /// It didn't, try the next CheckCharacter/CheckObject.
+    NoMatch,
+    /// The player picked this SetChoiceText option.
+    Choice(u8),
+    /// On to the next option of the same menu.
+    NextChoice,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+    /// GoToLabel opcodes whose label is never set, as (op index, label).
+    pub unresolved: Vec<(usize, u16)>,
+}
+
+
+pub fn label_of(op: &Opcode) -> Option<u16> {
+    match (op.name.as_str(), op.args()) {
+        ("SetLabel" | "GoToLabel", [high, low]) => { Some(u16::from_be_bytes([*high, *low])) }
+        _ => { None }
+    }
+}
+
+
+fn is_section(op: &Opcode) -> bool {
+    matches!(op.name.as_str(), "CheckCharacter" | "CheckObject" | "SetChoiceText")
+}
+
+
+impl ControlFlowGraph {
+    /// Splits a script into basic blocks and connects them.
+    ///
+    /// Sections opened by CheckCharacter, CheckObject and SetChoiceText are
+    /// treated the same way the decompiler braces them: a section runs until
+    /// the next one starts, and never falls through into it.
+    pub fn build(script: &LinScript) -> Self {
+        let ops = &script.ops;
+        if ops.is_empty() {
+            return ControlFlowGraph::default();
+        }
+
+        // Find where every block starts
+        let mut leaders: Vec<bool> = vec![false; ops.len() + 1];
+        leaders[0] = true;
+        leaders[ops.len()] = true;
+
+        for (idx, op) in ops.iter().enumerate() {
+            match op.name.as_str() {
+                "SetLabel" => { leaders[idx] = true; }
+                "GoToLabel" | "StopScript" => { leaders[idx + 1] = true; }
+                "CheckCharacter" | "CheckObject" | "SetChoiceText" => {
+                    leaders[idx] = true;
+                    leaders[idx + 1] = true;
+                }
+                // The guarded opcode sits in a block of its own
+                "IfFlagCheck" => {
+                    leaders[idx + 1] = true;
+                    leaders[(idx + 2).min(ops.len())] = true;
+                }
+                _ => {}
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut block_of: Vec<usize> = vec![0; ops.len() + 1];
+        let mut start = 0usize;
+        for idx in 1..=ops.len() {
+            if leaders[idx] {
+                blocks.push(BasicBlock { start, end: idx });
+                start = idx;
+            }
+            block_of[idx] = blocks.len();
+        }
+
+        let labels: HashMap<u16, usize> = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.name == "SetLabel")
+            .filter_map(|(idx, op)| Some((label_of(op)?, block_of[idx])))
+            .collect();
+
+        let mut edges: Vec<Edge> = Vec::new();
+        let mut unresolved: Vec<(usize, u16)> = Vec::new();
+
+        for (block_idx, block) in blocks.iter().enumerate() {
+            let last = block.end - 1;
+            let op = &ops[last];
+            let next = if block.end < ops.len() { Some(block_of[block.end]) } else { None };
+
+            // Where this block goes when nothing jumps
+            let fallthrough = match next {
+                Some(next) if !is_section(&ops[blocks[next].start]) => { Some(next) }
+                _ => { None }
+            };
+
+            // The next sibling section, for the no-match and next-choice edges
+            let next_section = |names: &[&str]| {
+                ops[block.end..]
+                    .iter()
+                    .position(|op| names.contains(&op.name.as_str()))
+                    .map(|offset| block_of[block.end + offset])
+            };
+
+            match op.name.as_str() {
+                "GoToLabel" => {
+                    let label = label_of(op).unwrap_or(0);
+                    match labels.get(&label) {
+                        Some(to) => {
+                            edges.push(Edge { from: block_idx, to: *to, kind: EdgeKind::Jump });
+                        }
+                        None => {
+                            unresolved.push((last, label));
+                        }
+                    }
+                }
+                "StopScript" => {}
+                "IfFlagCheck" => {
+                    if let Some(next) = next {
+                        edges.push(Edge { from: block_idx, to: next, kind: EdgeKind::FlagPass });
+                    }
+                    if block.end + 1 < ops.len() {
+                        edges.push(Edge { from: block_idx, to: block_of[block.end + 1], kind: EdgeKind::FlagFail });
+                    }
+                }
+                "CheckCharacter" | "CheckObject" => {
+                    if let Some(next) = next {
+                        edges.push(Edge { from: block_idx, to: next, kind: EdgeKind::Match });
+                    }
+                    if let Some(to) = next_section(&["CheckCharacter", "CheckObject"]) {
+                        edges.push(Edge { from: block_idx, to, kind: EdgeKind::NoMatch });
+                    }
+                }
+                "SetChoiceText" => {
+                    // One built by hand can lack its option, but still runs into its block
+                    let kind = match op.args().first() {
+                        Some(&choice) => { EdgeKind::Choice(choice) }
+                        None => { EdgeKind::Fallthrough }
+                    };
+                    if let Some(next) = next {
+                        edges.push(Edge { from: block_idx, to: next, kind });
+                    }
+                    if let Some(to) = next_section(&["SetChoiceText"]) {
+                        edges.push(Edge { from: block_idx, to, kind: EdgeKind::NextChoice });
+                    }
+                }
+                _ => {
+                    if let Some(next) = fallthrough {
+                        edges.push(Edge { from: block_idx, to: next, kind: EdgeKind::Fallthrough });
+                    }
+                }
+            }
+        }
+
+        ControlFlowGraph { blocks, edges, unresolved }
+    }
+
+
+    pub fn to_dot(&self, script: &LinScript) -> String {
+        let mut out = String::from("digraph script {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let lines: Vec<String> = block_lines(script, block)
+                .iter()
+                .map(|line| line.replace('\\', "\\\\").replace('"', "\\\"") + "\\l")
+                .collect();
+            out += &format!("    b{} [label=\"{}\"];\n", idx, lines.concat());
+        }
+
+        for edge in &self.edges {
+            match edge_label(edge.kind) {
+                Some(label) => { out += &format!("    b{} -> b{} [label=\"{}\"];\n", edge.from, edge.to, label); }
+                None => { out += &format!("    b{} -> b{};\n", edge.from, edge.to); }
+            }
+        }
+
+        out += "}\n";
+        out
+    }
+
+
+    pub fn to_mermaid(&self, script: &LinScript) -> String {
+        let mut out = String::from("flowchart TD\n");
+
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let lines: Vec<String> = block_lines(script, block)
+                .iter()
+                .map(|line| line
+                    .replace('&', "#amp;")
+                    .replace('"', "#quot;")
+                    .replace('<', "#lt;")
+                    .replace('>', "#gt;"))
+                .collect();
+            out += &format!("    b{}[\"{}\"]\n", idx, lines.join("<br/>"));
+        }
+
+        for edge in &self.edges {
+            match edge_label(edge.kind) {
+                Some(label) => { out += &format!("    b{} -->|{}| b{}\n", edge.from, label, edge.to); }
+                None => { out += &format!("    b{} --> b{}\n", edge.from, edge.to); }
+            }
+        }
+
+        out
+    }
+}
+
+
+fn edge_label(kind: EdgeKind) -> Option<String> {
+    match kind {
+        EdgeKind::Fallthrough => { None }
+        EdgeKind::Jump        => { Some("goto".to_string()) }
+        EdgeKind::FlagPass    => { Some("pass".to_string()) }
+        EdgeKind::FlagFail    => { Some("fail".to_string()) }
+        EdgeKind::Match       => { Some("match".to_string()) }
+        EdgeKind::NoMatch     => { Some("no match".to_string()) }
+        EdgeKind::Choice(choice) => { Some(format!("choice {}", choice)) }
+        EdgeKind::NextChoice  => { Some("next".to_string()) }
+    }
+}
+
+
+// Each opcode in the block as it would appear in the .txt,
+// with long lines of dialog cut short so the graph stays readable.
+fn block_lines(script: &LinScript, block: &BasicBlock) -> Vec<String> {
+    script.ops[block.start..block.end]
+        .iter()
+        .map(|op| match script.text_for(op) {
+            Some(text) if text.chars().count() > 40 => {
+                format!("Text(\"{}...\")", text.chars().take(40).collect::<String>())
+            }
+            Some(text) => { format!("Text(\"{}\")", text) }
+            None => { op.to_string() }
+        })
+        .collect()
+}
+
+
+/// Writes a .lin's control flow graph as Graphviz (`dot`) or Mermaid (`mermaid`).
+pub fn export_cfg(filename: String, output_folder: String, format: &str) -> eyre::Result<()> {
+    log::info!("building control flow graph for {}", filename);
+
+    let script = read_lin(&filename)?;
+    let cfg = ControlFlowGraph::build(&script);
+
+    for (op_idx, label) in &cfg.unresolved {
+        log::warn!("GoToLabel at opcode {} jumps to missing label {}", op_idx, label);
+    }
+
+    let (output, extension) = match format {
+        "dot"     => { (cfg.to_dot(&script), "dot") }
+        "mermaid" => { (cfg.to_mermaid(&script), "mmd") }
+        other     => { eyre::bail!("Unknown graph format '{}' (expected 'dot' or 'mermaid')", other) }
+    };
+
+    let mut file = File::create(output_path(&filename, output_folder, extension)?)
+        .wrap_err("Output Directory not found")?;
+
+    file.write_all(output.as_bytes()).wrap_err("Could not write to file (Unknown issue)")?;
+
+    log::info!("wrote to file");
+
+    Ok(())
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::opcode::Opcode;
+    use crate::script::LinScript;
+
+    use super::{ControlFlowGraph, Edge, EdgeKind};
+
+    fn script(lines: &[&str]) -> LinScript {
+        LinScript {
+            ops: lines
+                .iter()
+                .map(|line| Opcode::try_from_string(line.to_string(), 0).0.unwrap())
+                .collect(),
+            text_entries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_build_flag_check_and_jump() {
+        let script = script(&[
+            "CheckFlagB(0, 19, 0, 0, 2)",   // b0
+            "IfFlagCheck()",
+            "GoToLabel(1, 244)",            // b1
+            "ChangeUi(20, 1)",              // b2
+            "SetLabel(1, 244)",             // b3
+            "WaitInput()",
+        ]);
+
+        let cfg = ControlFlowGraph::build(&script);
+
+        assert_that!(cfg.blocks.len(), equal_to(4));
+        assert_that!(cfg.edges.clone(), equal_to(vec![
+            Edge { from: 0, to: 1, kind: EdgeKind::FlagPass },
+            Edge { from: 0, to: 2, kind: EdgeKind::FlagFail },
+            Edge { from: 1, to: 3, kind: EdgeKind::Jump },
+            Edge { from: 2, to: 3, kind: EdgeKind::Fallthrough },
+        ]));
+        assert_that!(cfg.unresolved.len(), equal_to(0));
+    }
+
+    #[test]
+    fn test_build_sections() {
+        let script = script(&[
+            "CheckCharacter(3)",  // b0
+            "WaitInput()",        // b1
+            "CheckCharacter(4)",  // b2
+            "GoToLabel(0, 9)",    // b3
+        ]);
+
+        let cfg = ControlFlowGraph::build(&script);
+
+        assert_that!(cfg.edges.clone(), equal_to(vec![
+            Edge { from: 0, to: 1, kind: EdgeKind::Match },
+            Edge { from: 0, to: 2, kind: EdgeKind::NoMatch },
+            Edge { from: 2, to: 3, kind: EdgeKind::Match },
+        ]));
+        assert_that!(cfg.unresolved.clone(), equal_to(vec![(3, 9)]));
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let script = script(&["SetChoiceText(1)", "WaitInput()"]);
+        let cfg = ControlFlowGraph::build(&script);
+
+        assert_that!(cfg.to_mermaid(&script).as_str(), equal_to(
+            "flowchart TD\n    b0[\"SetChoiceText(1)\"]\n    b1[\"WaitInput()\"]\n    b0 -->|choice 1| b1\n"
+        ));
+    }
+
+    #[test]
+    fn test_choice_without_option() {
+        // As Instruction("SetChoiceText", b"") would make it
+        let script = script(&["SetChoiceText()", "WaitInput()", "SetChoiceText(2)", "WaitInput()"]);
+        let cfg = ControlFlowGraph::build(&script);
+
+        assert_that!(cfg.edges.clone(), equal_to(vec![
+            Edge { from: 0, to: 1, kind: EdgeKind::Fallthrough },
+            Edge { from: 0, to: 2, kind: EdgeKind::NextChoice },
+            Edge { from: 2, to: 3, kind: EdgeKind::Choice(2) },
+        ]));
+    }
+}
@@ -0,0 +1,42 @@
+// Character ids as they appear in Speaker, Sprite, Voice and CheckCharacter.
+// Only the ids confirmed so far are named, everything else is written as a number.
+const NAMES: [&str; 20] = [
+    "Makoto Naegi",
+    "Kiyotaka Ishimaru",
+    "Byakuya Togami",
+    "Mondo Owada",
+    "Leon Kuwata",
+    "Hifumi Yamada",
+    "Yasuhiro Hagakure",
+    "Sayaka Maizono",
+    "Kyoko Kirigiri",
+    "Aoi Asahina",
+    "Toko Fukawa",
+    "Sakura Ogami",
+    "Celestia Ludenberg",
+    "Junko Enoshima",
+    "Chihiro Fujisaki",
+    "Monokuma",
+    "Real Junko Enoshima",
+    "Alter Ego",
+    "Genocide Jack",
+    "Jin Kirigiri",
+];
+
+
+pub fn name(id: u8) -> Option<&'static str> {
+    NAMES.get(id as usize).copied()
+}
+
+
+pub fn name_or_id(id: u8) -> String {
+    match name(id) {
+        Some(name) => { name.to_string() }
+        None => { format!("Character {}", id) }
+    }
+}
+
+
+pub fn all() -> impl Iterator<Item = (u8, &'static str)> {
+    NAMES.iter().enumerate().map(|(id, name)| (id as u8, *name))
+}
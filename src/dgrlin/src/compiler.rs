@@ -1,26 +1,394 @@
 use eyre::Context;
 use log;
 
-use std::io::BufReader;
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{read_to_string, File};
 use std::io::prelude::*;
+use std::path::Path;
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 
-use crate::opcode::Opcode;
+use crate::error::{InFile, LinError};
+use crate::markup;
+use crate::opcode::{self, strip_comment, Opcode};
+use crate::script::LinScript;
+use crate::sourcemap::{self, Position};
+
+
+/// Comments from a .txt, tied to the opcode they sit above or beside
+/// so the formatter can put them back in the same place.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Comments {
+    /// Whole-line comments above an opcode, by opcode index.
+    /// Comments on brace lines are moved up here too.
+    pub leading: BTreeMap<usize, Vec<String>>,
+    /// A comment after an opcode on the same line.
+    pub trailing: BTreeMap<usize, String>,
+    /// Comments after the last opcode.
+    pub end: Vec<String>,
+}
+
+
+/// A parsed .txt script.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Source {
+    pub script: LinScript,
+    pub comments: Comments,
+    /// The 1-based source line of every opcode.
+    pub lines: Vec<usize>,
+    /// The 1-based column every opcode starts at.
+    pub columns: Vec<usize>,
+}
+
+impl Source {
+    pub fn positions(&self) -> Vec<Position> {
+        self.lines.iter().zip(&self.columns).map(|(&line, &column)| Position { line, column }).collect()
+    }
+}
+
+
+/// Knobs for `compile_lin_with_options`. The defaults match `compile_lin`.
+#[derive(Default, Debug, Clone)]
+pub struct CompileOptions {
+    /// Also write `<name>.map.json` beside the .lin, see `sourcemap`.
+    pub source_map: bool,
+}
 
 
 pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()> {
+    compile_lin_with_options(filename, output_folder, &CompileOptions::default())
+}
+
+
+pub fn compile_lin_with_options(filename: String, output_folder: String, options: &CompileOptions) -> eyre::Result<()> {
     log::info!("compiling {}", filename);
 
-    let f = File::open(filename.clone())?;
-    let reader = BufReader::new(f).lines().flatten();
+    let source = read_to_string(&filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+    let parsed = parse(&source).wrap_err(InFile(filename.clone()))?;
+
+    log::info!("parsed file");
+
+    for warning in markup_warnings(&parsed) {
+        log::warn!("{}", warning);
+    }
+
+    let bytes = encode(&parsed.script);
+
+    log::info!("compiled file");
+
+    // And write to file
+    // Luckily, this one is handled well
+
+    // Original:
+    // let output_filename = filename.rsplit_once("/").unwrap().1.split(".").next().unwrap();
+    let output_filename = match filename.rsplit_once("/") {
+        // Grab everything after the last slash in the filepath
+        Some((_, output_file_with_extension)) => {
+            // Remove the file extension
+            match output_file_with_extension.split(".").next() {
+                Some(output_filename) => { output_filename }
+                // This'll call if the input file doesn't have an extension
+                None => { output_file_with_extension }
+            }
+        },
+        None => { eyre::bail!("Output Directory not found") }
+    };
+
+    let output = output_folder + "/" + output_filename + ".lin";
+    let mut file = File::create(&output)
+        .wrap_err("Output Directory not found")?;
+
+    file.write_all(&bytes[..]).wrap_err("Could not write to file (Unknown issue)")?;
+    
+    log::info!("wrote to file");
+
+    if options.source_map {
+        let output = Path::new(&output);
+        sourcemap::write_map(output, output, Path::new(&filename), &parsed.script, &parsed.positions())?;
+    }
+
+    Ok(())
+}
+
 
-    log::info!("opened file");
+/// A problem with one line of a .txt script.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    /// 1-based.
+    pub line: usize,
+    pub message: String,
+}
 
+impl Diagnostic {
+    pub fn new(line: usize, message: String) -> Self {
+        Diagnostic { line, message }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+
+/// Reads a .txt script into opcodes and text, keeping its comments to one side.
+/// Every line that isn't an opcode, a brace or a comment is reported with its line number,
+/// and so is every block that doesn't match the layout the decompiler writes (see `BlockChecker`).
+pub fn parse(source: &str) -> eyre::Result<Source> {
+    let (parsed, errors) = parse_with_diagnostics(source);
+
+    if !errors.is_empty() {
+        return Err(LinError::syntax(source, errors).into());
+    }
+
+    Ok(parsed)
+}
+
+
+/// `parse`, but carrying on past problems. Unreadable lines are left out of the script.
+pub fn parse_with_diagnostics(source: &str) -> (Source, Vec<Diagnostic>) {
+    let mut parsed = Source::default();
+    let mut pending: Vec<String> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+    let mut blocks = BlockChecker::default();
+
+    for (line_idx, raw_line) in source.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let code = strip_comment(raw_line).trim();
+        let comment = raw_line.trim()[code.len()..].trim();
+
+        match code {
+            "{" => { blocks.open_brace(line_number, &mut errors); }
+            "}" => { blocks.close_brace(line_number, &mut errors); }
+            _ => {}
+        }
+
+        if matches!(code, "" | "{" | "}") {
+            if !comment.is_empty() {
+                pending.push(comment.to_string());
+            }
+            continue;
+        }
+
+        let name = code.split('(').next().unwrap_or(code);
+        blocks.opcode(name, line_number, &mut errors);
+
+        // One reason this may be None is a lack of double quotes surrounding line
+        let (op, text) = if code.contains('(') && code.ends_with(')') {
+            Opcode::try_from_string(code.to_string(), parsed.script.text_entries.len() as u32)
+        } else {
+            (None, None)
+        };
+
+        let mut op = match op {
+            Some(op) => { op }
+            None => {
+                errors.push(Diagnostic::new(line_number, format!("could not read '{}'", code)));
+                continue;
+            }
+        };
+
+        match opcode::info(&op.name) {
+            // try_from_string lets these through as 0xFE, which the game can't run
+            None if op.name != "Raw" => {
+                errors.push(Diagnostic::new(line_number, format!("unknown opcode '{}'", op.name)));
+            }
+            Some(info) => {
+                if let Some(problem) = info.check_arg_count(op.args().len()) {
+                    errors.push(Diagnostic::new(line_number, problem));
+                }
+            }
+            _ => {}
+        }
+
+        let op_idx = parsed.script.ops.len();
+        if let Some(text) = text {
+            op.text_id = Some(parsed.script.text_entries.len() as u16);
+            parsed.script.text_entries.push(text);
+        }
+        if !pending.is_empty() {
+            parsed.comments.leading.insert(op_idx, std::mem::take(&mut pending));
+        }
+        if !comment.is_empty() {
+            parsed.comments.trailing.insert(op_idx, comment.to_string());
+        }
+
+        parsed.script.ops.push(op);
+        parsed.lines.push(line_number);
+        parsed.columns.push(Position::of_line(line_number, raw_line).column);
+    }
+
+    blocks.finish(&mut errors);
+
+    parsed.comments.end = pending;
+    (parsed, errors)
+}
+
+
+// An open `{`, and the opcode it belongs to.
+struct Block {
+    opener: String,
+    line: usize,
+    ops: usize,
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A stray '{' has no opener, but still has to be closed
+        if self.opener.is_empty() {
+            write!(f, "the block from line {}", self.line)
+        } else {
+            write!(f, "the {} block from line {}", self.opener, self.line)
+        }
+    }
+}
+
+
+/// Follows the `{ }` blocks of a script as it's read.
+///
+/// The .lin has no blocks, so they have to match what the game will actually do:
+/// * IfFlagCheck guards exactly one opcode.
+/// * A SetChoiceText option runs until the next SetChoiceText, so options sit side by side,
+///   never inside each other, and nothing else can come between them.
+/// * CheckCharacter and CheckObject sections run until the next section, so they are never
+///   inside another block, and nothing else can come between them either.
+#[derive(Default)]
+struct BlockChecker {
+    open: Vec<Block>,
+    // An opcode that needs a block, waiting for its '{'
+    awaiting: Option<(String, usize)>,
+    // The block closed last, until the next opcode comes along
+    closed: Option<(String, usize)>,
+}
+
+impl BlockChecker {
+    fn open_brace(&mut self, line: usize, errors: &mut Vec<Diagnostic>) {
+        let (opener, opener_line) = match self.awaiting.take() {
+            Some(awaiting) => { awaiting }
+            None => {
+                errors.push(Diagnostic::new(line, "'{' doesn't follow an IfFlagCheck, SetChoiceText, CheckCharacter or CheckObject".to_string()));
+                (String::new(), line)
+            }
+        };
+
+        self.open.push(Block { opener, line: opener_line, ops: 0 });
+    }
+
+
+    fn close_brace(&mut self, line: usize, errors: &mut Vec<Diagnostic>) {
+        // Unless it's the bare IfFlagCheck at the very end, see `finish`
+        if !self.awaiting_flag_check() {
+            self.missing_brace(errors);
+        }
+
+        let block = match self.open.pop() {
+            Some(block) => { block }
+            None => {
+                errors.push(Diagnostic::new(line, "'}' has no '{' to close".to_string()));
+                return;
+            }
+        };
+
+        if block.opener == "IfFlagCheck" && block.ops != 1 {
+            errors.push(Diagnostic::new(line, format!("{} must hold exactly one opcode, not {}", block, block.ops)));
+        }
+
+        self.closed = Some((block.opener, block.line));
+    }
+
+
+    fn opcode(&mut self, name: &str, line: usize, errors: &mut Vec<Diagnostic>) {
+        self.missing_brace(errors);
+
+        let is_section = name == "CheckCharacter" || name == "CheckObject";
+
+        if let Some((opener, opener_line)) = self.closed.take() {
+            let carries_on = match opener.as_str() {
+                "SetChoiceText" => { name != "SetChoiceText" && !is_section }
+                "CheckCharacter" | "CheckObject" => { !is_section }
+                _ => { false }
+            };
+            if carries_on {
+                errors.push(Diagnostic::new(line, format!(
+                    "{} comes after the {} block from line {}, but still runs as part of it",
+                    name, opener, opener_line
+                )));
+            }
+        }
+
+        if is_section && let Some(block) = self.open.last() {
+            errors.push(Diagnostic::new(line, format!("{} can't be inside {}", name, block)));
+        }
+        if name == "SetChoiceText"
+            && let Some(block) = self.open.iter().rev().find(|block| block.opener == "SetChoiceText") {
+            errors.push(Diagnostic::new(line, format!("SetChoiceText can't be inside {}", block)));
+        }
+
+        // The opcode an IfFlagCheck guards doesn't get a block of its own
+        let guarded = match self.open.last_mut() {
+            Some(block) => {
+                block.ops += 1;
+                block.opener == "IfFlagCheck"
+            }
+            None => { false }
+        };
+
+        if !guarded && (is_section || name == "SetChoiceText" || name == "IfFlagCheck") {
+            self.awaiting = Some((name.to_string(), line));
+        }
+    }
+
+
+    fn finish(mut self, errors: &mut Vec<Diagnostic>) {
+        // An IfFlagCheck at the very end has nothing to guard, and the decompiler leaves it bare
+        if self.awaiting_flag_check() {
+            self.awaiting = None;
+        }
+        self.missing_brace(errors);
+
+        for block in self.open.iter().rev() {
+            let opener = if block.opener.is_empty() { "'{'" } else { block.opener.as_str() };
+            errors.push(Diagnostic::new(block.line, format!("{} block is never closed", opener)));
+        }
+    }
+
+
+    fn awaiting_flag_check(&self) -> bool {
+        matches!(&self.awaiting, Some((opener, _)) if opener == "IfFlagCheck")
+    }
+
+
+    fn missing_brace(&mut self, errors: &mut Vec<Diagnostic>) {
+        if let Some((opener, line)) = self.awaiting.take() {
+            errors.push(Diagnostic::new(line, format!("{} needs a '{{' block after it", opener)));
+        }
+    }
+}
+
+
+/// Markup problems in every line of text.
+/// These don't stop a compile, the game copes with most of them.
+pub fn markup_warnings(parsed: &Source) -> Vec<Diagnostic> {
+    parsed.script.ops
+        .iter()
+        .zip(&parsed.lines)
+        .filter_map(|(op, line_number)| Some((parsed.script.text_for(op)?, line_number)))
+        .flat_map(|(text, line_number)| {
+            markup::validate(text)
+                .into_iter()
+                .map(move |issue| Diagnostic::new(*line_number, issue.message))
+        })
+        .collect()
+}
+
+
+/// Builds the bytes of a .lin file.
+pub fn encode(script: &LinScript) -> Vec<u8> {
     let mut bytes: Vec<u8> = Vec::new();
-    let mut text_list: Vec<String> = Vec::new();
-    let mut text_id: u32 = 0u32;
+    let text_id = script.text_entries.len() as u32;
 
     // SECTION 0 [ HEADER ]
     // 2 0 0 0 16 0 0 0    <- File Identifier
@@ -29,32 +397,13 @@ pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()>
     bytes.append(&mut vec![0x00; 8]);
     
     // SECTION 1 [ OPCODES ]
-    // Mainly just take each line and see what sticks.
-    // TODO: stop and tell python when something doesn't work here
-    // I WANT LINE NUMBERS HERE
-    for line in reader {
-        let try_from_result: (Option<Opcode>, Option<String>) = Opcode::try_from_string(line, text_id);
-
-        match try_from_result.0 {
-            Some(operation) => {
-                bytes.append(&mut operation.to_hex());
-                match try_from_result.1 {
-                    Some(line) => {
-                        text_list.push(line);
-                        text_id += 1;
-                    },
-                    None => {}
-                }
-            },
-            // One reason this may be called is a lack of double quotes surrounding line
-            // This will be thrown back up to the python window to inform user.
-            None => {},
-        }
+    for operation in &script.ops {
+        bytes.append(&mut operation.to_hex());
     }
 
     // SECTION 2 [ TEXT SCRIPT OFFSETS ]
     // First, Buffer to nearest multiple of 4
-    let buffer_amount = if bytes.len() % 4 == 0 {
+    let buffer_amount = if bytes.len().is_multiple_of(4) {
         0
     } else {
         4 - bytes.len() % 4
@@ -75,9 +424,9 @@ pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()>
     let _ = bytes.write_u32::<LittleEndian>(text_id);
 
     let hexed_text_lines: Vec<Vec<u8>> = 
-        text_list
-            .into_iter()
-            .map(|line: String| {
+        script.text_entries
+            .iter()
+            .map(|line: &String| {
                 let mut line_in_hex: Vec<u8> = Vec::new();
                 let mut chars = line.chars();
                 loop {
@@ -92,12 +441,20 @@ pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()>
                             },
                             Some(c3) => {
                                 let _ = line_in_hex.write_u16::<LittleEndian>('\\' as u16);
-                                let _ = line_in_hex.write_u16::<LittleEndian>(c3 as u16);
+                                for unit in c3.encode_utf16(&mut [0u16; 2]) {
+                                    let _ = line_in_hex.write_u16::<LittleEndian>(*unit);
+                                }
+                            }
+                            // A backslash ending the line is just a backslash
+                            None => {
+                                let _ = line_in_hex.write_u16::<LittleEndian>('\\' as u16);
                             }
-                            None => {}
                         },
+                        // Past U+FFFF takes a surrogate pair
                         Some(the_char) => {
-                            let _ = line_in_hex.write_u16::<LittleEndian>(the_char as u16);
+                            for unit in the_char.encode_utf16(&mut [0u16; 2]) {
+                                let _ = line_in_hex.write_u16::<LittleEndian>(*unit);
+                            }
                         }
                     }
                 }
@@ -128,6 +485,11 @@ pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()>
         bytes.append(&mut hex_line);
     }
 
+    // The game's files end padded to a multiple of 4 as well, counted in the size
+    while !bytes.len().is_multiple_of(4) {
+        bytes.push(0x00);
+    }
+
     // SECTION 0 AGAIN [ ADD BYTE NUMBERS ]
     let mut text_address_vec: Vec<u8> = Vec::new();
     let _ = text_address_vec.write_u32::<LittleEndian>(bytes.len() as u32);
@@ -135,38 +497,119 @@ pub fn compile_lin(filename: String, output_folder: String) -> eyre::Result<()>
     // Update Header
     bytes.splice(12..=15, text_address_vec);
 
-    log::info!("compiled file");
+    bytes
+}
 
-    // And write to file
-    // Luckily, this one is handled well
 
-    // Original:
-    // let output_filename = filename.rsplit_once("/").unwrap().1.split(".").next().unwrap();
-    let output_filename = match filename.rsplit_once("/") {
-        // Grab everything after the last slash in the filepath
-        Some((_, output_file_with_extension)) => {
-            // Remove the file extension
-            match output_file_with_extension.split(".").next() {
-                Some(output_filename) => { output_filename }
-                // This'll call if the input file doesn't have an extension
-                None => { output_file_with_extension }
-            }
-        },
-        None => { eyre::bail!("Output Directory not found") }
-    };
 
-    let mut file = File::create(output_folder + "/" + output_filename + ".lin")
-        .wrap_err("Output Directory not found")?;
 
-    file.write(&bytes[..]).wrap_err("Could not write to file (Unknown issue)")?;
-    
-    log::info!("wrote to file");
 
-    Ok(())
-}
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::decompiler::decode;
+
+    use super::{encode, parse};
+
+    #[test]
+    fn test_encode_pads_end_of_file() {
+        // 42 bytes up to the end of "Hi!", then two of padding, which the size counts
+        let bytes = encode(&parse("Text(\"Hi!\")\n").unwrap().script);
+        assert_that!(bytes.len(), equal_to(44));
+        assert_that!(bytes[12..16].to_vec(), equal_to(vec![44, 0, 0, 0]));
+        assert_that!(bytes[42..].to_vec(), equal_to(vec![0, 0]));
+
+        // Which is how the game's own files end, so they compile back the same
+        let sample = std::fs::read(format!("{}/../data/e01_104_001.lin", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let script = decode(&sample).unwrap();
+        assert_that!(encode(&script), equal_to(sample));
+    }
+
+    #[test]
+    fn test_encode_text_units() {
+        // U+1F600 is the pair D83D DE00, and the backslash at the end is kept
+        let bytes = encode(&parse("Text(\"\u{1F600}C:\\\")\n").unwrap().script);
+        assert_that!(bytes[34..46].to_vec(), equal_to(vec![0x3D, 0xD8, 0x00, 0xDE, 0x43, 0x00, 0x3A, 0x00, 0x5C, 0x00, 0x00, 0x00]));
+
+        let script = decode(&bytes).unwrap();
+        assert_that!(script.text_entries, equal_to(vec!["\u{1F600}C:\\".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_keeps_comments() {
+        let parsed = parse("// top\nText(\"Hi (there)\") // greeting\nIfFlagCheck()\n{ // brace\nWaitFrame()\n}\n// last\n").unwrap();
 
+        assert_that!(parsed.script.ops.len(), equal_to(3));
+        assert_that!(parsed.script.text_entries, equal_to(vec!["Hi (there)".to_string()]));
+        assert_that!(parsed.script.ops[0].text_id, equal_to(Some(0)));
+        assert_that!(parsed.lines, equal_to(vec![2, 3, 5]));
+        assert_that!(parsed.comments.leading[&0].clone(), equal_to(vec!["// top".to_string()]));
+        assert_that!(parsed.comments.trailing[&0].as_str(), equal_to("// greeting"));
+        assert_that!(parsed.comments.leading[&2].clone(), equal_to(vec!["// brace".to_string()]));
+        assert_that!(parsed.comments.end.clone(), equal_to(vec!["// last".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_reports_line_numbers() {
+        let error = parse("WaitFrame()\nWaitFrame\nText(unquoted)\nWaitFrames()\n").unwrap_err().to_string();
+
+        assert_that!(error.as_str(), equal_to(
+            "line 2: could not read 'WaitFrame'\nline 3: could not read 'Text(unquoted)'\nline 4: unknown opcode 'WaitFrames'"
+        ));
 
+        // SetChoiceText() would compile to 70 2B 70 3A, and the game would read the WaitInput as its argument
+        let error = parse("CheckFlagA(0, 1, 0, 0, 6, 0, 2, 0, 1)\nGoToLabel(1)\nSetChoiceText()\n{\n    WaitInput()\n}\n")
+            .unwrap_err()
+            .to_string();
+        assert_that!(error.as_str(), equal_to(
+            "line 2: GoToLabel takes 2 arguments, not 1\nline 3: SetChoiceText takes 1 argument, not 0"
+        ));
+    }
 
+    fn errors(source: &str) -> Vec<String> {
+        match parse(source) {
+            Ok(_) => { Vec::new() }
+            Err(error) => { error.to_string().lines().map(str::to_string).collect() }
+        }
+    }
 
+    #[test]
+    fn test_parse_checks_blocks() {
+        let good = "\
+CheckCharacter(3)
+{
+    SetChoiceText(1)
+    {
+        IfFlagCheck()
+        {
+            GoToLabel(0, 1)
+        }
+    }
+    SetChoiceText(255)
+    {
+        IfFlagCheck()
+    }
+}
+";
+        assert_that!(errors(good), equal_to(Vec::<String>::new()));
 
-//// [ TESTS ] ////
\ No newline at end of file
+        assert_that!(errors("IfFlagCheck()\n{\nWaitFrame()\nWaitFrame()\n}\n"), equal_to(vec![
+            "line 5: the IfFlagCheck block from line 1 must hold exactly one opcode, not 2".to_string(),
+        ]));
+        assert_that!(errors("IfFlagCheck()\nWaitFrame()\n}\n{\n"), equal_to(vec![
+            "line 1: IfFlagCheck needs a '{' block after it".to_string(),
+            "line 3: '}' has no '{' to close".to_string(),
+            "line 4: '{' doesn't follow an IfFlagCheck, SetChoiceText, CheckCharacter or CheckObject".to_string(),
+            "line 4: '{' block is never closed".to_string(),
+        ]));
+        assert_that!(errors("SetChoiceText(1)\n{\nSetChoiceText(2)\n{\n}\n}\nWaitFrame()\n"), equal_to(vec![
+            "line 3: SetChoiceText can't be inside the SetChoiceText block from line 1".to_string(),
+            "line 7: WaitFrame comes after the SetChoiceText block from line 1, but still runs as part of it".to_string(),
+        ]));
+        assert_that!(errors("CheckObject(1)\n{\nCheckCharacter(2)\n{\n}\n}\n"), equal_to(vec![
+            "line 3: CheckCharacter can't be inside the CheckObject block from line 1".to_string(),
+        ]));
+    }
+}
@@ -2,418 +2,575 @@ use eyre::Context;
 use log;
 
 use std::fs::read;
-use hex::ToHex;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 
 use byteorder::{ByteOrder, LittleEndian, BigEndian};
 
-use crate::opcode::Opcode;
+use crate::opcode::{self, Opcode};
+use crate::script::LinScript;
+use crate::characters;
+use crate::compiler::Comments;
+use crate::error::{InFile, LinError};
+use crate::listing;
+use crate::sourcemap::{self, Position};
+
+
+/// Knobs for the .txt that `decompile_lin_with_options` writes.
+/// The defaults match what `decompile_lin` has always produced.
+#[derive(Default, Debug, Clone)]
+pub struct DecompileOptions {
+    /// Append `// Speaker [sprite, voice]` after every Text line.
+    /// The compiler skips these, so annotated files still recompile.
+    pub annotate_speakers: bool,
+    /// Also write `<name>.map.json` beside the .txt, see `sourcemap`. Only for a single .lin.
+    pub source_map: bool,
+    /// Also write `<name>.lst`, an annotated hex dump, see `listing`. Only for a single .lin.
+    pub listing: bool,
+    /// Salvage what `decode_lenient` can from a damaged file instead of giving up.
+    /// What was wrong is logged and listed in comments at the end of the .txt.
+    pub lenient: bool,
+}
 
 
 pub fn decompile_lin(filename: String, output_folder: String) -> eyre::Result<()> {
+    decompile_lin_with_options(filename, output_folder, &DecompileOptions::default())
+}
+
+
+/// A .pak is opened up and every script in it decompiled, into a folder named after it.
+pub fn decompile_lin_with_options(filename: String, output_folder: String, options: &DecompileOptions) -> eyre::Result<()> {
+    if filename.ends_with(".pak") {
+        crate::pak::decompile_pak(&filename, &output_folder, options)?;
+        return Ok(());
+    }
+
     log::info!("decompiling {}", filename);
 
-  //let mut data = read(filename.clone()).unwrap().into_iter().peekable();
-    let mut data = match read(filename.clone()) {
-        Ok(opened_file) => { opened_file.into_iter().peekable() }
-        Err(_) => { eyre::bail!("File \"{}\" could not be opened.", filename) }
+    let (script, comments) = if options.lenient {
+        let recovered = read_lin_lenient(&filename)?;
+        for problem in &recovered.problems {
+            log::warn!("{}: {}", filename, problem_line(problem));
+        }
+        let end = recovered.problems.iter().map(|problem| format!("// recovered: {}", problem_line(problem))).collect();
+        (recovered.script, Comments { end, ..Default::default() })
+    } else {
+        (read_lin(&filename)?, Comments::default())
     };
 
-    let mut ops:  Vec<Opcode> = Vec::new();
-    let mut idx:  usize = 0;
+    log::info!("decompiled file");
+
+    let output = output_path(&filename, output_folder, "txt")?;
+    let mut file = File::create(&output)
+        .wrap_err("Output Directory not found")?;
+
+    let positions = write_source(&mut file, &script, &comments, options)?;
+
+    log::info!("wrote to file");
+
+    if options.source_map {
+        let output = Path::new(&output);
+        sourcemap::write_map(output, Path::new(&filename), output, &script, &positions)?;
+    }
+
+    if options.listing {
+        std::fs::write(Path::new(&output).with_extension("lst"), listing::listing_file(&filename)?)
+            .wrap_err("Could not write to file (Unknown issue)")?;
+    }
+
+    Ok(())
+}
+
+
+/// Decodes a .lin file and writes the result out as JSON,
+/// including who is speaking each line of text.
+/// `styled_spans` adds each line split into coloured runs.
+pub fn export_json(filename: String, output_folder: String, styled_spans: bool) -> eyre::Result<()> {
+    log::info!("exporting {}", filename);
+
+    let script = read_lin(&filename)?;
+
+    let mut file = File::create(output_path(&filename, output_folder, "json")?)
+        .wrap_err("Output Directory not found")?;
+
+    file.write_all(script.to_json_with(styled_spans)?.as_bytes()).wrap_err("Could not write to file (Unknown issue)")?;
+
+    log::info!("wrote to file");
+
+    Ok(())
+}
+
+
+pub fn read_lin(filename: &str) -> eyre::Result<LinScript> {
+    let data = match read(filename) {
+        Ok(opened_file) => { opened_file }
+        Err(_) => { eyre::bail!("File \"{}\" could not be opened.", filename) }
+    };
 
     log::info!("opened file");
 
-    // SECTION 0 [ HEADER ]
-    if data.next() != Some(0x02) {
-        log::error!("Not a valid .lin file");
-        eyre::bail!("Not a valid .lin file (Header Inconsistency)");
+    decode(&data).wrap_err(InFile(filename.to_string()))
+}
+
+
+/// `read_lin` with `decode_lenient`. Only a file that can't be opened is an error.
+pub fn read_lin_lenient(filename: &str) -> eyre::Result<Recovered> {
+    match read(filename) {
+        Ok(data) => { Ok(decode_lenient(&data)) }
+        Err(_) => { eyre::bail!("File \"{}\" could not be opened.", filename) }
     }
-    let _ = (data.next(), data.next(), data.next());
+}
 
-    if data.next() != Some(0x10) {
-        log::error!("Not a valid .lin file");
-        eyre::bail!("Not a valid .lin file (Header Inconsistency)");
+
+/// "0x12: Invalid opcode 'ff' at index 18"
+pub fn problem_line(problem: &LinError) -> String {
+    match problem.offset() {
+        Some(offset) => { format!("{:#x}: {}", offset, problem) }
+        None => { problem.to_string() }
     }
-    
-    let _ = (data.next(), data.next(), data.next());
+}
 
-    // I shouldn't need the addresses provided here
-    let _ = (data.next(), data.next(), data.next(), data.next());
-    let _ = (data.next(), data.next(), data.next(), data.next());
 
-    idx += 16;
+/// Turns the raw bytes of a .lin file into its opcodes and text lines.
+pub fn decode(bytes: &[u8]) -> eyre::Result<LinScript> {
+    if let Some(problem) = header_problems(bytes).into_iter().next() {
+        log::error!("Not a valid .lin file");
+        return Err(problem.into());
+    }
 
     // SECTION 1 [ OPCODES ]
-    loop {
-        if data.peek() == None { // .lin with no text
-            break;
-        }
-        if data.peek() != Some(&0x70) { // Text Begins
-            break;
-        }
+    // They run until a byte that isn't 0x70, where the text begins, and no further than the
+    // header's text offset says. A string table with 112 lines starts with a 0x70 of its own.
+    let limit = match bytes.get(8..12).map(|field| LittleEndian::read_u32(field) as usize) {
+        Some(text_offset) if (16..=bytes.len()).contains(&text_offset) => { text_offset }
+        _ => { bytes.len() }
+    };
 
-        let _ = data.next();
-        idx += 1;
+    let mut ops: Vec<Opcode> = Vec::new();
+    let mut idx = 16usize;
+    while idx < limit && bytes[idx] == 0x70 {
+        let op = decode_op(bytes, idx, limit)?;
+        idx += op.hexcode.len();
+        ops.push(op);
+    }
 
-        let cmd: u8 = match data.next() {
-            Some(op) => { op }
-            None => { eyre::bail!("End of file found prematurly") }
-        };
-        idx += 1;
-
-        let opcode_info: (&str, u8) = match vec![cmd].encode_hex::<String>().as_str() {
-            "00" => ("0x00", 2u8),
-            "02" => ("Text", 2u8),
-            "03" => ("TextBoxFormat", 1u8),
-            "04" => ("PostProcessingFilter", 4u8),
-            "05" => ("Movie", 2u8),
-            "06" => ("Animation", 8u8),
-            "08" => ("Voice", 5u8),
-            "09" => ("Music", 3u8),
-            "0a" => ("Sound", 3u8),
-            "0b" => ("SoundB", 2u8),
-            "0c" => ("AddTruthBullets", 2u8),
-            "0d" => ("AddPresents", 3u8),
-            "0e" => ("UnlockSkill", 2u8),
-            "0f" => ("StudentTitleEntry", 3u8),
-            "14" => ("TrialCamera", 3u8),
-            "15" => ("LoadMap", 3u8),
-            "19" => ("LoadScript", 3u8),
-            "1a" => ("StopScript", 0u8),
-            "1b" => ("RunScript", 3u8),
-            "1c" => ("0x1C", 0u8),
-            "1e" => ("Sprite", 5u8),
-            "1f" => ("ScreenFlash", 7u8),
-            "20" => ("SpriteFlash", 5u8),
-            "21" => ("Speaker", 1u8),
-            "22" => ("ScreenFade", 3u8),
-            "25" => ("ChangeUi", 2u8),
-            "26" => ("SetFlag", 3u8),
-            "27" => ("CheckCharacter", 1u8),
-            "29" => ("CheckObject", 1u8),
-            "2a" => ("SetLabel", 2u8),
-            "2b" => ("SetChoiceText", 1u8),
-            "2e" => ("CameraShake", 2u8),
-            "30" => ("ShowBackground", 3u8),
-            "33" => ("0x33", 4u8),
-            "34" => ("GoToLabel", 2u8),
-            "35" => ("CheckFlagA", 255u8),
-            "36" => ("CheckFlagB", 255u8),
-            "3a" => ("WaitInput", 0u8),
-            "3b" => ("WaitFrame", 0u8),
-            "3c" => ("IfFlagCheck", 0u8),
-            default => {
-                for line in ops {
-                    println!("{}", line);
-                }
-                eyre::bail!("Invalid opcode '{}' at index {}", default, idx);
-            }
-        };
+    // SECTIONS 2 AND 3 [ STRING TABLE AND TEXT ]
+    let (text_entries, problem) = decode_text(bytes, idx);
+    if let Some(problem) = problem {
+        return Err(problem.into());
+    }
 
-        // Special Cases
-        // Check Flag A and B can have different numbers of arguments.
-        // There is a pattern, but more research needed for confirmation.
-        if opcode_info.0 == "CheckFlagA" {
-            let mut temp_hex_vec: Vec<u8> = vec![0x70, 0x00];
+    Ok(LinScript { ops, text_entries })
+}
 
-            while data.peek() != Some(&0x70) {
-                temp_hex_vec.push(data.next().expect("This better not be called (decompiler CheckFlagA)"));
-            }
 
-            ops.push(Opcode {
-                name: opcode_info.0.to_string(),
-                hexcode: temp_hex_vec,
-                text_id: None
-            });
+/// What `decode_lenient` could make of a damaged file.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Recovered {
+    pub script: LinScript,
+    /// Everything that was wrong, in the order it was found.
+    pub problems: Vec<LinError>,
+}
 
-            continue;
+
+/// `decode`, but carrying on past problems to salvage what it can.
+///
+/// A bad header field is noted and otherwise ignored. Where an opcode is unknown or its arguments
+/// are cut short, decoding picks up again at the next 0x70 that starts a whole opcode (or the
+/// string table), and the bytes in between are kept as a `Raw(...)` opcode, so they go back
+/// into the file unchanged when it's recompiled.
+pub fn decode_lenient(bytes: &[u8]) -> Recovered {
+    let mut problems = header_problems(bytes);
+    if bytes.len() < 16 {
+        problems.push(LinError::Truncated { offset: bytes.len(), message: "File ends inside the header".to_string() });
+        return Recovered { script: LinScript::default(), problems };
+    }
+
+    let size = LittleEndian::read_u32(&bytes[12..16]) as usize;
+    if size > bytes.len() {
+        problems.push(LinError::Truncated { offset: bytes.len(), message: format!("File ends at {:#x}, the header says {:#x}", bytes.len(), size) });
+    } else if size < bytes.len() {
+        problems.push(LinError::Format { offset: 12, message: format!("File size in the header is {:#x}, it's really {:#x}", size, bytes.len()) });
+    }
+
+    // The header's text offset is where the opcodes end, when it can be believed.
+    // Otherwise they end where the string table's first line does, at the latest.
+    let text_offset = LittleEndian::read_u32(&bytes[8..12]) as usize;
+    let trusted = (16..=bytes.len()).contains(&text_offset);
+    let limit = if trusted {
+        text_offset
+    } else {
+        problems.push(LinError::Format { offset: 8, message: format!("Text offset {:#x} is outside the file", text_offset) });
+        bytes.windows(2).skip(16).position(|pair| pair == [0xFF, 0xFE]).map_or(bytes.len(), |pos| pos + 16)
+    };
+
+    let mut ops: Vec<Opcode> = Vec::new();
+    let mut idx = 16usize;
+    while idx < limit {
+        // Padding before the text
+        if bytes[idx..limit].iter().all(|&byte| byte == 0x00) {
+            break;
         }
 
-        if opcode_info.0 == "CheckFlagB" {
-            let mut temp_hex_vec: Vec<u8> = vec![0x70, 0x00];
+        if bytes[idx] != 0x70 {
+            // Without a text offset to go by, this is where the text begins, as for `decode`
+            if !trusted {
+                break;
+            }
+            let next = resync(bytes, idx + 1, limit);
+            problems.push(LinError::Format { offset: idx, message: format!("{} bytes that aren't an opcode", next - idx) });
+            ops.push(raw(&bytes[idx..next]));
+            idx = next;
+            continue;
+        }
 
-            while data.peek() != Some(&0x70) {
-                temp_hex_vec.push(data.next().expect("This better not be called (decompiler CheckFlagB)"));
+        match decode_op(bytes, idx, limit) {
+            Ok(op) => {
+                idx += op.hexcode.len();
+                ops.push(op);
             }
+            Err(problem) => {
+                let next = resync(bytes, idx + 1, limit);
+                problems.push(problem);
+                ops.push(raw(&bytes[idx..next]));
+                idx = next;
+            }
+        }
+    }
 
-            ops.push(Opcode {
-                name: opcode_info.0.to_string(),
-                hexcode: temp_hex_vec,
-                text_id: None
-            });
+    let (text_entries, problem) = decode_text(bytes, if trusted { limit } else { idx });
+    problems.extend(problem);
 
-            continue;
+    let mut offset = 16usize;
+    for op in &ops {
+        if let Some(text_id) = op.text_id
+            && text_id as usize >= text_entries.len()
+        {
+            problems.push(LinError::Format { offset, message: format!("Text({}) has no line of text", text_id) });
         }
+        offset += op.hexcode.len();
+    }
+
+    Recovered { script: LinScript { ops, text_entries }, problems }
+}
 
-        // Text's argument is the array index of it's corresponding line of text
-        // Stored at end of file, grabbed later.
-        if opcode_info.0 == "Text" {
-            let mut temp_hex_vec: Vec<u8> = vec![0x70, 0x00];
 
-            temp_hex_vec.push(match data.next() {
-                Some(op) => { op }
-                None => { eyre::bail!("End of file found prematurly (0x70, 0x02, ---)") }
-            });
+// The header is `2, 16, text offset, file size`, each a little endian u32.
+// Only the first two can be checked without reading the rest.
+fn header_problems(bytes: &[u8]) -> Vec<LinError> {
+    let mut problems: Vec<LinError> = Vec::new();
 
-            temp_hex_vec.push(match data.next() {
-                Some(op) => { op }
-                None => { eyre::bail!("End of file found prematurly (0x70, 0x02, 0xXX, ---)") }
-            });
+    if bytes.first() != Some(&0x02) {
+        problems.push(LinError::Format { offset: 0, message: "Not a valid .lin file (Header Inconsistency)".to_string() });
+    }
+    if bytes.get(4) != Some(&0x10) {
+        problems.push(LinError::Format { offset: 4, message: "Not a valid .lin file (Header Inconsistency)".to_string() });
+    }
 
-            ops.push(Opcode {
-                // The only instance of Big Endian in this entire stupid format.
-                text_id: Some(BigEndian::read_u16(&temp_hex_vec[2..4])),
-                name: opcode_info.0.to_string(),
-                hexcode: temp_hex_vec
-            });
+    problems
+}
 
-            continue;
-        }
 
-        // Get the hex values
-        // I'm realizing there are some redundancies here.
-        // Might need refactoring
-        let mut temp_hex_vec: Vec<u8> = vec![0x70, 0x00];
+// The opcode starting at the 0x70 at `idx`, which has to end by `limit`.
+fn decode_op(bytes: &[u8], idx: usize, limit: usize) -> Result<Opcode, LinError> {
+    let bytes = &bytes[..limit.min(bytes.len())];
 
-        for _ in 0..opcode_info.1 {
-            temp_hex_vec.push(match data.next() {
-                Some(op) => { op }
-                None => { eyre::bail!("End of file found prematurly.") }
-            });
-        }
+    let cmd: u8 = match bytes.get(idx + 1) {
+        Some(op) => { *op }
+        None => { return Err(truncated(idx + 1, "End of file found prematurly")) }
+    };
 
-        ops.push(Opcode {
-            name: opcode_info.0.to_string(),
-            hexcode: temp_hex_vec,
-            text_id: None
-        })
+    let opcode_info = match opcode::info_for_code(cmd) {
+        Some(info) => { info }
+        None => { return Err(LinError::UnknownOpcode { offset: idx, code: cmd }) }
+    };
+    let name = opcode_info.name.to_string();
+    let args_start = idx + 2;
+
+    // Special Cases
+    // Check Flag A and B can have different numbers of arguments.
+    // There is a pattern, but more research needed for confirmation.
+    if name == "CheckFlagA" || name == "CheckFlagB" {
+        return match bytes[args_start..].iter().position(|&byte| byte == 0x70) {
+            Some(arg_count) => { Ok(Opcode { name, hexcode: bytes[idx..args_start + arg_count].to_vec(), text_id: None }) }
+            None => { Err(truncated(bytes.len(), &format!("End of file found prematurly ({})", opcode_info.name))) }
+        };
     }
 
-    // SECTION 2 [ SKIP ALL THE LINE ADDRESS DATA ]
-    // Don't really need this to read the text
-    // Recalculated when compiling back into hex
-    loop {
-        // For textless .lins
-        if data.peek() == None {
-            break;
+    // Text's argument is the array index of it's corresponding line of text
+    // Stored at end of file, grabbed later.
+    if name == "Text" {
+        if bytes.len() < args_start + 1 {
+            return Err(truncated(args_start, "End of file found prematurly (0x70, 0x02, ---)"));
         }
-        // This section ends with [0xFF, 0xFE]
-        if data.next() == Some(0xFF) { 
-            if data.next() == Some(0xFE) {
-                break;
-            }
+        if bytes.len() < args_start + 2 {
+            return Err(truncated(args_start + 1, "End of file found prematurly (0x70, 0x02, 0xXX, ---)"));
         }
+
+        return Ok(Opcode {
+            // The only instance of Big Endian in this entire stupid format.
+            text_id: Some(BigEndian::read_u16(&bytes[args_start..args_start + 2])),
+            name,
+            hexcode: bytes[idx..args_start + 2].to_vec(),
+        });
     }
-    
 
-    // SECTION 3 [ THE TEXT SCRIPT ]
-    // Lines of text are null-terminated strings seperated by [0xFF, 0xFE]
+    let end = args_start + opcode_info.arg_count as usize;
+    if bytes.len() < end {
+        return Err(truncated(bytes.len(), "End of file found prematurly."));
+    }
+
+    Ok(Opcode { name, hexcode: bytes[idx..end].to_vec(), text_id: None })
+}
+
+
+// The next offset from `from` where a whole opcode starts and is followed by another, the padding or `limit`.
+fn resync(bytes: &[u8], from: usize, limit: usize) -> usize {
+    let next = (from..limit)
+        .find(|&idx| {
+            bytes[idx] == 0x70 && decode_op(bytes, idx, limit).is_ok_and(|op| {
+                let end = idx + op.hexcode.len();
+                end == limit || bytes[end] == 0x70 || bytes[end..limit].iter().all(|&byte| byte == 0x00)
+            })
+        })
+        .unwrap_or(limit);
+
+    // Leave the padding out of a Raw that runs to the end
+    if next == limit {
+        let padding = bytes[from..limit].iter().rev().take_while(|&&byte| byte == 0x00).count();
+        return limit - padding;
+    }
+    next
+}
+
+
+/// Bytes `decode_lenient` couldn't make sense of, written back out as they are.
+pub fn raw(bytes: &[u8]) -> Opcode {
+    Opcode { name: "Raw".to_string(), hexcode: bytes.to_vec(), text_id: None }
+}
+
+
+// SECTION 2 [ SKIP ALL THE LINE ADDRESS DATA ]
+// Don't really need this to read the text, it's recalculated when compiling back into hex.
+// SECTION 3 [ THE TEXT SCRIPT ]
+// Lines of text are null-terminated UTF-16 strings, each after [0xFF, 0xFE].
+// Returns the lines up to anything that stops it, and what that was.
+fn decode_text(bytes: &[u8], start: usize) -> (Vec<String>, Option<LinError>) {
     let mut text_entries: Vec<String> = Vec::new();
+
+    // This section ends with [0xFF, 0xFE]
+    let Some(first) = bytes.get(start..).and_then(|rest| rest.windows(2).position(|pair| pair == [0xFF, 0xFE])) else {
+        return (text_entries, None);
+    };
+    let mut idx = start + first;
+
     loop {
-        if data.peek() == None {
+        // Padding out to a multiple of 4 after the last line
+        if bytes[idx..].iter().all(|&byte| byte == 0x00) {
             break;
         }
 
         // Use up the line seperator
-        if data.peek() == Some(&0xFF) {
-            let _ = (data.next(), data.next());
+        if bytes[idx] == 0xFF {
+            idx += 2;
         }
 
-        // Collect each character (2 Bytes) and treat as ascii.
-        // This implies the ability to use UTF-8
-        // More research needed
-        let line: String = {
-            let mut next_string_chars: Vec<char> = Vec::new();
-
-            loop {
-                let next_char: char = 
-                    String::from_utf16(
-                        &[LittleEndian::read_u16(&[
-                            match data.next() {
-                                Some(op) => { op }
-                                None => { eyre::bail!("End of file found prematurly") }
-                            }, 
-                            match data.next() {
-                                Some(op) => { op }
-                                None => { eyre::bail!("End of file found prematurly") }
-                            }
-                        ])]).unwrap().chars().next().unwrap();
-                
-                // let next_char: u8 = data.next().unwrap();
-                // let extra: u8 = data.next().unwrap();
-                
-                // // BACKSLASH
-                // // Newlines should be written in plaintext
-                // // Converted back into 0x0A when compiling.
-                if next_char == 0x0A as char {
-                    next_string_chars.push('\\');
-                    next_string_chars.push('n');
-                    continue;
-                }
-
-                // NULL Character
-                // Ends the line
-                if next_char == 0x00 as char {
-                    break;
-                }
-
-                next_string_chars.push(next_char as char);
+        // Newlines are written in plaintext, converted back into 0x0A when compiling.
+        let mut units: Vec<u16> = Vec::new();
+        loop {
+            let Some(pair) = bytes.get(idx..idx + 2) else {
+                text_entries.push(text_line(&units));
+                return (text_entries, Some(truncated(bytes.len(), "End of file found prematurly")));
+            };
+            idx += 2;
+
+            // NULL Character ends the line
+            match LittleEndian::read_u16(pair) {
+                0x00 => { break; }
+                unit => { units.push(unit); }
             }
+        }
+        text_entries.push(text_line(&units));
 
-            next_string_chars.into_iter().collect()
-        };
-        text_entries.push(line);
+        if idx >= bytes.len() {
+            break;
+        }
     }
 
-    log::info!("decompiled file");
+    (text_entries, None)
+}
+
+
+fn text_line(units: &[u16]) -> String {
+    String::from_utf16_lossy(units).replace('\n', "\\n")
+}
+
+
+fn truncated(offset: usize, message: &str) -> LinError {
+    LinError::Truncated { offset, message: message.to_string() }
+}
+
+
+/// Writes the brace-indented .txt form of a script. Returns where each opcode was written.
+pub fn write_script<W: Write>(file: &mut W, script: &LinScript, options: &DecompileOptions) -> eyre::Result<Vec<Position>> {
+    write_source(file, script, &Comments::default(), options)
+}
 
-    //let output_filename = filename.rsplit_once("/").unwrap().1.split(".").next().unwrap();
-    let output_filename = match filename.rsplit_once("/") {
-        // Grab everything after the last slash in the filepath
-        Some((_, output_file_with_extension)) => {
-            // Remove the file extension
-            match output_file_with_extension.split(".").next() {
-                Some(output_filename) => { output_filename }
-                // This'll call if the input file doesn't have an extension
-                None => { output_file_with_extension }
-            }
-        },
-        None => { eyre::bail!("Output Directory not found") }
-    };
 
-    let mut file = File::create(output_folder + "/" + output_filename + ".txt").wrap_err("Output Directory not found")?;
-    
+/// Writes the brace-indented .txt form of a script, putting `comments` back by their opcodes.
+/// This layout is the canonical one the formatter holds files to. Returns where each opcode was written.
+pub fn write_source<W: Write>(file: &mut W, script: &LinScript, comments: &Comments, options: &DecompileOptions) -> eyre::Result<Vec<Position>> {
+    let speakers = if options.annotate_speakers {
+        script.speakers()
+    } else {
+        Vec::new()
+    };
+    let mut speakers = speakers.iter().peekable();
 
     // AND write it all down
     let mut indent_level = 0usize;
     let mut flag_check: bool  = false;
     let mut in_choice_text: bool = false;
     let mut line_idx = 1usize;
+    let mut positions: Vec<Position> = Vec::with_capacity(script.ops.len());
 
     // Write each opcode down
-    for line in ops {
+    for (op_idx, line) in script.ops.iter().enumerate() {
+        let leading = comments.leading.get(&op_idx);
+        let trailing = comments.trailing.get(&op_idx).map(String::as_str);
+
         // Flag check runs the next line only if it passes
         if flag_check {
-            write!(file, "{}{{\n"  , indent(indent_level))
+            writeln!(file, "{}{{"  , indent(indent_level))
+                .wrap_err(format!("Could not write line {}", line_idx))?;
+            line_idx += 1;
+            // It can be a line of text as well
+            let op = match script.text_for(line) {
+                Some(text_line) => { format!("Text(\"{}\")", text_line) }
+                None => { line.to_string() }
+            };
+            let annotation = speakers
+                .next_if(|info| info.op_index == op_idx)
+                .map(|info| format!("// {}", speaker_annotation(info)));
+            let trailing = trailing.or(annotation.as_deref());
+
+            positions.push(write_op(file, &mut line_idx, indent_level+1, &op, leading, trailing)
+                .wrap_err(format!("Could not write line {}", line_idx))?);
+            writeln!(file, "{}}}"  , indent(indent_level))
                 .wrap_err(format!("Could not write line {}", line_idx))?;
-            write!(file, "{}{}\n"  , indent(indent_level+1), line)
-                .wrap_err(format!("Could not write line {}", line_idx+1))?;
-            write!(file, "{}}}\n"  , indent(indent_level))
-                .wrap_err(format!("Could not write line {}", line_idx+2))?;
 
-            line_idx += 3;
+            line_idx += 1;
             flag_check = false;
             continue;
         }
-        
+
         match line.name.as_str() {
-            "CheckCharacter" => {
+            "CheckCharacter" | "CheckObject" => {
                 while indent_level > 0 {
                     indent_level -= 1;
-                    write!(file, "{}}}\n", indent(indent_level))
+                    writeln!(file, "{}}}", indent(indent_level))
                         .wrap_err(format!("Could not write line {}", line_idx))?;
                     line_idx += 1;
                     in_choice_text = false;
                 }
-                
-                write!(file, "{}{}\n", indent(indent_level), line)
-                    .wrap_err(format!("Could not write line {}", line_idx))?;
-                write!(file, "{}{{\n", indent(indent_level))
-                    .wrap_err(format!("Could not write line {}", line_idx+1))?;
-
-                line_idx += 2;
-                indent_level += 1;
-            }
-            "CheckObject" => {
-                while indent_level > 0 {
-                    indent_level -= 1;
-                    write!(file, "{}}}\n", indent(indent_level))
-                        .wrap_err(format!("Could not write line {}", line_idx))?;
 
-                    line_idx += 1;
-                    in_choice_text = false;
-                }
-
-                write!(file, "{}{}\n", indent(indent_level), line)
+                positions.push(write_op(file, &mut line_idx, indent_level, &line.to_string(), leading, trailing)
+                    .wrap_err(format!("Could not write line {}", line_idx))?);
+                writeln!(file, "{}{{", indent(indent_level))
                     .wrap_err(format!("Could not write line {}", line_idx))?;
-                write!(file, "{}{{\n", indent(indent_level))
-                    .wrap_err(format!("Could not write line {}", line_idx+1))?;
-                
-                line_idx += 2;
+
+                line_idx += 1;
                 indent_level += 1;
             }
             "IfFlagCheck" => {
-                write!(file, "{}{}\n", indent(indent_level), line)
-                    .wrap_err(format!("Could not write line {}", line_idx))?;
+                positions.push(write_op(file, &mut line_idx, indent_level, &line.to_string(), leading, trailing)
+                    .wrap_err(format!("Could not write line {}", line_idx))?);
 
-                line_idx += 1;
                 flag_check = true;
             }
 
             "SetChoiceText" => {
                 if in_choice_text {
                     indent_level -= 1;
-                    write!(file, "{}}}\n", indent(indent_level))
+                    writeln!(file, "{}}}", indent(indent_level))
                         .wrap_err(format!("Could not write line {}", line_idx))?;
-                    
+
                     line_idx += 1;
                 }
                 else {
                     in_choice_text = true;
                 }
 
-                write!(file, "{}{}\n", indent(indent_level), line)
+                positions.push(write_op(file, &mut line_idx, indent_level, &line.to_string(), leading, trailing)
+                    .wrap_err(format!("Could not write line {}", line_idx))?);
+                writeln!(file, "{}{{", indent(indent_level))
                     .wrap_err(format!("Could not write line {}", line_idx))?;
-                write!(file, "{}{{\n", indent(indent_level))
-                    .wrap_err(format!("Could not write line {}", line_idx+1))?;
 
-                line_idx += 2;
+                line_idx += 1;
                 indent_level += 1;
             }
 
             "Text" => {
-                let output = match line.text_id {
-                    Some(text_id) => { text_entries.get(text_id as usize) }
-                    // This shouldn't be called, as to get to this point, 
-                    // a text opcode object should always be instantiated with a text id.
-                    // This one's the consiquence of my actions and will probably
-                    // Be out of here after my opcode refactor.
-                    None => { eyre::bail!("Text opcode does not contain valid text id.") }
+                // Decoded Text always has an id, only one built by hand can lack it
+                let Some(text_id) = line.text_id else {
+                    eyre::bail!("Text opcode does not contain valid text id.")
                 };
 
-                match output {
+                match script.text_entries.get(text_id as usize) {
+                    // Kept byte for byte, so recompiling doesn't lose the opcode
                     None => {
-                        log::error!("Text line with id '{}' not found.", line.text_id.expect("Text id lost between line 376 and now."));
-                        continue;
+                        log::warn!("Text line with id '{}' not found, kept as Raw", text_id);
+                        positions.push(write_op(file, &mut line_idx, indent_level, &raw(&line.hexcode).to_string(), leading, trailing)
+                            .wrap_err(format!("Could not write line {}", line_idx))?);
                     }
-                    Some(text_line) => { write!(
-                        file, "{}Text(\"{}\")\n", 
-                        indent(indent_level), 
-                        text_line)
-                            .wrap_err(format!("Could not write line {}", line_idx))?;
-
-                        line_idx += 1;
+                    Some(text_line) => {
+                        // A comment from the source wins over a fresh annotation
+                        let annotation = speakers
+                            .next_if(|info| info.op_index == op_idx)
+                            .map(|info| format!("// {}", speaker_annotation(info)));
+                        let trailing = trailing.or(annotation.as_deref());
+
+                        positions.push(write_op(file, &mut line_idx, indent_level, &format!("Text(\"{}\")", text_line), leading, trailing)
+                            .wrap_err(format!("Could not write line {}", line_idx))?);
                     }
                 }
             }
             _ => {
-                write!(file, "{}{}\n", indent(indent_level), line)
-                    .wrap_err(format!("Could not write line {}", line_idx+1))?;
-                line_idx += 1;
+                positions.push(write_op(file, &mut line_idx, indent_level, &line.to_string(), leading, trailing)
+                    .wrap_err(format!("Could not write line {}", line_idx))?);
             }
         }
     }
 
     while indent_level > 0 {
         indent_level -= 1;
-        let _ = write!(file, "{}}}\n", indent(indent_level));
+        let _ = writeln!(file, "{}}}", indent(indent_level));
     }
-    
-    log::info!("wrote to file");
 
-    Ok(())
+    for comment in &comments.end {
+        writeln!(file, "{}", comment).wrap_err("Could not write comments at end of file")?;
+    }
+
+    Ok(positions)
+}
+
+
+// An opcode line, with the comments above and beside it. Moves line_idx past them and returns where the opcode went.
+fn write_op<W: Write>(file: &mut W, line_idx: &mut usize, level: usize, op: &str, leading: Option<&Vec<String>>, trailing: Option<&str>) -> std::io::Result<Position> {
+    for comment in leading.into_iter().flatten() {
+        writeln!(file, "{}{}", indent(level), comment)?;
+        *line_idx += 1;
+    }
+
+    match trailing {
+        Some(comment) => { writeln!(file, "{}{} {}", indent(level), op, comment)?; }
+        None => { writeln!(file, "{}{}", indent(level), op)?; }
+    }
+
+    let position = Position { line: *line_idx, column: indent(level).len() + 1 };
+    *line_idx += 1;
+    Ok(position)
 }
 
 
@@ -423,7 +580,259 @@ fn indent(amount: usize) -> String {
 }
 
 
+// "Makoto Naegi [sprite 14, voice 1:3]"
+fn speaker_annotation(info: &crate::speaker::SpeakerInfo) -> String {
+    let mut annotation = match info.speaker {
+        Some(speaker) => { characters::name_or_id(speaker) }
+        None => { "Unknown".to_string() }
+    };
 
+    let mut details: Vec<String> = Vec::new();
+    if let Some(sprite) = info.sprite {
+        details.push(format!("sprite {}", sprite));
+    }
+    if let Some(voice) = &info.voice {
+        details.push(format!("voice {}:{}", voice.chapter, voice.id));
+    }
 
+    if !details.is_empty() {
+        annotation += &format!(" [{}]", details.join(", "));
+    }
 
-//// [ TESTS ] ////
\ No newline at end of file
+    annotation
+}
+
+
+pub(crate) fn output_path(filename: &str, output_folder: String, extension: &str) -> eyre::Result<String> {
+    //let output_filename = filename.rsplit_once("/").unwrap().1.split(".").next().unwrap();
+    let output_filename = match filename.rsplit_once("/") {
+        // Grab everything after the last slash in the filepath
+        Some((_, output_file_with_extension)) => {
+            // Remove the file extension
+            match output_file_with_extension.split(".").next() {
+                Some(output_filename) => { output_filename }
+                // This'll call if the input file doesn't have an extension
+                None => { output_file_with_extension }
+            }
+        },
+        None => { eyre::bail!("Output Directory not found") }
+    };
+
+    Ok(output_folder + "/" + output_filename + "." + extension)
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::assets::asset_of;
+    use crate::cfg::ControlFlowGraph;
+    use crate::compiler::{encode, parse, parse_with_diagnostics};
+    use crate::error::LinError;
+    use crate::flags::conditions;
+    use crate::listing::listing;
+    use crate::lsp::diagnostics;
+    use crate::script::LinScript;
+    use crate::simulator::Simulator;
+
+    use super::{decode, decode_lenient, decompile_lin_with_options, write_script, DecompileOptions};
+
+    fn sample(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/../data/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn test_decode_keeps_opcode_byte() {
+        let script = decode(&sample("e01_104_001.lin")).unwrap();
+
+        // ChangeUi(21, 2)
+        assert_that!(script.ops[1].hexcode.clone(), equal_to(vec![0x70, 0x25, 0x15, 0x02]));
+        assert_that!(script.text_entries.len(), equal_to(5));
+    }
+
+    #[test]
+    fn test_annotated_text_lines() {
+        let script = decode(&sample("e01_103_000.lin")).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+
+        write_script(&mut output, &script, &DecompileOptions { annotate_speakers: true, ..Default::default() }).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_that!(output.contains(
+            "definitely assume there was a struggle.\\n\") // Makoto Naegi [sprite 0, voice 1:3]"
+        ), is(true));
+    }
+
+    #[test]
+    fn test_text_in_flag_check() {
+        // Text on its own in an IfFlagCheck block is still written with its line, and annotated
+        let source = "Speaker(0)\nIfFlagCheck()\n{\n    Text(\"Hi\")\n}\n";
+        let script = parse(source).unwrap().script;
+        let mut output: Vec<u8> = Vec::new();
+
+        write_script(&mut output, &script, &DecompileOptions { annotate_speakers: true, ..Default::default() }).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_that!(output.as_str(), equal_to("Speaker(0)\nIfFlagCheck()\n{\n    Text(\"Hi\") // Makoto Naegi\n}\n"));
+        assert_that!(parse(&output).unwrap().script, equal_to(script));
+    }
+
+    #[test]
+    fn test_decode_stops_at_text_offset() {
+        // With 112 lines, the count right after the opcodes starts with a 0x70
+        let source: String = (0..112).map(|idx| format!("Text(\"{}\")\n", idx)).collect();
+        let script = parse(&source).unwrap().script;
+        let bytes = encode(&script);
+        assert_that!(bytes[0x1D0], equal_to(0x70));
+
+        assert_that!(decode(&bytes).unwrap(), equal_to(script));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let header = [0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let error = |bytes: &[u8]| decode(bytes).unwrap_err().downcast::<LinError>().unwrap();
+
+        assert_that!(error(&sample("BROKENe01_100_000.lin")), equal_to(LinError::Format {
+            offset: 4,
+            message: "Not a valid .lin file (Header Inconsistency)".to_string(),
+        }));
+        // CheckFlagA runs up to the next 0x70, which never comes
+        assert_that!(error(&[&header[..], &[0x70, 0x35, 0x00, 0x01]].concat()), equal_to(LinError::Truncated {
+            offset: 20,
+            message: "End of file found prematurly (CheckFlagA)".to_string(),
+        }));
+        assert_that!(error(&[&header[..], &[0x70, 0x1C, 0x70, 0xFF]].concat()), equal_to(LinError::UnknownOpcode {
+            offset: 18,
+            code: 0xFF,
+        }));
+    }
+
+
+    #[test]
+    fn test_decode_lenient() {
+        let broken = decode_lenient(&sample("BROKENe01_100_000.lin"));
+        assert_that!(broken.problems.len(), equal_to(1));
+        assert_that!(broken.script, equal_to(decode(&sample("e01_100_000.lin")).unwrap()));
+
+        // A WaitInput's 0x3A knocked out, so its bytes are kept as they are and the rest still reads
+        let mut bytes = encode(&parse("Speaker(0)\nText(\"Hi\")\nWaitInput()\nWaitInput()\n").unwrap().script);
+        bytes[0x18] = 0xFF;
+        let recovered = decode_lenient(&bytes);
+
+        assert_that!(recovered.problems.clone(), equal_to(vec![LinError::UnknownOpcode { offset: 0x17, code: 0xFF }]));
+        let mut source: Vec<u8> = Vec::new();
+        write_script(&mut source, &recovered.script, &DecompileOptions::default()).unwrap();
+        let source = String::from_utf8(source).unwrap();
+        assert_that!(source.as_str(), equal_to("Speaker(0)\nText(\"Hi\")\nRaw(112, 255)\nWaitInput()\n"));
+        assert_that!(encode(&parse(&source).unwrap().script), equal_to(bytes.clone()));
+
+        // Cut off anywhere, it never gives up
+        let sample = sample("e01_100_000.lin");
+        for len in 0..sample.len() {
+            let recovered = decode_lenient(&sample[..len]);
+            assert_that!(recovered.problems.is_empty(), is(false));
+        }
+    }
+
+
+    #[test]
+    fn test_lenient_text_without_line() {
+        // The second Text points past the end of the string table
+        let mut bytes = encode(&parse("Text(\"Hi\")\nText(\"Bye\")\nWaitInput()\n").unwrap().script);
+        bytes[0x17] = 5;
+        let recovered = decode_lenient(&bytes);
+        assert_that!(recovered.problems.clone(), equal_to(vec![LinError::Format { offset: 0x14, message: "Text(5) has no line of text".to_string() }]));
+
+        let mut source: Vec<u8> = Vec::new();
+        write_script(&mut source, &recovered.script, &DecompileOptions::default()).unwrap();
+        let source = String::from_utf8(source).unwrap();
+        assert_that!(source.as_str(), equal_to("Text(\"Hi\")\nRaw(112, 2, 0, 5)\nWaitInput()\n"));
+
+        // Every opcode survives being compiled again
+        assert_that!(decode_lenient(&encode(&parse(&source).unwrap().script)).script.ops, equal_to(recovered.script.ops));
+    }
+
+
+    #[test]
+    fn test_lenient_listing() {
+        // The listing is as forgiving as the decompile it comes with
+        let folder = std::env::temp_dir().join(format!("dgrlin-lenient-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let filename = format!("{}/../data/BROKENe01_100_000.lin", env!("CARGO_MANIFEST_DIR"));
+        let options = DecompileOptions { listing: true, lenient: true, ..Default::default() };
+
+        decompile_lin_with_options(filename, folder.to_string_lossy().to_string(), &options).unwrap();
+        let listed = std::fs::read_to_string(folder.join("BROKENe01_100_000.lst")).unwrap();
+        assert_that!(listed.ends_with("; problems\n;   0x4: Not a valid .lin file (Header Inconsistency)\n"), is(true));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_raw_in_analyses() {
+        // A stray byte between two opcodes is kept as Raw(171), too short to have arguments
+        let mut bytes = encode(&parse("Speaker(0)\nText(\"Hi\")\nWaitInput()\n").unwrap().script);
+        bytes.insert(0x17, 0xAB);
+        bytes.remove(0x1A);
+        let script = decode_lenient(&bytes).script;
+        assert_that!(script.ops[2].hexcode.clone(), equal_to(vec![0xAB]));
+
+        let mut output: Vec<u8> = Vec::new();
+        write_script(&mut output, &script, &DecompileOptions { annotate_speakers: true, ..Default::default() }).unwrap();
+        assert_that!(String::from_utf8(output).unwrap().contains("Raw(171)\n"), is(true));
+        analyse(&script);
+    }
+
+    // Everything that reads a script, as the fuzz targets run them
+    fn analyse(script: &LinScript) {
+        let options = DecompileOptions { annotate_speakers: true, ..Default::default() };
+        write_script(&mut std::io::sink(), script, &options).unwrap();
+        assert_that!(script.to_json_with(true).is_ok(), is(true));
+
+        let cfg = ControlFlowGraph::build(script);
+        let _ = (cfg.to_dot(script), cfg.to_mermaid(script));
+        Simulator::new(script).run();
+        for op in &script.ops {
+            let _ = (conditions(op), asset_of(op));
+        }
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        // The samples, and the inputs the fuzz targets have broken before
+        let root = env!("CARGO_MANIFEST_DIR");
+        let mut files: Vec<_> = std::fs::read_dir(format!("{}/../data", root)).unwrap()
+            .chain(std::fs::read_dir(format!("{}/fuzz/regressions", root)).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+
+        for path in files {
+            let bytes = std::fs::read(&path).unwrap();
+            if path.extension().is_some_and(|ext| ext == "txt") {
+                let source = String::from_utf8_lossy(&bytes);
+                let _ = diagnostics(&source);
+                analyse(&parse_with_diagnostics(&source).0.script);
+                continue;
+            }
+
+            let _ = listing(&bytes);
+            analyse(&decode_lenient(&bytes).script);
+            if let Ok(script) = decode(&bytes) {
+                analyse(&script);
+                let bytes = encode(&script);
+                assert_that!(decode(&bytes).unwrap(), equal_to(script));
+            }
+        }
+
+        // 112 lines of text: the count is not an opcode
+        let script = decode(&std::fs::read(format!("{}/fuzz/regressions/count_112.lin", root)).unwrap()).unwrap();
+        assert_that!(script.ops.len(), equal_to(1));
+        assert_that!(script.text_entries.len(), equal_to(112));
+    }
+}
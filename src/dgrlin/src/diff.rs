@@ -0,0 +1,265 @@
+use serde::Serialize;
+
+use crate::opcode::Opcode;
+use crate::script::LinScript;
+
+
+/// One difference between two scripts. Indexes are opcode indexes into each side.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    Inserted { b_index: usize, opcode: String },
+    Removed { a_index: usize, opcode: String },
+    /// The same opcode with different arguments.
+    Changed { a_index: usize, b_index: usize, old: String, new: String },
+    /// A Text that lines up on both sides, but says something different.
+    TextChanged { a_index: usize, b_index: usize, old: String, new: String },
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct ScriptDiff {
+    pub changes: Vec<Change>,
+}
+
+impl ScriptDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+
+    pub fn to_json(&self) -> eyre::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+
+/// How two opcodes line up in an LCS alignment.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Edit {
+    Same(usize, usize),
+    Removed(usize),
+    Inserted(usize),
+}
+
+
+/// Lines `a` up against `b` along their longest common subsequence.
+/// The shared start and end are trimmed off first, so small edits to a big script stay cheap,
+/// and the rest is found in linear space, so two unrelated big scripts don't need an n·m table.
+pub fn align<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut edits: Vec<Edit> = (0..prefix).map(|idx| Edit::Same(idx, idx)).collect();
+    split(middle_a, middle_b, prefix, prefix, &mut edits);
+    edits.extend((0..suffix).map(|idx| Edit::Same(a.len() - suffix + idx, b.len() - suffix + idx)));
+
+    edits
+}
+
+
+// Hirschberg's LCS: split `a` in half, find where in `b` the two halves' LCS meet best,
+// and solve each side on its own. Only two rows of the usual table are ever kept.
+fn split<T: PartialEq>(a: &[T], b: &[T], a_start: usize, b_start: usize, edits: &mut Vec<Edit>) {
+    let inserted = |range: std::ops::Range<usize>| range.map(move |j| Edit::Inserted(b_start + j));
+
+    match a {
+        [] => { edits.extend(inserted(0..b.len())); }
+        [only] => {
+            match b.iter().position(|item| item == only) {
+                Some(j) => {
+                    edits.extend(inserted(0..j));
+                    edits.push(Edit::Same(a_start, b_start + j));
+                    edits.extend(inserted(j + 1..b.len()));
+                }
+                None => {
+                    edits.extend(inserted(0..b.len()));
+                    edits.push(Edit::Removed(a_start));
+                }
+            }
+        }
+        _ if b.is_empty() => { edits.extend((0..a.len()).map(|i| Edit::Removed(a_start + i))); }
+        _ => {
+            let mid = a.len() / 2;
+            let before = lcs_row(a[..mid].iter(), b.iter());
+            let after = lcs_row(a[mid..].iter().rev(), b.iter().rev());
+
+            // The first place the two halves add up to the longest
+            let m = b.len();
+            let at = (0..=m).rev().max_by_key(|&j| before[j] + after[m - j]).unwrap_or(0);
+
+            split(&a[..mid], &b[..at], a_start, b_start, edits);
+            split(&a[mid..], &b[at..], a_start + mid, b_start + at, edits);
+        }
+    }
+}
+
+
+// The last row of the LCS table: row[j] is the LCS of all of `a` and the first j of `b`.
+fn lcs_row<'a, T: PartialEq + 'a>(
+    a: impl Iterator<Item = &'a T>,
+    b: impl Iterator<Item = &'a T> + Clone,
+) -> Vec<u32> {
+    let mut row = vec![0u32; b.clone().count() + 1];
+    for item_a in a {
+        let mut diagonal = 0u32;
+        for (j, item_b) in b.clone().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if item_a == item_b { diagonal + 1 } else { above.max(row[j]) };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+
+/// What an opcode is compared by. Every Text matches every other Text,
+/// so reworded dialog lines up and shows as a TextChanged instead of breaking the alignment.
+pub fn key(op: &Opcode) -> String {
+    if op.name == "Text" {
+        return op.name.clone();
+    }
+    op.to_string()
+}
+
+
+/// An opcode as it would be written in a .txt, with the actual line for a Text.
+pub fn describe(script: &LinScript, op: &Opcode) -> String {
+    match script.text_for(op) {
+        Some(text) => { format!("Text(\"{}\")", text) }
+        None => { op.to_string() }
+    }
+}
+
+
+pub fn diff(a: &LinScript, b: &LinScript) -> ScriptDiff {
+    let keys_a: Vec<String> = a.ops.iter().map(key).collect();
+    let keys_b: Vec<String> = b.ops.iter().map(key).collect();
+
+    let mut changes: Vec<Change> = Vec::new();
+    // Removals and insertions since the last opcode both sides share
+    let mut removed: Vec<usize> = Vec::new();
+    let mut inserted: Vec<usize> = Vec::new();
+
+    for edit in align(&keys_a, &keys_b) {
+        match edit {
+            Edit::Removed(a_index) => { removed.push(a_index); }
+            Edit::Inserted(b_index) => { inserted.push(b_index); }
+            Edit::Same(a_index, b_index) => {
+                flush(a, b, &mut removed, &mut inserted, &mut changes);
+
+                let (old, new) = (a.text_for(&a.ops[a_index]), b.text_for(&b.ops[b_index]));
+                if old != new {
+                    changes.push(Change::TextChanged {
+                        a_index,
+                        b_index,
+                        old: old.cloned().unwrap_or_default(),
+                        new: new.cloned().unwrap_or_default(),
+                    });
+                }
+            }
+        }
+    }
+    flush(a, b, &mut removed, &mut inserted, &mut changes);
+
+    ScriptDiff { changes }
+}
+
+
+// Pairs up the removals and insertions between two shared opcodes.
+// The n-th removal and n-th insertion are one Changed when they're the same opcode.
+fn flush(a: &LinScript, b: &LinScript, removed: &mut Vec<usize>, inserted: &mut Vec<usize>, changes: &mut Vec<Change>) {
+    let mut paired = 0usize;
+    while paired < removed.len().min(inserted.len()) && a.ops[removed[paired]].name == b.ops[inserted[paired]].name {
+        changes.push(Change::Changed {
+            a_index: removed[paired],
+            b_index: inserted[paired],
+            old: describe(a, &a.ops[removed[paired]]),
+            new: describe(b, &b.ops[inserted[paired]]),
+        });
+        paired += 1;
+    }
+
+    for a_index in removed.drain(..).skip(paired) {
+        changes.push(Change::Removed { a_index, opcode: describe(a, &a.ops[a_index]) });
+    }
+    for b_index in inserted.drain(..).skip(paired) {
+        changes.push(Change::Inserted { b_index, opcode: describe(b, &b.ops[b_index]) });
+    }
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::compiler::parse;
+    use crate::script::LinScript;
+
+    use super::{align, diff, Change, Edit};
+
+    fn script(source: &str) -> LinScript {
+        parse(source).unwrap().script
+    }
+
+    #[test]
+    fn test_align() {
+        let edits = align(&["a", "b", "c", "d"], &["a", "c", "x", "d"]);
+
+        assert_that!(edits, equal_to(vec![
+            Edit::Same(0, 0),
+            Edit::Removed(1),
+            Edit::Same(2, 1),
+            Edit::Inserted(2),
+            Edit::Same(3, 3),
+        ]));
+    }
+
+    #[test]
+    fn test_align_long_scripts() {
+        // Every third opcode gone from one side and every fifth changed on the other
+        let a: Vec<usize> = (0..2000).filter(|i| i % 3 != 0).collect();
+        let b: Vec<usize> = (0..2000).map(|i| if i % 5 == 0 { usize::MAX } else { i }).collect();
+
+        let edits = align(&a, &b);
+
+        let (mut next_a, mut next_b, mut same) = (0usize, 0usize, 0usize);
+        for edit in edits {
+            match edit {
+                Edit::Same(i, j) => {
+                    assert_that!((i, j, a[i]), equal_to((next_a, next_b, b[j])));
+                    (next_a, next_b, same) = (i + 1, j + 1, same + 1);
+                }
+                Edit::Removed(i) => {
+                    assert_that!(i, equal_to(next_a));
+                    next_a += 1;
+                }
+                Edit::Inserted(j) => {
+                    assert_that!(j, equal_to(next_b));
+                    next_b += 1;
+                }
+            }
+        }
+        assert_that!((next_a, next_b), equal_to((a.len(), b.len())));
+        assert_that!(same, equal_to(2000 - 667 - 400 + 134));
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = script("Speaker(0)\nText(\"Hello\")\nSprite(0, 0, 1, 0, 0)\nWaitInput()\nText(\"Bye\")\n");
+        let b = script("Speaker(0)\nText(\"Hello!\")\nSprite(0, 0, 2, 0, 0)\nWaitFrame()\nText(\"Bye\")\n");
+
+        assert_that!(diff(&a, &b).changes, equal_to(vec![
+            Change::TextChanged { a_index: 1, b_index: 1, old: "Hello".to_string(), new: "Hello!".to_string() },
+            Change::Changed { a_index: 2, b_index: 2, old: "Sprite(0, 0, 1, 0, 0)".to_string(), new: "Sprite(0, 0, 2, 0, 0)".to_string() },
+            Change::Removed { a_index: 3, opcode: "WaitInput()".to_string() },
+            Change::Inserted { b_index: 3, opcode: "WaitFrame()".to_string() },
+        ]));
+        assert_that!(diff(&a, &a).is_empty(), is(true));
+    }
+}
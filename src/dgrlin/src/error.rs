@@ -0,0 +1,66 @@
+use std::fmt;
+
+use crate::compiler::Diagnostic;
+
+
+/// Why a .lin wouldn't decode or a .txt wouldn't compile, for callers that handle each differently.
+/// These travel inside `eyre::Report`s, so `report.downcast_ref::<LinError>()` finds them.
+#[derive(PartialEq, Debug, Clone)]
+pub enum LinError {
+    /// Not laid out like a .lin. `offset` is where it stopped making sense.
+    Format { offset: usize, message: String },
+    /// The file ends partway through something.
+    Truncated { offset: usize, message: String },
+    /// An opcode byte the registry doesn't know, at the 0x70 before it.
+    UnknownOpcode { offset: usize, code: u8 },
+    /// Every problem in a .txt, with where the first one is.
+    Syntax { line: usize, column: usize, diagnostics: Vec<Diagnostic> },
+}
+
+impl LinError {
+    /// Builds a Syntax error, pointing at the first non-blank character of the first bad line.
+    pub fn syntax(source: &str, diagnostics: Vec<Diagnostic>) -> Self {
+        let line = diagnostics.first().map_or(0, |diagnostic| diagnostic.line);
+        let column = source.lines()
+            .nth(line.saturating_sub(1))
+            .map_or(1, |text| text.chars().take_while(|c| c.is_whitespace()).count() + 1);
+
+        LinError::Syntax { line, column, diagnostics }
+    }
+
+
+    /// The byte in the .lin it happened at, None for a .txt.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            LinError::Format { offset, .. } | LinError::Truncated { offset, .. } | LinError::UnknownOpcode { offset, .. } => { Some(*offset) }
+            LinError::Syntax { .. } => { None }
+        }
+    }
+}
+
+impl fmt::Display for LinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinError::Format { message, .. } => { write!(f, "{}", message) }
+            LinError::Truncated { message, .. } => { write!(f, "{}", message) }
+            LinError::UnknownOpcode { offset, code } => { write!(f, "Invalid opcode '{:02x}' at index {}", code, offset) }
+            LinError::Syntax { diagnostics, .. } => {
+                let errors: Vec<String> = diagnostics.iter().map(Diagnostic::to_string).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinError {}
+
+
+/// Context naming the file a `LinError` came from.
+#[derive(PartialEq, Debug, Clone)]
+pub struct InFile(pub String);
+
+impl fmt::Display for InFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "In \"{}\"", self.0)
+    }
+}
@@ -0,0 +1,273 @@
+use eyre::Context;
+use log;
+use serde::{Serialize, Serializer};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+use crate::opcode::Opcode;
+use crate::project::Project;
+
+
+/// A game flag, written `group:id` like `2:15`.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct FlagId {
+    pub group: u8,
+    pub id: u8,
+}
+
+impl fmt::Display for FlagId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.group, self.id)
+    }
+}
+
+impl FromStr for FlagId {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> eyre::Result<Self> {
+        match value.split_once(':') {
+            Some((group, id)) => {
+                Ok(FlagId {
+                    group: group.trim().parse().wrap_err(format!("Bad flag group in '{}'", value))?,
+                    id: id.trim().parse().wrap_err(format!("Bad flag id in '{}'", value))?,
+                })
+            }
+            None => { eyre::bail!("Flags are written 'group:id', got '{}'", value) }
+        }
+    }
+}
+
+
+/// One `flag <comparison> value` test out of a CheckFlagA/B.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Condition {
+    pub flag: FlagId,
+    pub comparison: u8,
+    pub value: u16,
+}
+
+
+/// Splits a CheckFlagA/B into its conditions and the bytes joining them.
+///
+/// CheckFlagA tests are `group, id, comparison, value` and
+/// CheckFlagB tests are `group, id, comparison, value, value`,
+/// with a single joining byte between each test.
+/// Leftover bytes that don't make a whole test are ignored.
+pub fn conditions(op: &Opcode) -> (Vec<Condition>, Vec<u8>) {
+    let size = match op.name.as_str() {
+        "CheckFlagA" => { 4 }
+        "CheckFlagB" => { 5 }
+        _ => { return (Vec::new(), Vec::new()); }
+    };
+
+    let args = op.args();
+    let mut tests: Vec<Condition> = Vec::new();
+    let mut joins: Vec<u8> = Vec::new();
+    let mut idx = 0usize;
+
+    while idx + size <= args.len() {
+        let test = &args[idx..idx + size];
+        tests.push(Condition {
+            flag: FlagId { group: test[0], id: test[1] },
+            comparison: test[2],
+            value: if size == 4 { test[3] as u16 } else { u16::from_be_bytes([test[3], test[4]]) },
+        });
+        idx += size;
+
+        if idx < args.len() {
+            joins.push(args[idx]);
+            idx += 1;
+        }
+    }
+
+    (tests, joins)
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct FlagUse {
+    pub script: String,
+    pub op_index: usize,
+    /// SetFlag, CheckFlagA or CheckFlagB
+    pub opcode: String,
+    /// The value set, or the value compared against.
+    pub value: u16,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct FlagUsage {
+    pub set: Vec<FlagUse>,
+    pub tested: Vec<FlagUse>,
+}
+
+
+/// Every flag across a project, and where it's set or tested.
+/// Tests are recorded on the CheckFlagA/B, not the IfFlagCheck that acts on them.
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct FlagReport {
+    /// Written with `group:id` keys, in numeric order.
+    #[serde(serialize_with = "by_flag_name")]
+    pub flags: BTreeMap<FlagId, FlagUsage>,
+    pub tested_never_set: Vec<String>,
+    pub set_never_tested: Vec<String>,
+}
+
+impl FlagReport {
+    pub fn build(project: &Project) -> Self {
+        let mut flags: BTreeMap<FlagId, FlagUsage> = BTreeMap::new();
+
+        for (name, script) in &project.scripts {
+            for (op_index, op) in script.ops.iter().enumerate() {
+                match (op.name.as_str(), op.args()) {
+                    ("SetFlag", [group, id, value]) => {
+                        flags.entry(FlagId { group: *group, id: *id }).or_default().set.push(FlagUse {
+                            script: name.clone(),
+                            op_index,
+                            opcode: op.name.clone(),
+                            value: *value as u16,
+                        });
+                    }
+                    ("CheckFlagA" | "CheckFlagB", _) => {
+                        for test in conditions(op).0 {
+                            flags.entry(test.flag).or_default().tested.push(FlagUse {
+                                script: name.clone(),
+                                op_index,
+                                opcode: op.name.clone(),
+                                value: test.value,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let tested_never_set = flags
+            .iter()
+            .filter(|(_, usage)| usage.set.is_empty())
+            .map(|(flag, _)| flag.to_string())
+            .collect();
+        let set_never_tested = flags
+            .iter()
+            .filter(|(_, usage)| usage.tested.is_empty())
+            .map(|(flag, _)| flag.to_string())
+            .collect();
+
+        FlagReport {
+            flags,
+            tested_never_set,
+            set_never_tested,
+        }
+    }
+
+
+    pub fn to_json(&self) -> eyre::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+
+// JSON keys have to be strings, so flags are written by name, keeping the map's order.
+fn by_flag_name<S: Serializer>(flags: &BTreeMap<FlagId, FlagUsage>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(flags.iter().map(|(flag, usage)| (flag.to_string(), usage)))
+}
+
+
+/// Builds the flag report for every .lin in `folder`.
+pub fn flag_report(folder: &str) -> eyre::Result<FlagReport> {
+    let project = Project::load(folder)?;
+    let report = FlagReport::build(&project);
+
+    for flag in &report.tested_never_set {
+        log::warn!("flag {} is tested but never set", flag);
+    }
+
+    Ok(report)
+}
+
+
+/// Writes the flag report for `folder` to `output_folder/flags.json`.
+pub fn export_flag_report(folder: String, output_folder: String) -> eyre::Result<()> {
+    let report = flag_report(&folder)?;
+
+    let mut file = File::create(output_folder + "/flags.json")
+        .wrap_err("Output Directory not found")?;
+    file.write_all(report.to_json()?.as_bytes()).wrap_err("Could not write to file (Unknown issue)")?;
+
+    log::info!("wrote flag report");
+
+    Ok(())
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::opcode::Opcode;
+    use crate::project::Project;
+    use crate::script::LinScript;
+
+    use super::{conditions, Condition, FlagId, FlagReport};
+
+    fn op(line: &str) -> Opcode {
+        Opcode::try_from_string(line.to_string(), 0).0.unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        let (tests, joins) = conditions(&op("CheckFlagA(22, 5, 0, 1, 6, 2, 15, 0, 0)"));
+
+        assert_that!(tests, equal_to(vec![
+            Condition { flag: FlagId { group: 22, id: 5 }, comparison: 0, value: 1 },
+            Condition { flag: FlagId { group: 2, id: 15 }, comparison: 0, value: 0 },
+        ]));
+        assert_that!(joins, equal_to(vec![6]));
+
+        let (tests, _) = conditions(&op("CheckFlagB(0, 19, 0, 1, 2)"));
+        assert_that!(tests[0].value, equal_to(258));
+    }
+
+    #[test]
+    fn test_report_lists_unmatched_flags() {
+        let mut project = Project::default();
+        project.scripts.insert("e01_001_000".to_string(), LinScript {
+            ops: vec![
+                op("SetFlag(0, 7, 1)"),
+                op("SetFlag(0, 8, 1)"),
+                op("CheckFlagA(0, 7, 0, 1)"),
+                op("CheckFlagA(2, 15, 0, 1)"),
+                op("IfFlagCheck()"),
+            ],
+            text_entries: Vec::new(),
+        });
+
+        let report = FlagReport::build(&project);
+
+        assert_that!(report.flags[&FlagId { group: 0, id: 7 }].tested[0].op_index, equal_to(2));
+        assert_that!(report.tested_never_set, equal_to(vec!["2:15".to_string()]));
+        assert_that!(report.set_never_tested, equal_to(vec!["0:8".to_string()]));
+    }
+
+    #[test]
+    fn test_report_in_numeric_order() {
+        let mut project = Project::default();
+        project.scripts.insert("e01_001_000".to_string(), LinScript {
+            ops: vec![op("SetFlag(10, 1, 1)"), op("SetFlag(2, 1, 1)"), op("SetFlag(2, 10, 1)")],
+            text_entries: Vec::new(),
+        });
+
+        let json = FlagReport::build(&project).to_json().unwrap();
+        let positions: Vec<usize> = ["\"2:1\"", "\"2:10\"", "\"10:1\""].iter().map(|key| json.find(key).unwrap()).collect();
+
+        assert_that!(positions.is_sorted(), is(true));
+    }
+}
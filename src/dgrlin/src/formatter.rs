@@ -0,0 +1,83 @@
+use eyre::Context;
+
+use std::fs::{read_to_string, write};
+
+use crate::compiler;
+use crate::decompiler::{write_source, DecompileOptions};
+
+
+/// A .txt script laid out the way the decompiler writes it, comments included.
+/// Blank lines are dropped, and comments on brace lines move up above the next opcode.
+pub fn format_source(source: &str) -> eyre::Result<String> {
+    let parsed = compiler::parse(source)?;
+
+    let mut formatted: Vec<u8> = Vec::new();
+    write_source(&mut formatted, &parsed.script, &parsed.comments, &DecompileOptions::default())?;
+
+    Ok(String::from_utf8(formatted)?)
+}
+
+
+/// Formats a .txt file in place, or only checks it when `check` is set.
+/// Returns whether the file was (or would be) changed.
+pub fn format_file(filename: &str, check: bool) -> eyre::Result<bool> {
+    let source = read_to_string(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+    let formatted = format_source(&source).wrap_err(format!("Could not parse \"{}\"", filename))?;
+
+    if formatted == source {
+        return Ok(false);
+    }
+
+    if !check {
+        write(filename, formatted).wrap_err("Could not write to file (Unknown issue)")?;
+    }
+
+    Ok(true)
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use super::format_source;
+
+    #[test]
+    fn test_format_source() {
+        let source = "\
+// Opening
+CheckCharacter(3)
+{
+Speaker(3)   // Mondo
+    IfFlagCheck()
+      {
+      GoToLabel(0, 1)
+  }
+
+  Text(\"Yo {bro}\")
+}
+// Done
+";
+
+        let formatted = format_source(source).unwrap();
+
+        assert_that!(formatted.as_str(), equal_to("\
+// Opening
+CheckCharacter(3)
+{
+    Speaker(3) // Mondo
+    IfFlagCheck()
+    {
+        GoToLabel(0, 1)
+    }
+    Text(\"Yo {bro}\")
+}
+// Done
+"));
+        assert_that!(format_source(&formatted).unwrap(), equal_to(formatted.clone()));
+    }
+}
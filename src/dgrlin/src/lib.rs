@@ -1,27 +1,367 @@
+// Every file marks its tests with "//// [ TESTS ] ////"
+#![allow(clippy::four_forward_slashes)]
+
 use pyo3::prelude::*;
 
 pub mod opcode;
 pub mod compiler;
 pub mod decompiler;
+pub mod script;
+pub mod speaker;
+pub mod characters;
+pub mod cfg;
+pub mod project;
+pub mod flags;
+pub mod assets;
+pub mod simulator;
+pub mod overflow;
+pub mod markup;
+pub mod formatter;
+pub mod lsp;
+pub mod batch;
+pub mod watch;
+pub mod diff;
+pub mod error;
+pub mod merge;
+pub mod pak;
+pub mod patch;
+pub mod listing;
+pub mod sourcemap;
+pub mod python;
+
+// Everything that touches files lets go of the GIL while it works, so other Python threads (a GUI, say) keep running.
+
+// source_map=True also writes <name>.map.json beside the output.
+#[pyfunction]
+#[pyo3(signature = (filename, output_folder, source_map=false))]
+fn compile(py: Python<'_>, filename: String, output_folder: String, source_map: bool) -> python::Result<()> {
+    let options = compiler::CompileOptions { source_map };
+    py.allow_threads(|| compiler::compile_lin_with_options(filename, output_folder, &options))?;
+    Ok(())
+}
+
+// Every .txt under folder, as a list of per-file result dicts.
+// progress(done, total, result) is called from the worker threads as each file finishes.
+#[pyfunction]
+#[pyo3(signature = (folder, output_folder, force=false, jobs=None, progress=None, cancel=None))]
+fn compile_folder<'py>(
+    py: Python<'py>,
+    folder: String,
+    output_folder: String,
+    force: bool,
+    jobs: Option<usize>,
+    progress: Option<PyObject>,
+    cancel: Option<python::PyCancelToken>,
+) -> python::Result<Bound<'py, PyAny>> {
+    let options = batch::BatchOptions { force, jobs, cancel: cancel.unwrap_or_default().token };
+    run_batch(py, &options, progress, |options, progress| batch::compile_tree_with_progress(&folder, &output_folder, options, progress))
+}
+
+// compile_folder, run on the event loop's default executor.
+#[pyfunction]
+#[pyo3(signature = (folder, output_folder, force=false, jobs=None, progress=None, cancel=None))]
+fn compile_folder_async<'py>(
+    py: Python<'py>,
+    folder: String,
+    output_folder: String,
+    force: bool,
+    jobs: Option<usize>,
+    progress: Option<PyObject>,
+    cancel: Option<python::PyCancelToken>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let call = py.import("functools")?.call_method1(
+        "partial",
+        (wrap_pyfunction!(compile_folder, py)?, folder, output_folder, force, jobs, progress, cancel),
+    )?;
+    py.import("asyncio")?.call_method0("get_running_loop")?.call_method1("run_in_executor", (py.None(), call))
+}
+
+#[pyfunction]
+#[pyo3(signature = (filename, output_folder, annotate_speakers=false, source_map=false, listing=false, lenient=false))]
+fn decompile(
+    py: Python<'_>,
+    filename: String,
+    output_folder: String,
+    annotate_speakers: bool,
+    source_map: bool,
+    listing: bool,
+    lenient: bool,
+) -> python::Result<()> {
+    let options = decompiler::DecompileOptions { annotate_speakers, source_map, listing, lenient };
+    py.allow_threads(|| decompiler::decompile_lin_with_options(filename, output_folder, &options))?;
+    Ok(())
+}
+
+// Every .lin under folder, the same way as compile_folder.
+#[pyfunction]
+#[pyo3(signature = (folder, output_folder, annotate_speakers=false, jobs=None, progress=None, cancel=None))]
+fn decompile_folder<'py>(
+    py: Python<'py>,
+    folder: String,
+    output_folder: String,
+    annotate_speakers: bool,
+    jobs: Option<usize>,
+    progress: Option<PyObject>,
+    cancel: Option<python::PyCancelToken>,
+) -> python::Result<Bound<'py, PyAny>> {
+    let options = batch::BatchOptions { force: false, jobs, cancel: cancel.unwrap_or_default().token };
+    let decompile = decompiler::DecompileOptions { annotate_speakers, ..Default::default() };
+    run_batch(py, &options, progress, |options, progress| batch::decompile_tree(&folder, &output_folder, options, &decompile, progress))
+}
+
+#[pyfunction]
+#[pyo3(signature = (folder, output_folder, annotate_speakers=false, jobs=None, progress=None, cancel=None))]
+fn decompile_folder_async<'py>(
+    py: Python<'py>,
+    folder: String,
+    output_folder: String,
+    annotate_speakers: bool,
+    jobs: Option<usize>,
+    progress: Option<PyObject>,
+    cancel: Option<python::PyCancelToken>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let call = py.import("functools")?.call_method1(
+        "partial",
+        (wrap_pyfunction!(decompile_folder, py)?, folder, output_folder, annotate_speakers, jobs, progress, cancel),
+    )?;
+    py.import("asyncio")?.call_method0("get_running_loop")?.call_method1("run_in_executor", (py.None(), call))
+}
+
+// Runs a batch without the GIL, handing each result to progress as a dict.
+// If progress raises, the rest of the batch is cancelled and the exception raised once it stops.
+// That goes through a token of the batch's own, so the caller's can still be used for the next one.
+fn run_batch<'py, B>(py: Python<'py>, options: &batch::BatchOptions, progress: Option<PyObject>, batch: B) -> python::Result<Bound<'py, PyAny>>
+where
+    B: FnOnce(&batch::BatchOptions, batch::Progress) -> eyre::Result<Vec<batch::FileResult>> + Send,
+{
+    let options = batch::BatchOptions { cancel: options.cancel.child(), ..options.clone() };
+    let raised: std::sync::Mutex<Option<PyErr>> = std::sync::Mutex::new(None);
+
+    let report = |done: usize, total: usize, result: &batch::FileResult| {
+        let Some(progress) = &progress else { return };
+
+        Python::with_gil(|py| {
+            let called = serde_json::to_string(result)
+                .map_err(|err| PyErr::from(python::Error::from(err)))
+                .and_then(|json| py.import("json")?.call_method1("loads", (json,)))
+                .and_then(|result| progress.call1(py, (done, total, result)));
+
+            if let Err(err) = called {
+                options.cancel.cancel();
+                raised.lock().unwrap().get_or_insert(err);
+            }
+        });
+    };
+
+    let results = py.allow_threads(|| batch(&options, &report))?;
+    if let Some(err) = raised.into_inner().unwrap() {
+        return Err(err.into());
+    }
+
+    let json = serde_json::to_string(&results)?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// A .pak's entries as a list of {"path", "offset", "size", "kind"} dicts.
+#[pyfunction]
+fn list_pak<'py>(py: Python<'py>, filename: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| serde_json::to_string(&pak::read_pak(&filename)?.list()).map_err(eyre::Report::from))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// Every entry as it is, with nested .pak's as folders. Returns the files written.
+#[pyfunction]
+fn extract_pak(py: Python<'_>, filename: String, output_folder: String) -> python::Result<Vec<String>> {
+    let written = py.allow_threads(|| pak::extract(&pak::read_pak(&filename)?, std::path::Path::new(&output_folder)))?;
+    Ok(written.iter().map(|path| path.to_string_lossy().to_string()).collect())
+}
+
+// Packs a folder back into a .pak, compiling any .txt's on the way.
+#[pyfunction]
+fn compile_pak(py: Python<'_>, folder: String, output_file: String) -> python::Result<()> {
+    Ok(py.allow_threads(|| pak::compile_pak(&folder, &output_file))?)
+}
 
 #[pyfunction]
-fn compile(filename: String, output_folder: String) -> eyre::Result<()> {
-    compiler::compile_lin(filename, output_folder)?;
+#[pyo3(signature = (filename, output_folder, styled_spans=false))]
+fn export_json(py: Python<'_>, filename: String, output_folder: String, styled_spans: bool) -> python::Result<()> {
+    py.allow_threads(|| decompiler::export_json(filename, output_folder, styled_spans))?;
     Ok(())
 }
 
 #[pyfunction]
-fn decompile(filename: String, output_folder: String) -> eyre::Result<()> {
-    decompiler::decompile_lin(filename, output_folder)?;
+#[pyo3(signature = (filename, output_folder, format="dot"))]
+fn export_cfg(py: Python<'_>, filename: String, output_folder: String, format: &str) -> python::Result<()> {
+    py.allow_threads(|| cfg::export_cfg(filename, output_folder, format))?;
     Ok(())
 }
 
+#[pyfunction]
+fn export_call_graph(py: Python<'_>, folder: String, output_folder: String) -> python::Result<Vec<String>> {
+    Ok(py.allow_threads(|| project::export_call_graph(folder, output_folder))?)
+}
+
+#[pyfunction]
+fn export_flag_report(py: Python<'_>, folder: String, output_folder: String) -> python::Result<()> {
+    Ok(py.allow_threads(|| flags::export_flag_report(folder, output_folder))?)
+}
+
+// Handed over as a plain dict, by way of the same JSON the export writes.
+#[pyfunction]
+fn flag_report<'py>(py: Python<'py>, folder: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| flags::flag_report(&folder)?.to_json())?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+#[pyfunction]
+#[pyo3(signature = (path, manifest=None))]
+fn asset_report<'py>(py: Python<'py>, path: String, manifest: Option<String>) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| assets::asset_report(&path, manifest.as_deref())?.to_json())?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// flags are {"group:id": value}, the result is the Run as a dict.
+#[pyfunction]
+#[pyo3(signature = (filename, flags=None, choices=None, character=None, object=None))]
+fn simulate<'py>(
+    py: Python<'py>,
+    filename: String,
+    flags: Option<std::collections::HashMap<String, u16>>,
+    choices: Option<Vec<u8>>,
+    character: Option<u8>,
+    object: Option<u8>,
+) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| -> eyre::Result<String> {
+        let script = decompiler::read_lin(&filename)?;
+        let mut simulator = simulator::Simulator::new(&script);
+
+        for (flag, value) in flags.unwrap_or_default() {
+            simulator.flags.insert(flag.parse()?, value);
+        }
+        simulator.choices.extend(choices.unwrap_or_default());
+        simulator.character = character;
+        simulator.object = object;
+
+        Ok(serde_json::to_string(&simulator.run())?)
+    })?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// config is the path to an overflow config JSON, or None for the default box.
+#[pyfunction]
+#[pyo3(signature = (path, config=None))]
+fn check_overflow<'py>(py: Python<'py>, path: String, config: Option<String>) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| -> eyre::Result<String> {
+        let config = match config {
+            Some(filename) => { overflow::OverflowConfig::load(&filename)? }
+            None => { overflow::OverflowConfig::default() }
+        };
+        let project = project::Project::load_path(&path)?;
+        Ok(serde_json::to_string(&overflow::check_project(&project, &config))?)
+    })?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// Problems with the <CLT> tags in one line of text, as dicts.
+#[pyfunction]
+fn validate_markup<'py>(py: Python<'py>, text: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = serde_json::to_string(&markup::validate(&text))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+#[pyfunction]
+fn tokenize_markup<'py>(py: Python<'py>, text: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = serde_json::to_string(&markup::tokenize(&text))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// True when the file was (or with check=True, would be) reformatted.
+#[pyfunction]
+#[pyo3(signature = (filename, check=false))]
+fn format_script(py: Python<'_>, filename: String, check: bool) -> python::Result<bool> {
+    Ok(py.allow_threads(|| formatter::format_file(&filename, check))?)
+}
+
+// Either side can be a .lin or a .txt. The result is {"changes": [...]}.
+#[pyfunction]
+fn diff_scripts<'py>(py: Python<'py>, a: String, b: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| diff::diff(&script::LinScript::open(&a)?, &script::LinScript::open(&b)?).to_json())?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// Writes the merge over ours unless output is given, and returns how many conflicts it had.
+#[pyfunction]
+#[pyo3(signature = (base, ours, theirs, output=None))]
+fn merge_scripts(py: Python<'_>, base: String, ours: String, theirs: String, output: Option<String>) -> python::Result<usize> {
+    let output = output.unwrap_or_else(|| ours.clone());
+    Ok(py.allow_threads(|| merge::merge_files(&base, &ours, &theirs, &output))?)
+}
+
+
+// Writes a patch of the changes from base to modded (a .lin or .txt).
+#[pyfunction]
+fn create_patch(py: Python<'_>, base: String, modded: String, output: String) -> python::Result<()> {
+    py.allow_threads(|| patch::create_file(&base, &modded, &output))?;
+    Ok(())
+}
+
+// Applies the patch files in order and returns where later ones overrode earlier ones, as dicts.
+#[pyfunction]
+fn apply_patches<'py>(py: Python<'py>, base: String, patches: Vec<String>, output: String) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| -> eyre::Result<String> {
+        Ok(serde_json::to_string(&patch::apply_files(&base, &patches, &output)?)?)
+    })?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+
+// The opcode at a byte offset in a .lin and where it is in the source, as a dict, or None outside the opcodes.
+// Uses the .map.json beside the .lin if there is one, otherwise the .txt the decompiler would write.
+#[pyfunction]
+fn locate<'py>(py: Python<'py>, filename: String, offset: usize) -> python::Result<Bound<'py, PyAny>> {
+    let json = py.allow_threads(|| -> eyre::Result<String> {
+        let map = sourcemap::for_lin(&filename)?;
+        Ok(serde_json::to_string(&map.locate(offset))?)
+    })?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?)
+}
+
+// The annotated hex dump `decompile(listing=True)` writes, as a string.
+#[pyfunction]
+fn lin_listing(py: Python<'_>, filename: String) -> python::Result<String> {
+    Ok(py.allow_threads(|| listing::listing_file(&filename))?)
+}
 
 #[pymodule]
 fn dgrlin(m: &Bound<'_, PyModule>) -> PyResult<()> {
     pyo3_log::init();
 
     m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_folder, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_folder_async, m)?)?;
     m.add_function(wrap_pyfunction!(decompile, m)?)?;
+    m.add_function(wrap_pyfunction!(decompile_folder, m)?)?;
+    m.add_function(wrap_pyfunction!(decompile_folder_async, m)?)?;
+    m.add_function(wrap_pyfunction!(list_pak, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_pak, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_pak, m)?)?;
+    m.add_function(wrap_pyfunction!(export_json, m)?)?;
+    m.add_function(wrap_pyfunction!(export_cfg, m)?)?;
+    m.add_function(wrap_pyfunction!(export_call_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(export_flag_report, m)?)?;
+    m.add_function(wrap_pyfunction!(flag_report, m)?)?;
+    m.add_function(wrap_pyfunction!(asset_report, m)?)?;
+    m.add_function(wrap_pyfunction!(simulate, m)?)?;
+    m.add_function(wrap_pyfunction!(check_overflow, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_markup, m)?)?;
+    m.add_function(wrap_pyfunction!(tokenize_markup, m)?)?;
+    m.add_function(wrap_pyfunction!(format_script, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_scripts, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_scripts, m)?)?;
+    m.add_function(wrap_pyfunction!(create_patch, m)?)?;
+    m.add_function(wrap_pyfunction!(apply_patches, m)?)?;
+    m.add_function(wrap_pyfunction!(locate, m)?)?;
+    m.add_function(wrap_pyfunction!(lin_listing, m)?)?;
+    python::register(m)?;
     Ok(())
 }
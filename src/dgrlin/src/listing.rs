@@ -0,0 +1,254 @@
+use eyre::Context;
+
+use std::fmt::Write;
+use std::fs::read;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::decompiler::{decode_lenient, problem_line};
+use crate::script::LinScript;
+
+
+// Raw bytes shown on each row of a listing
+const ROW_BYTES: usize = 8;
+
+
+/// An annotated hex dump of a .lin: every header field, opcode, offset table entry and line of text
+/// with its offset and raw bytes, and the padding between them. For working out what unknown opcodes do.
+///
+/// The opcodes are read the way `decode_lenient` reads them, so bytes it can't make out are listed
+/// as unknown rather than refused, and what was wrong with the file is listed at the end.
+/// The string table is read from the offsets in the file rather than trusted, and anything the
+/// offsets don't cover is listed as padding (all zero) or unknown bytes.
+pub fn listing(bytes: &[u8]) -> String {
+    let recovered = decode_lenient(bytes);
+    let mut out = Listing { bytes, text: String::new() };
+    out.sections(&recovered.script);
+
+    if !recovered.problems.is_empty() {
+        out.section("problems");
+        for problem in &recovered.problems {
+            let _ = writeln!(out.text, ";   {}", problem_line(problem));
+        }
+    }
+
+    out.text
+}
+
+
+pub fn listing_file(filename: &str) -> eyre::Result<String> {
+    let bytes = read(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+    Ok(listing(&bytes))
+}
+
+
+struct Listing<'a> {
+    bytes: &'a [u8],
+    text: String,
+}
+
+impl Listing<'_> {
+    fn sections(&mut self, script: &LinScript) {
+        let bytes = self.bytes;
+
+        // SECTION 0 [ HEADER ]
+        self.section("header");
+        self.field(0, "type");
+        self.field(4, "header length");
+        let text_offset = self.field(8, "text offset");
+        self.field(12, "file size");
+
+        // SECTION 1 [ OPCODES ]
+        self.section("opcodes");
+        let mut offset = 16usize;
+        for (op_idx, op) in script.ops.iter().enumerate() {
+            // Bytes decode_lenient couldn't read as an opcode
+            let mut mnemonic = if op.name == "Raw" {
+                format!("{:<5} unknown ({} bytes)", op_idx, op.hexcode.len())
+            } else {
+                format!("{:<5} {}", op_idx, op)
+            };
+            if let Some(text) = script.text_for(op) {
+                mnemonic += &format!("  \"{}\"", text);
+            }
+            self.row(offset, op.hexcode.len(), &mnemonic);
+            offset += op.hexcode.len();
+        }
+
+        let Some(text_offset) = text_offset.filter(|&text_offset| text_offset >= offset && text_offset < bytes.len()) else {
+            self.gap(offset, bytes.len());
+            return;
+        };
+        self.gap(offset, text_offset);
+
+        // SECTION 2 [ STRING TABLE ]
+        self.section("string table");
+        let count = self.field(text_offset, "string count").unwrap_or(0);
+
+        // Offsets are from the start of the table, with one more after the last line marking where it ends
+        let mut starts: Vec<usize> = Vec::new();
+        for idx in 0..=count {
+            let label = if idx == count { "end".to_string() } else { format!("string {}", idx) };
+            let entry = text_offset + 4 + idx * 4;
+
+            match self.u32_at(entry) {
+                Some(value) => {
+                    self.row(entry, 4, &format!("{:<15} {:#x} -> {:#x}", label, value, text_offset + value));
+                    starts.push(text_offset + value);
+                }
+                None => {
+                    self.gap(entry, bytes.len());
+                    return;
+                }
+            }
+        }
+        offset = text_offset + 8 + count * 4;
+
+        // SECTION 3 [ TEXT ]
+        self.section("text");
+        for (idx, window) in starts.windows(2).enumerate() {
+            let (start, end) = (window[0], window[1].min(bytes.len()));
+            if start < offset || start >= end {
+                let _ = writeln!(self.text, "; string {} at {:#x} is out of place", idx, start);
+                continue;
+            }
+
+            self.gap(offset, start);
+            self.row(start, end - start, &format!("[{}] \"{}\"", idx, decode_text(&bytes[start..end])));
+            offset = end;
+        }
+        self.gap(offset, bytes.len());
+    }
+
+
+    fn section(&mut self, name: &str) {
+        let _ = writeln!(self.text, "; {}", name);
+    }
+
+
+    fn u32_at(&self, offset: usize) -> Option<usize> {
+        self.bytes.get(offset..offset + 4).map(|field| LittleEndian::read_u32(field) as usize)
+    }
+
+
+    // A little endian u32, labelled and returned. A field cut short by the end of the file is listed as unknown.
+    fn field(&mut self, offset: usize, name: &str) -> Option<usize> {
+        match self.u32_at(offset) {
+            Some(value) => {
+                self.row(offset, 4, &format!("{:<15} {} ({:#x})", name, value, value));
+                Some(value)
+            }
+            None => {
+                self.gap(offset, self.bytes.len());
+                None
+            }
+        }
+    }
+
+
+    // `size` bytes from `offset`, ROW_BYTES to a row, with the annotation on the first.
+    fn row(&mut self, offset: usize, size: usize, annotation: &str) {
+        let end = (offset + size).min(self.bytes.len());
+        let width = ROW_BYTES * 3 - 1;
+
+        for (idx, chunk) in self.bytes[offset.min(end)..end].chunks(ROW_BYTES).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let annotation = if idx == 0 { annotation } else { "" };
+            let row = format!("{:08x}  {:<width$}  {}", offset + idx * ROW_BYTES, hex.join(" "), annotation);
+            let _ = writeln!(self.text, "{}", row.trim_end());
+        }
+    }
+
+
+    // Bytes nothing accounts for, between `start` and `end`.
+    fn gap(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+
+        let kind = if self.bytes[start..end].iter().all(|&byte| byte == 0) { "padding" } else { "unknown" };
+        self.row(start, end - start, &format!("{} ({} bytes)", kind, end - start));
+    }
+}
+
+
+// FF FE, then UTF-16 up to a null, written the way the decompiler writes it
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(&[0xFF, 0xFE]).unwrap_or(bytes);
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(LittleEndian::read_u16)
+        .take_while(|&unit| unit != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units).replace('\n', "\\n")
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::compiler::{encode, parse};
+
+    use super::listing;
+
+    #[test]
+    fn test_listing() {
+        let bytes = encode(&parse("Speaker(0)\nText(\"Hi\")\nWaitInput()\n").unwrap().script);
+        let listed = listing(&bytes);
+
+        assert_that!(listed.as_str(), equal_to("\
+; header
+00000000  02 00 00 00              type            2 (0x2)
+00000004  10 00 00 00              header length   16 (0x10)
+00000008  1c 00 00 00              text offset     28 (0x1c)
+0000000c  30 00 00 00              file size       48 (0x30)
+; opcodes
+00000010  70 21 00                 0     Speaker(0)
+00000013  70 02 00 00              1     Text(0)  \"Hi\"
+00000017  70 3a                    2     WaitInput()
+00000019  00 00 00                 padding (3 bytes)
+; string table
+0000001c  01 00 00 00              string count    1 (0x1)
+00000020  0c 00 00 00              string 0        0xc -> 0x28
+00000024  14 00 00 00              end             0x14 -> 0x30
+; text
+00000028  ff fe 48 00 69 00 00 00  [0] \"Hi\"
+"));
+
+        // An opcode it doesn't know is listed as bytes, and the file cut off is listed as far as it goes
+        let mut unknown = bytes.clone();
+        unknown[0x18] = 0xFF;
+        assert_that!(listing(&unknown[..0x1A]).as_str(), equal_to("\
+; header
+00000000  02 00 00 00              type            2 (0x2)
+00000004  10 00 00 00              header length   16 (0x10)
+00000008  1c 00 00 00              text offset     28 (0x1c)
+0000000c  30 00 00 00              file size       48 (0x30)
+; opcodes
+00000010  70 21 00                 0     Speaker(0)
+00000013  70 02 00 00              1     Text(0)
+00000017  70 ff                    2     unknown (2 bytes)
+00000019  00                       padding (1 bytes)
+; problems
+;   0x1a: File ends at 0x1a, the header says 0x30
+;   0x8: Text offset 0x1c is outside the file
+;   0x17: Invalid opcode 'ff' at index 23
+;   0x13: Text(0) has no line of text
+"));
+
+        assert_that!(listing(&bytes[..4]).as_str(), equal_to("\
+; header
+00000000  02 00 00 00              type            2 (0x2)
+; opcodes
+; problems
+;   0x4: Not a valid .lin file (Header Inconsistency)
+;   0x4: File ends inside the header
+"));
+    }
+}
@@ -0,0 +1,535 @@
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+
+use crate::cfg::label_of;
+use crate::characters;
+use crate::compiler::{parse_with_diagnostics, Source};
+use crate::markup::{self, Severity};
+use crate::opcode::{self, strip_comment, OPCODES};
+
+
+// LSP SymbolKind and CompletionItemKind numbers
+const SYMBOL_NAMESPACE: u32 = 3;
+const SYMBOL_CONSTANT: u32 = 14;
+const SYMBOL_ENUM_MEMBER: u32 = 22;
+const COMPLETION_FUNCTION: u32 = 3;
+const COMPLETION_VALUE: u32 = 12;
+
+
+// No script comes anywhere near this, so anything bigger is skipped rather than read into memory
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+
+/// One message from the editor, or what was wrong with one that couldn't be read.
+#[derive(PartialEq, Debug)]
+pub enum Incoming {
+    Message(Value),
+    Malformed(String),
+}
+
+
+/// Reads one `Content-Length` framed JSON-RPC message. None once the editor hangs up.
+/// Only a failure to read stdin is an error; a bad header or body comes back as Malformed.
+pub fn read_message<R: BufRead>(reader: &mut R) -> eyre::Result<Option<Incoming>> {
+    let mut length: Option<Result<usize, String>> = None;
+
+    loop {
+        let mut header: Vec<u8> = Vec::new();
+        if reader.read_until(b'\n', &mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = String::from_utf8_lossy(&header);
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse().map_err(|_| format!("bad Content-Length '{}'", value.trim())));
+        }
+    }
+
+    let length = match length {
+        Some(Ok(length)) => { length }
+        Some(Err(error)) => { return Ok(Some(Incoming::Malformed(error))); }
+        None => { return Ok(Some(Incoming::Malformed("message has no Content-Length".to_string()))); }
+    };
+
+    if length > MAX_MESSAGE {
+        std::io::copy(&mut Read::take(&mut *reader, length as u64), &mut std::io::sink())?;
+        return Ok(Some(Incoming::Malformed(format!("message of {} bytes is too big", length))));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    match serde_json::from_slice(&body) {
+        Ok(message) => { Ok(Some(Incoming::Message(message))) }
+        Err(error) => { Ok(Some(Incoming::Malformed(error.to_string()))) }
+    }
+}
+
+
+/// The reply to a message that couldn't be read. Its id is unknown, so it's null.
+pub fn parse_error(problem: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": null,
+        "error": { "code": -32700, "message": problem },
+    })
+}
+
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> eyre::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+
+/// A language server for .txt scripts, kept separate from stdin/stdout so it can be tested.
+///
+/// Every request re-parses the whole document, which is quick at script sizes.
+/// Scripts have no macros, so go-to-definition only knows about labels.
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    /// Set once the editor sends `exit`.
+    pub exited: bool,
+}
+
+impl Server {
+    /// Answers one message from the editor, returning whatever should be sent back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        // Replies to requests we never make
+        let method = match message["method"].as_str() {
+            Some(method) => { method }
+            None => { return Vec::new(); }
+        };
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+
+        let result = match method {
+            "initialize" => { Some(capabilities()) }
+            "shutdown"   => { Some(Value::Null) }
+            "exit" => {
+                self.exited = true;
+                None
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                return vec![self.publish(&uri)];
+            }
+            // Only full syncs are asked for, so the last change is the whole document
+            "textDocument/didChange" => {
+                if let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    self.documents.insert(uri.clone(), text["text"].as_str().unwrap_or("").to_string());
+                }
+                return vec![self.publish(&uri)];
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))];
+            }
+            "textDocument/completion"     => { Some(self.completion(params)) }
+            "textDocument/hover"          => { Some(self.hover(params)) }
+            "textDocument/definition"     => { Some(self.definition(params)) }
+            "textDocument/documentSymbol" => { Some(self.symbols(&uri)) }
+            _ => { None }
+        };
+
+        match (message.get("id"), result) {
+            (Some(id), Some(result)) => { vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })] }
+            (Some(id), None) => {
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32601, "message": format!("{} is not supported", method) },
+                })]
+            }
+            (None, _) => { Vec::new() }
+        }
+    }
+
+
+    fn publish(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).map(String::as_str).unwrap_or("");
+        notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics(text) }))
+    }
+
+
+    // The document, the line the cursor is on, and how many bytes into that line it is.
+    fn cursor<'a>(&'a self, params: &Value) -> Option<(&'a str, usize, &'a str, usize)> {
+        let text = self.documents.get(params["textDocument"]["uri"].as_str()?)?;
+        let line_idx = params["position"]["line"].as_u64()? as usize;
+        let line = text.lines().nth(line_idx).unwrap_or("");
+        let column = byte_offset(line, params["position"]["character"].as_u64()? as usize);
+
+        Some((text, line_idx, line, column))
+    }
+
+
+    /// Opcode names at the start of a line, and character names for character arguments.
+    fn completion(&self, params: &Value) -> Value {
+        let (_, _, line, column) = match self.cursor(params) {
+            Some(cursor) => { cursor }
+            None => { return json!([]); }
+        };
+        let before = &line[..column];
+
+        match before.split_once('(') {
+            None => {
+                OPCODES
+                    .iter()
+                    .map(|info| json!({
+                        "label": info.name,
+                        "kind": COMPLETION_FUNCTION,
+                        "detail": info.signature(),
+                        "documentation": info.doc,
+                    }))
+                    .collect()
+            }
+            Some((name, args)) => {
+                let arg_idx = args.matches(',').count();
+                let wants_character = opcode::info(name.trim())
+                    .and_then(|info| info.args.get(arg_idx))
+                    .is_some_and(|arg| *arg == "character");
+
+                if !wants_character {
+                    return json!([]);
+                }
+
+                characters::all()
+                    .map(|(id, name)| json!({
+                        "label": name,
+                        "kind": COMPLETION_VALUE,
+                        "detail": format!("character {}", id),
+                        "insertText": id.to_string(),
+                        "filterText": name,
+                        "sortText": format!("{:03}", id),
+                    }))
+                    .collect()
+            }
+        }
+    }
+
+
+    /// The opcode's arguments and what it does, plus who any character arguments are.
+    fn hover(&self, params: &Value) -> Value {
+        let (_, _, line, _) = match self.cursor(params) {
+            Some(cursor) => { cursor }
+            None => { return Value::Null; }
+        };
+
+        let code = strip_comment(line).trim();
+        let (name, args) = code.split_once('(').unwrap_or((code, ""));
+        let info = match opcode::info(name) {
+            Some(info) => { info }
+            None => { return Value::Null; }
+        };
+
+        let mut docs = format!("```\n{}\n```\n{}\n\nOpcode 0x{:02X}", info.signature(), info.doc, info.code);
+
+        // Text's argument is the line itself, not numbers
+        if info.name != "Text" {
+            let values = args.trim_end_matches(')').split(',').map(str::trim);
+            for (arg, value) in info.args.iter().zip(values) {
+                if *arg == "character"
+                    && let Some(character) = value.parse().ok().and_then(characters::name) {
+                    docs += &format!("\n\ncharacter {} is {}", value, character);
+                }
+            }
+        }
+
+        json!({ "contents": { "kind": "markdown", "value": docs } })
+    }
+
+
+    /// From a GoToLabel to its SetLabel.
+    fn definition(&self, params: &Value) -> Value {
+        let (text, line_idx, _, _) = match self.cursor(params) {
+            Some(cursor) => { cursor }
+            None => { return Value::Null; }
+        };
+        let (source, _) = parse_with_diagnostics(text);
+
+        let label = match op_on_line(&source, line_idx + 1) {
+            Some(op_idx) if source.script.ops[op_idx].name == "GoToLabel" => { label_of(&source.script.ops[op_idx]) }
+            _ => { None }
+        };
+
+        let target = source.script.ops
+            .iter()
+            .zip(&source.lines)
+            .find(|(op, _)| op.name == "SetLabel" && label.is_some() && label_of(op) == label);
+
+        match target {
+            Some((_, line_number)) => {
+                json!({ "uri": params["textDocument"]["uri"], "range": line_range(text, line_number - 1) })
+            }
+            None => { Value::Null }
+        }
+    }
+
+
+    /// Character and object sections, with their choices and labels inside.
+    fn symbols(&self, uri: &str) -> Value {
+        let text = match self.documents.get(uri) {
+            Some(text) => { text }
+            None => { return json!([]); }
+        };
+        let (source, _) = parse_with_diagnostics(text);
+
+        let mut symbols: Vec<Value> = Vec::new();
+        let mut section: Option<String> = None;
+
+        for (op, line_number) in source.script.ops.iter().zip(&source.lines) {
+            let (name, kind) = match (op.name.as_str(), op.args()) {
+                ("CheckCharacter", [character]) => { (characters::name_or_id(*character), SYMBOL_NAMESPACE) }
+                ("CheckObject", [object]) => { (format!("Object {}", object), SYMBOL_NAMESPACE) }
+                ("SetChoiceText", [255]) => { ("End of choices".to_string(), SYMBOL_ENUM_MEMBER) }
+                ("SetChoiceText", [option]) => { (format!("Choice {}", option), SYMBOL_ENUM_MEMBER) }
+                ("SetLabel", _) => { (format!("Label {}", label_of(op).unwrap_or(0)), SYMBOL_CONSTANT) }
+                _ => { continue; }
+            };
+
+            let mut symbol = json!({
+                "name": name,
+                "kind": kind,
+                "location": { "uri": uri, "range": line_range(text, line_number - 1) },
+            });
+
+            if kind == SYMBOL_NAMESPACE {
+                section = Some(name);
+            }
+            else if let Some(section) = &section {
+                symbol["containerName"] = json!(section);
+            }
+
+            symbols.push(symbol);
+        }
+
+        json!(symbols)
+    }
+}
+
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": 1,
+            "completionProvider": { "triggerCharacters": ["(", ","] },
+            "hoverProvider": true,
+            "definitionProvider": true,
+            "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "dgrlin-lsp", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+
+/// Parse errors and markup problems, as LSP diagnostics.
+pub fn diagnostics(text: &str) -> Vec<Value> {
+    let (source, errors) = parse_with_diagnostics(text);
+
+    let mut found: Vec<Value> = errors
+        .iter()
+        .map(|error| json!({
+            "range": line_range(text, error.line - 1),
+            "severity": 1,
+            "source": "dgrlin",
+            "message": error.message,
+        }))
+        .collect();
+
+    for (op, line_number) in source.script.ops.iter().zip(&source.lines) {
+        let entry = match source.script.text_for(op) {
+            Some(entry) => { entry }
+            None => { continue; }
+        };
+        let line = text.lines().nth(line_number - 1).unwrap_or("");
+        let text_start = line.find("Text(\"").map(|start| start + 6).unwrap_or(0);
+
+        for issue in markup::validate(entry) {
+            // Offsets are in characters, and run to the end of the tag
+            let start = entry.char_indices().nth(issue.offset).map(|(byte, _)| byte).unwrap_or(entry.len());
+            let end = entry[start..].find('>').map(|end| start + end + 1).unwrap_or(entry.len());
+
+            found.push(json!({
+                "range": {
+                    "start": position(line, line_number - 1, text_start + start),
+                    "end": position(line, line_number - 1, text_start + end),
+                },
+                "severity": if issue.severity == Severity::Error { 1 } else { 2 },
+                "source": "dgrlin",
+                "message": issue.message,
+            }));
+        }
+    }
+
+    found
+}
+
+
+// The opcode written on a 1-based line, if any.
+fn op_on_line(source: &Source, line_number: usize) -> Option<usize> {
+    source.lines.iter().position(|line| *line == line_number)
+}
+
+
+// LSP counts columns in UTF-16 code units.
+fn position(line: &str, line_idx: usize, byte: usize) -> Value {
+    json!({ "line": line_idx, "character": line[..byte.min(line.len())].encode_utf16().count() })
+}
+
+
+fn byte_offset(line: &str, utf16_column: usize) -> usize {
+    let mut units = 0usize;
+    for (byte, c) in line.char_indices() {
+        if units >= utf16_column {
+            return byte;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+
+fn line_range(text: &str, line_idx: usize) -> Value {
+    let line = text.lines().nth(line_idx).unwrap_or("");
+    json!({ "start": position(line, line_idx, 0), "end": position(line, line_idx, line.len()) })
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::{diagnostics, read_message, write_message, Incoming, Server};
+
+    const SCRIPT: &str = "\
+CheckCharacter(8)
+{
+    Speaker(8)
+    GoToLabel(0, 2)
+    Text(\"<CLT 3>Hmm.\")
+    SetLabel(0, 2)
+}
+";
+
+    fn open(server: &mut Server) {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///e01.txt", "text": SCRIPT } },
+        }));
+    }
+
+    fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Value {
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": "file:///e01.txt" },
+                "position": { "line": line, "character": character },
+            },
+        }));
+        replies[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut written: Vec<u8> = Vec::new();
+        write_message(&mut written, &json!({ "id": 1 })).unwrap();
+
+        assert_that!(String::from_utf8(written.clone()).unwrap().as_str(), equal_to("Content-Length: 8\r\n\r\n{\"id\":1}"));
+        assert_that!(read_message(&mut written.as_slice()).unwrap(), equal_to(Some(Incoming::Message(json!({ "id": 1 })))));
+        assert_that!(read_message(&mut "".as_bytes()).unwrap(), equal_to(None));
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        // Each is skipped, and the message after them still reads. One too big is skipped over unread.
+        let input = "Content-Length: x\r\n\r\nContent-Type: json\r\n\r\nContent-Length: 3\r\n\r\n{\"}\
+                     Content-Length: 8\r\n\r\n{\"id\":1}Content-Length: 99999999999\r\n\r\n{\"id\":2}";
+        let mut reader = input.as_bytes();
+
+        let mut read = || match read_message(&mut reader).unwrap() {
+            Some(Incoming::Malformed(problem)) => { problem }
+            other => { format!("{:?}", other) }
+        };
+        assert_that!(read().as_str(), equal_to("bad Content-Length 'x'"));
+        assert_that!(read().as_str(), equal_to("message has no Content-Length"));
+        assert_that!(read().starts_with("EOF while parsing"), is(true));
+        assert_that!(read().as_str(), equal_to("Some(Message(Object {\"id\": Number(1)}))"));
+        assert_that!(read().as_str(), equal_to("message of 99999999999 bytes is too big"));
+        assert_that!(read_message(&mut reader).unwrap(), equal_to(None));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let found = diagnostics("IfFlagCheck()\nText(\"Red <CLT 3>text\")\n");
+
+        assert_that!(found.len(), equal_to(2));
+        assert_that!(found[0]["message"].clone(), equal_to(json!("IfFlagCheck needs a '{' block after it")));
+        assert_that!(found[1]["severity"].clone(), equal_to(json!(2)));
+        assert_that!(found[1]["range"]["start"].clone(), equal_to(json!({ "line": 1, "character": 10 })));
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut server = Server::default();
+        open(&mut server);
+
+        let hover = request(&mut server, "textDocument/hover", 2, 6);
+        assert_that!(hover["contents"]["value"].as_str().unwrap().contains("character 8 is Kyoko Kirigiri"), is(true));
+
+        let definition = request(&mut server, "textDocument/definition", 3, 6);
+        assert_that!(definition["range"]["start"].clone(), equal_to(json!({ "line": 5, "character": 0 })));
+
+        let completion = request(&mut server, "textDocument/completion", 2, 12);
+        assert_that!(completion[8]["label"].clone(), equal_to(json!("Kyoko Kirigiri")));
+
+        let symbols = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/documentSymbol",
+            "params": { "textDocument": { "uri": "file:///e01.txt" } },
+        }));
+        assert_that!(symbols[0]["result"][1]["name"].clone(), equal_to(json!("Label 2")));
+        assert_that!(symbols[0]["result"][1]["containerName"].clone(), equal_to(json!("Kyoko Kirigiri")));
+    }
+
+    #[test]
+    fn test_raw_symbols() {
+        // Raw bytes can be too short to have arguments at all
+        let mut server = Server::default();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///e01.txt", "text": "Raw(1)\nRaw()\nSetLabel(0, 1)\n" } },
+        }));
+
+        let symbols = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/documentSymbol",
+            "params": { "textDocument": { "uri": "file:///e01.txt" } },
+        }));
+        assert_that!(symbols[0]["result"][0]["name"].clone(), equal_to(json!("Label 1")));
+    }
+}
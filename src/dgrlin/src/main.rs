@@ -0,0 +1,559 @@
+use std::process::ExitCode;
+
+use dgrlin::assets;
+use dgrlin::batch::{self, BatchOptions, Status};
+use dgrlin::compiler;
+use dgrlin::formatter;
+use dgrlin::diff::{self, Change};
+use dgrlin::merge;
+use dgrlin::decompiler::{self, read_lin, DecompileOptions};
+use dgrlin::overflow::{self, OverflowConfig};
+use dgrlin::pak;
+use dgrlin::patch;
+use dgrlin::project::Project;
+use dgrlin::script::LinScript;
+use dgrlin::simulator::{Event, Simulator};
+use dgrlin::sourcemap::{self, SourceMap};
+use dgrlin::watch::{self, WatchOptions};
+
+
+const USAGE: &str = "\
+usage: dgrlin <command> [args]
+
+commands:
+    compile <file.txt> <output folder> [--source-map]
+    compile <folder> <output.pak>
+    build <source folder> <output folder> [--force] [--jobs <n>] [--json]
+    watch <source folder> <output folder> [--mirror <folder>] [--debounce <ms>]
+    decompile <file.lin | file.pak> <output folder> [--annotate-speakers] [--source-map]
+              [--listing] [--lenient]
+    locate <file.lin> <offset> [--map <file.map.json>] [--json]
+    assets <file.lin | folder> [--manifest <file>] [--json]
+    simulate <file.lin> [--flag <group:id=value>]... [--choice <option>]...
+             [--character <id>] [--object <id>] [--json]
+    overflow <file.lin | folder> [--config <file.json>] [--fix <output folder>] [--json]
+    fmt <file.txt>... [--check]
+    diff <a.lin | a.txt> <b.lin | b.txt> [--json]
+    merge <base> <ours> <theirs> [--output <file>]
+    pak list <file.pak> [--json]
+    pak extract <file.pak> <output folder>
+    pak repack <folder> <output.pak>
+    patch create <base.lin> <modded.lin | modded.txt> <output.json>
+    patch apply <base.lin> <patch.json>... --output <file.lin> [--json]";
+
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("compile")   => { compile(&args[1..]) }
+        Some("build")     => { build(&args[1..]) }
+        Some("watch")     => { watch(&args[1..]) }
+        Some("decompile") => { decompile(&args[1..]) }
+        Some("locate")    => { locate(&args[1..]) }
+        Some("assets")    => { assets(&args[1..]) }
+        Some("simulate")  => { simulate(&args[1..]) }
+        Some("overflow")  => { overflow(&args[1..]) }
+        Some("fmt")       => { fmt(&args[1..]) }
+        Some("diff")      => { diff(&args[1..]) }
+        Some("merge")     => { merge(&args[1..]) }
+        Some("pak")       => { pak(&args[1..]) }
+        Some("patch")     => { patch(&args[1..]) }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(code) => { code }
+        Err(err) => {
+            eprintln!("error: {:?}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// Positional arguments, `--flag`s and `--option value`s.
+/// Only the names in `value_options` take a value, and may be given more than once.
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String], value_options: &[&str]) -> eyre::Result<Self> {
+        let mut parsed = Args { positional: Vec::new(), flags: Vec::new(), options: Vec::new() };
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if value_options.contains(&name) => {
+                    match args.next() {
+                        Some(value) => { parsed.options.push((name.to_string(), value.clone())); }
+                        None => { eyre::bail!("--{} needs a value", name) }
+                    }
+                }
+                Some(name) => { parsed.flags.push(name.to_string()); }
+                None => { parsed.positional.push(arg.clone()); }
+            }
+        }
+
+        Ok(parsed)
+    }
+
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.all(name).pop()
+    }
+
+
+    fn all(&self, name: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+
+    fn expect_positional(&self, count: usize) -> eyre::Result<()> {
+        if self.positional.len() != count {
+            eyre::bail!("expected {} arguments, got {}\n\n{}", count, self.positional.len(), USAGE);
+        }
+        Ok(())
+    }
+}
+
+
+fn compile(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &[])?;
+    args.expect_positional(2)?;
+
+    // A folder, as decompiling a .pak leaves it, goes back into a .pak
+    if std::path::Path::new(&args.positional[0]).is_dir() {
+        pak::compile_pak(&args.positional[0], &args.positional[1])?;
+    } else {
+        let options = compiler::CompileOptions { source_map: args.flag("source-map") };
+        compiler::compile_lin_with_options(args.positional[0].clone(), args.positional[1].clone(), &options)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+
+fn build(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["jobs"])?;
+    args.expect_positional(2)?;
+
+    let options = BatchOptions {
+        force: args.flag("force"),
+        jobs: args.option("jobs").map(str::parse).transpose()?,
+        ..Default::default()
+    };
+    let results = batch::compile_tree(&args.positional[0], &args.positional[1], &options)?;
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    else {
+        for result in &results {
+            match result.status {
+                Status::Compiled => { println!("compiled {}", result.source); }
+                Status::Failed   => { println!("failed   {}", result.source); }
+                // Nothing cancels or decompiles here
+                Status::Skipped | Status::Decompiled | Status::Cancelled => {}
+            }
+            for error in &result.errors {
+                println!("    error: {}", error);
+            }
+            for warning in &result.warnings {
+                println!("    warning: {}", warning);
+            }
+        }
+
+        let count = |status: Status| results.iter().filter(|result| result.status == status).count();
+        println!("{} compiled, {} unchanged, {} failed", count(Status::Compiled), count(Status::Skipped), count(Status::Failed));
+    }
+
+    if results.iter().any(|result| result.status == Status::Failed) {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+
+fn watch(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["mirror", "debounce"])?;
+    args.expect_positional(2)?;
+
+    let mut options = WatchOptions {
+        mirror: args.option("mirror").map(str::to_string),
+        ..WatchOptions::default()
+    };
+    if let Some(debounce) = args.option("debounce") {
+        options.debounce = std::time::Duration::from_millis(debounce.parse()?);
+    }
+
+    watch::watch(&args.positional[0], &args.positional[1], &options)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+
+fn decompile(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &[])?;
+    args.expect_positional(2)?;
+
+    let options = DecompileOptions {
+        annotate_speakers: args.flag("annotate-speakers"),
+        source_map: args.flag("source-map"),
+        listing: args.flag("listing"),
+        lenient: args.flag("lenient"),
+    };
+
+    decompiler::decompile_lin_with_options(args.positional[0].clone(), args.positional[1].clone(), &options)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+
+// Offsets can be given in hex (0x1A4) or decimal
+fn locate(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["map"])?;
+    args.expect_positional(2)?;
+
+    let offset = &args.positional[1];
+    let offset = match offset.strip_prefix("0x").or_else(|| offset.strip_prefix("0X")) {
+        Some(hex) => { usize::from_str_radix(hex, 16)? }
+        None => { offset.parse()? }
+    };
+
+    let map = match args.option("map") {
+        Some(filename) => { SourceMap::load(filename)? }
+        None => { sourcemap::for_lin(&args.positional[0])? }
+    };
+    let found = map.locate(offset);
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+    }
+    else if let Some(mapping) = found {
+        println!(
+            "{}:{}:{} {} (opcode {}, bytes {:#x}..{:#x})",
+            map.source, mapping.line, mapping.column, mapping.opcode, mapping.op_index, mapping.start, mapping.end
+        );
+    }
+    else {
+        let section = match map.mappings.first() {
+            Some(first) if offset < first.start => { "the header" }
+            _ => { "the padding or text table" }
+        };
+        println!("{:#x} is in {} of {}, not an opcode", offset, section, map.lin);
+    }
+
+    Ok(if found.is_some() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+
+fn assets(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["manifest"])?;
+    args.expect_positional(1)?;
+
+    let report = assets::asset_report(&args.positional[0], args.option("manifest"))?;
+
+    if args.flag("json") {
+        println!("{}", report.to_json()?);
+    }
+    else {
+        for (asset, uses) in &report.assets {
+            println!("{}", asset);
+            for asset_use in uses {
+                println!("    {} opcode {} ({})", asset_use.script, asset_use.op_index, asset_use.opcode);
+            }
+        }
+
+        for asset in &report.missing {
+            println!("missing: {}", asset);
+        }
+    }
+
+    // Missing assets fail the command so it can gate a mod build
+    if report.missing.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+
+fn simulate(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["flag", "choice", "character", "object"])?;
+    args.expect_positional(1)?;
+
+    let script = read_lin(&args.positional[0])?;
+    let mut simulator = Simulator::new(&script);
+
+    for flag in args.all("flag") {
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => { (flag, value.parse()?) }
+            None => { (flag, 1) }
+        };
+        simulator.flags.insert(flag.parse()?, value);
+    }
+    for choice in args.all("choice") {
+        simulator.choices.push_back(choice.parse()?);
+    }
+    if let Some(character) = args.option("character") {
+        simulator.character = Some(character.parse()?);
+    }
+    if let Some(object) = args.option("object") {
+        simulator.object = Some(object.parse()?);
+    }
+
+    let run = simulator.run();
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&run)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for event in &run.events {
+        match event {
+            Event::Line { text, speaker, .. } => {
+                let speaker = match speaker {
+                    Some(speaker) => { dgrlin::characters::name_or_id(*speaker) }
+                    None => { "???".to_string() }
+                };
+                println!("{}: {}", speaker, text.as_deref().unwrap_or("<missing text>"));
+            }
+            Event::Choice { picked, .. } => { println!("> choice {}", picked); }
+            Event::Script { opcode, target, .. } => { println!("> {} {}", opcode, target); }
+            _ => {}
+        }
+    }
+    println!("> stopped: {:?}", run.halt);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+
+fn overflow(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["config", "fix"])?;
+    args.expect_positional(1)?;
+
+    let config = match args.option("config") {
+        Some(filename) => { OverflowConfig::load(filename)? }
+        None => { OverflowConfig::default() }
+    };
+    let mut project = Project::load_path(&args.positional[0])?;
+
+    let overflows = overflow::check_project(&project, &config);
+
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&overflows)?);
+    }
+    else {
+        for found in &overflows {
+            println!(
+                "{} text {} (opcode {}): {} wide in {} lines, box is {} wide in {} lines",
+                found.script, found.text_id, found.op_index, found.width, found.lines, found.max_width, found.max_lines
+            );
+            println!("    {}", found.text);
+        }
+    }
+
+    // Wrapped scripts come back out as .txt, ready to check over and compile
+    if let Some(output_folder) = args.option("fix") {
+        for (name, script) in project.scripts.iter_mut() {
+            let (fixed, unfixed) = overflow::fix(name, script, &config);
+            for found in &unfixed {
+                println!("{} text {} (opcode {}) still won't fit once wrapped, split or shorten it", name, found.text_id, found.op_index);
+            }
+            if fixed == 0 {
+                continue;
+            }
+
+            let mut file = std::fs::File::create(format!("{}/{}.txt", output_folder, name))?;
+            decompiler::write_script(&mut file, script, &DecompileOptions::default())?;
+            println!("wrapped {}", name);
+        }
+    }
+
+    if overflows.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+
+fn fmt(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &[])?;
+    if args.positional.is_empty() {
+        eyre::bail!("expected at least 1 argument\n\n{}", USAGE);
+    }
+
+    let check = args.flag("check");
+    let mut changed = false;
+
+    for filename in &args.positional {
+        if formatter::format_file(filename, check)? {
+            changed = true;
+            if check {
+                println!("would reformat {}", filename);
+            } else {
+                println!("formatted {}", filename);
+            }
+        }
+    }
+
+    // Only --check fails, so it can gate a commit
+    if check && changed {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+
+fn diff(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &[])?;
+    args.expect_positional(2)?;
+
+    let a = LinScript::open(&args.positional[0])?;
+    let b = LinScript::open(&args.positional[1])?;
+    let found = diff::diff(&a, &b);
+
+    if args.flag("json") {
+        println!("{}", found.to_json()?);
+    }
+    else {
+        for change in &found.changes {
+            let (kind, a_index, b_index, shown) = match change {
+                Change::Removed { a_index, opcode } => { ("removed", Some(a_index), None, opcode.clone()) }
+                Change::Inserted { b_index, opcode } => { ("inserted", None, Some(b_index), opcode.clone()) }
+                Change::Changed { a_index, b_index, old, new } => {
+                    ("changed", Some(a_index), Some(b_index), format!("{} -> {}", old, new))
+                }
+                Change::TextChanged { a_index, b_index, old, new } => {
+                    ("text", Some(a_index), Some(b_index), format!("\"{}\"\n{:25}\"{}\"", old, "", new))
+                }
+            };
+
+            let a_index = a_index.map(|idx| format!("a:{}", idx)).unwrap_or_default();
+            let b_index = b_index.map(|idx| format!("b:{}", idx)).unwrap_or_default();
+            println!("{:<8} {:<7} {:<7} {}", kind, a_index, b_index, shown);
+        }
+    }
+
+    // Like diff(1), differences exit 1
+    if found.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+
+// As a git merge driver: dgrlin merge %O %A %B
+fn merge(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["output"])?;
+    args.expect_positional(3)?;
+
+    let (base, ours, theirs) = (&args.positional[0], &args.positional[1], &args.positional[2]);
+    let output = args.option("output").unwrap_or(ours.as_str());
+
+    let conflicts = merge::merge_files(base, ours, theirs, output)?;
+
+    // Git reads a non-zero exit as "left conflicted"
+    if conflicts == 0 {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!("{} conflict(s) merging {}", conflicts, output);
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+
+fn pak(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &[])?;
+
+    match args.positional.first().map(String::as_str) {
+        Some("list") => {
+            args.expect_positional(2)?;
+            let listing = pak::read_pak(&args.positional[1])?.list();
+
+            if args.flag("json") {
+                println!("{}", serde_json::to_string_pretty(&listing)?);
+            }
+            else {
+                for entry in &listing {
+                    let depth = entry.path.matches('/').count();
+                    println!("{:<12} {:#010x} {:>9} {}{}", entry.path, entry.offset, entry.size, "  ".repeat(depth), entry.kind);
+                }
+            }
+        }
+        Some("extract") => {
+            args.expect_positional(3)?;
+            let written = pak::extract(&pak::read_pak(&args.positional[1])?, std::path::Path::new(&args.positional[2]))?;
+            println!("extracted {} files", written.len());
+        }
+        Some("repack") => {
+            args.expect_positional(3)?;
+            pak::compile_pak(&args.positional[1], &args.positional[2])?;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(ExitCode::from(2));
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+
+// Patches are applied in the order given, so later ones win where they overlap
+fn patch(args: &[String]) -> eyre::Result<ExitCode> {
+    let args = Args::parse(args, &["output"])?;
+
+    match args.positional.first().map(String::as_str) {
+        Some("create") => {
+            args.expect_positional(4)?;
+            let patch = patch::create_file(&args.positional[1], &args.positional[2], &args.positional[3])?;
+            println!("{} hunks", patch.hunks.len());
+        }
+        Some("apply") if args.positional.len() >= 3 => {
+            let Some(output) = args.option("output") else {
+                eyre::bail!("patch apply needs --output\n\n{}", USAGE);
+            };
+            let overlaps = patch::apply_files(&args.positional[1], &args.positional[2..], output)?;
+
+            if args.flag("json") {
+                println!("{}", serde_json::to_string_pretty(&overlaps)?);
+            }
+            else {
+                for overlap in &overlaps {
+                    println!("overlap: {}", overlap);
+                }
+            }
+
+            if !overlaps.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(ExitCode::from(2));
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
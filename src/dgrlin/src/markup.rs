@@ -0,0 +1,269 @@
+use serde::Serialize;
+
+use std::fmt;
+
+
+/// Tags the game is known to understand.
+/// `<CLT n>` switches to text colour `n` and `<CLT>` switches back.
+pub const KNOWN_TAGS: [&str; 1] = ["CLT"];
+
+
+/// A piece of a line of dialog.
+/// Rendering the tokens back with `render` always gives back the exact original,
+/// tags the game doesn't know about included.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Token {
+    Text { text: String },
+    /// `<NAME>` or `<NAME arg>`, kept exactly as written in `raw`.
+    Tag { raw: String, name: String, arg: Option<String> },
+    /// `%CTRL_SHOW_HELP%`, swapped for a button prompt by the game.
+    Variable { name: String },
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Text { text }    => write!(f, "{}", text),
+            Token::Tag { raw, .. }  => write!(f, "{}", raw),
+            Token::Variable { name } => write!(f, "%{}%", name),
+        }
+    }
+}
+
+
+pub fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut plain = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let token = match c {
+            '<' => { rest.find('>').and_then(|end| parse_tag(&rest[..=end])) }
+            '%' => { rest[1..].find('%').and_then(|end| parse_variable(&rest[..end + 2])) }
+            _   => { None }
+        };
+
+        match token {
+            Some((token, length)) => {
+                if !plain.is_empty() {
+                    tokens.push(Token::Text { text: std::mem::take(&mut plain) });
+                }
+                tokens.push(token);
+                rest = &rest[length..];
+            }
+            None => {
+                plain.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    if !plain.is_empty() {
+        tokens.push(Token::Text { text: plain });
+    }
+
+    tokens
+}
+
+
+// `raw` runs from '<' to '>'. Tag names are uppercase letters, with one optional argument.
+fn parse_tag(raw: &str) -> Option<(Token, usize)> {
+    let inner = &raw[1..raw.len() - 1];
+    let (name, arg) = match inner.split_once(' ') {
+        Some((name, arg)) => { (name, Some(arg.to_string())) }
+        None => { (inner, None) }
+    };
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+
+    Some((Token::Tag { raw: raw.to_string(), name: name.to_string(), arg }, raw.len()))
+}
+
+
+// `raw` runs from '%' to '%'. Only SHOUTING_SNAKE_CASE counts, so "100% sure" stays text.
+fn parse_variable(raw: &str) -> Option<(Token, usize)> {
+    let name = &raw[1..raw.len() - 1];
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+        return None;
+    }
+
+    Some((Token::Variable { name: name.to_string() }, raw.len()))
+}
+
+
+pub fn render(tokens: &[Token]) -> String {
+    tokens.iter().map(|token| token.to_string()).collect()
+}
+
+
+/// The text with every tag taken out. Variables are left in.
+pub fn plain_text(text: &str) -> String {
+    tokenize(text)
+        .iter()
+        .filter(|token| !matches!(token, Token::Tag { .. }))
+        .map(|token| token.to_string())
+        .collect()
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct MarkupIssue {
+    pub severity: Severity,
+    /// Character offset into the text.
+    pub offset: usize,
+    pub message: String,
+}
+
+
+/// Unknown tags, bad colour numbers, stray `<CLT>`s and colours left open.
+///
+/// A colour left open at the end of a line is only a warning,
+/// since the game resets it anyway and the original script does it in places.
+pub fn validate(text: &str) -> Vec<MarkupIssue> {
+    let mut issues: Vec<MarkupIssue> = Vec::new();
+    let mut offset = 0usize;
+    let mut open: Option<usize> = None;
+
+    for token in tokenize(text) {
+        if let Token::Tag { raw, name, arg } = &token {
+            match (name.as_str(), arg) {
+                ("CLT", Some(colour)) => {
+                    if colour.parse::<u8>().is_err() {
+                        issues.push(MarkupIssue {
+                            severity: Severity::Error,
+                            offset,
+                            message: format!("'{}' needs a colour number from 0 to 255", raw),
+                        });
+                    }
+                    if let Some(opened) = open {
+                        issues.push(MarkupIssue {
+                            severity: Severity::Warning,
+                            offset,
+                            message: format!("'{}' opened while the colour from offset {} is still open", raw, opened),
+                        });
+                    }
+                    open = Some(offset);
+                }
+                ("CLT", None) => {
+                    if open.is_none() {
+                        issues.push(MarkupIssue {
+                            severity: Severity::Warning,
+                            offset,
+                            message: "'<CLT>' closes a colour that was never opened".to_string(),
+                        });
+                    }
+                    open = None;
+                }
+                _ => {
+                    issues.push(MarkupIssue {
+                        severity: Severity::Warning,
+                        offset,
+                        message: format!("Unknown tag '{}'", raw),
+                    });
+                }
+            }
+        }
+
+        offset += token.to_string().chars().count();
+    }
+
+    if let Some(opened) = open {
+        issues.push(MarkupIssue {
+            severity: Severity::Warning,
+            offset: opened,
+            message: "Colour is never closed with '<CLT>'".to_string(),
+        });
+    }
+
+    issues
+}
+
+
+/// A run of text drawn in one colour. `colour` is None for the default.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct StyledSpan {
+    pub text: String,
+    pub colour: Option<u8>,
+}
+
+
+/// The line as coloured runs, with tags applied and dropped.
+/// Unknown tags don't change the colour.
+pub fn styled_spans(text: &str) -> Vec<StyledSpan> {
+    let mut spans: Vec<StyledSpan> = Vec::new();
+    let mut colour: Option<u8> = None;
+
+    for token in tokenize(text) {
+        match token {
+            Token::Tag { name, arg, .. } if name == "CLT" => {
+                colour = arg.and_then(|arg| arg.parse().ok());
+            }
+            Token::Tag { .. } => {}
+            other => {
+                let piece = other.to_string();
+                match spans.last_mut() {
+                    Some(last) if last.colour == colour => { last.text += &piece; }
+                    _ => { spans.push(StyledSpan { text: piece, colour }); }
+                }
+            }
+        }
+    }
+
+    spans
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use super::{plain_text, render, styled_spans, tokenize, validate, Severity, StyledSpan, Token};
+
+    #[test]
+    fn test_tokenize_round_trips() {
+        let text = "<CLT 23>Press <CLT><CLT 3>%CTRL_SHOW_HELP%<CLT> for 100% of <HUH 2>it\\n";
+        let tokens = tokenize(text);
+
+        assert_that!(tokens[0].clone(), equal_to(Token::Tag {
+            raw: "<CLT 23>".to_string(), name: "CLT".to_string(), arg: Some("23".to_string())
+        }));
+        assert_that!(tokens[4].clone(), equal_to(Token::Variable { name: "CTRL_SHOW_HELP".to_string() }));
+        assert_that!(render(&tokens).as_str(), equal_to(text));
+        assert_that!(plain_text(text).as_str(), equal_to("Press %CTRL_SHOW_HELP% for 100% of it\\n"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_that!(validate("<CLT 4>Fine<CLT>").len(), equal_to(0));
+
+        let issues = validate("<CLT x>Oops<CLR>");
+        assert_that!(issues.len(), equal_to(3));
+        assert_that!(issues[0].severity, equal_to(Severity::Error));
+        assert_that!(issues[1].message.as_str(), equal_to("Unknown tag '<CLR>'"));
+        assert_that!(issues[2].offset, equal_to(0));
+    }
+
+    #[test]
+    fn test_styled_spans() {
+        assert_that!(styled_spans("Try <CLT 3>this<CLT>."), equal_to(vec![
+            StyledSpan { text: "Try ".to_string(), colour: None },
+            StyledSpan { text: "this".to_string(), colour: Some(3) },
+            StyledSpan { text: ".".to_string(), colour: None },
+        ]));
+    }
+}
@@ -0,0 +1,367 @@
+use eyre::Context;
+
+use std::fs::{read, write};
+
+use crate::compiler::{self, Comments};
+use crate::decompiler::{decode, write_source, DecompileOptions};
+use crate::diff::{align, describe, key, Edit};
+use crate::error::InFile;
+use crate::opcode::Opcode;
+use crate::script::LinScript;
+
+
+/// One version of a script, with its comments when it came from a .txt.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Side {
+    pub script: LinScript,
+    pub comments: Comments,
+    /// Whether it was a .txt, so the merge is written back out the same way.
+    pub is_source: bool,
+}
+
+impl Side {
+    /// .lin files are told apart by their header, since git hands a merge driver temporary files without extensions.
+    pub fn open(filename: &str) -> eyre::Result<Self> {
+        let bytes = read(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+
+        if bytes.starts_with(&[0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00]) {
+            let script = decode(&bytes).wrap_err(InFile(filename.to_string()))?;
+            return Ok(Side { script, comments: Comments::default(), is_source: false });
+        }
+
+        let parsed = compiler::parse(&String::from_utf8(bytes)?).wrap_err(InFile(filename.to_string()))?;
+        Ok(Side { script: parsed.script, comments: parsed.comments, is_source: true })
+    }
+
+
+    fn item(&self, op_idx: usize) -> Item {
+        Item {
+            op: self.script.ops[op_idx].clone(),
+            text: self.script.text_for(&self.script.ops[op_idx]).cloned(),
+            leading: self.comments.leading.get(&op_idx).cloned().unwrap_or_default(),
+            trailing: self.comments.trailing.get(&op_idx).cloned(),
+            shown: describe(&self.script, &self.script.ops[op_idx]),
+        }
+    }
+}
+
+
+/// An opcode taken from one side, carrying its own line of text and comments.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Item {
+    pub op: Opcode,
+    pub text: Option<String>,
+    pub leading: Vec<String>,
+    pub trailing: Option<String>,
+    /// What it's compared by: the opcode as written, with the actual line for a Text.
+    pub shown: String,
+}
+
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Piece {
+    Merged(Item),
+    Conflict { ours: Vec<Item>, theirs: Vec<Item> },
+}
+
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Merge {
+    pub pieces: Vec<Piece>,
+}
+
+impl Merge {
+    pub fn conflicts(&self) -> usize {
+        self.pieces.iter().filter(|piece| matches!(piece, Piece::Conflict { .. })).count()
+    }
+
+
+    /// The merged script, or None while there are conflicts.
+    pub fn to_script(&self) -> Option<LinScript> {
+        if self.conflicts() > 0 {
+            return None;
+        }
+        Some(self.build().0)
+    }
+
+
+    /// The merged .txt, conflicts and all.
+    /// Each conflict gets git style markers, which the compiler refuses until they're sorted out.
+    pub fn to_source(&self) -> eyre::Result<String> {
+        let (script, comments) = self.build();
+
+        let mut written: Vec<u8> = Vec::new();
+        write_source(&mut written, &script, &comments, &DecompileOptions::default())?;
+
+        // Markers ride along as comments, but belong at the start of the line
+        let lines: Vec<&str> = std::str::from_utf8(&written)?
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim_start();
+                if ["<<<<<<< ", "=======", ">>>>>>> "].iter().any(|marker| trimmed.starts_with(marker)) {
+                    trimmed
+                } else {
+                    line
+                }
+            })
+            .collect();
+
+        Ok(lines.join("\n") + "\n")
+    }
+
+
+    // Renumbers the text as it goes, since both sides may have added lines.
+    fn build(&self) -> (LinScript, Comments) {
+        let mut script = LinScript::default();
+        let mut comments = Comments::default();
+        let mut pending: Vec<String> = Vec::new();
+
+        let mut push = |item: &Item, pending: &mut Vec<String>| {
+            let op_idx = script.ops.len();
+            let mut op = item.op.clone();
+
+            if let Some(text) = &item.text {
+                let text_id = script.text_entries.len() as u16;
+                op.text_id = Some(text_id);
+                op.hexcode.splice(2.., text_id.to_be_bytes());
+                script.text_entries.push(text.clone());
+            }
+
+            pending.extend(item.leading.iter().cloned());
+            if !pending.is_empty() {
+                comments.leading.insert(op_idx, std::mem::take(pending));
+            }
+            if let Some(trailing) = &item.trailing {
+                comments.trailing.insert(op_idx, trailing.clone());
+            }
+
+            script.ops.push(op);
+        };
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Merged(item) => { push(item, &mut pending); }
+                Piece::Conflict { ours, theirs } => {
+                    pending.push("<<<<<<< ours".to_string());
+                    for item in ours {
+                        push(item, &mut pending);
+                    }
+                    pending.push("=======".to_string());
+                    for item in theirs {
+                        push(item, &mut pending);
+                    }
+                    pending.push(">>>>>>> theirs".to_string());
+                }
+            }
+        }
+
+        comments.end = pending;
+        (script, comments)
+    }
+}
+
+
+/// Three-way merges two edits of `base`, opcode by opcode and line by line.
+///
+/// Opcodes every side still has are kept as anchors. Between two anchors, whichever side
+/// changed something wins, and if both changed it differently that stretch is a conflict.
+/// Where both made the same change, their comments are merged item by item the same way.
+/// An anchored Text whose line was reworded is merged the same way on its own.
+pub fn merge(base: &Side, ours: &Side, theirs: &Side) -> Merge {
+    let keys = |side: &Side| -> Vec<String> { side.script.ops.iter().map(key).collect() };
+    let (base_keys, our_keys, their_keys) = (keys(base), keys(ours), keys(theirs));
+
+    // Where each base opcode went on either side, if it's still there
+    let matches = |edits: Vec<Edit>| -> Vec<Option<usize>> {
+        let mut matched = vec![None; base_keys.len()];
+        for edit in edits {
+            if let Edit::Same(base_idx, side_idx) = edit {
+                matched[base_idx] = Some(side_idx);
+            }
+        }
+        matched
+    };
+    let ours_at = matches(align(&base_keys, &our_keys));
+    let theirs_at = matches(align(&base_keys, &their_keys));
+
+    let mut pieces: Vec<Piece> = Vec::new();
+    let (mut base_from, mut ours_from, mut theirs_from) = (0usize, 0usize, 0usize);
+
+    for base_idx in 0..base_keys.len() {
+        let (our_idx, their_idx) = match (ours_at[base_idx], theirs_at[base_idx]) {
+            (Some(our_idx), Some(their_idx)) => { (our_idx, their_idx) }
+            _ => { continue; }
+        };
+
+        merge_chunk(
+            (base_from..base_idx).map(|idx| base.item(idx)).collect(),
+            (ours_from..our_idx).map(|idx| ours.item(idx)).collect(),
+            (theirs_from..their_idx).map(|idx| theirs.item(idx)).collect(),
+            &mut pieces,
+        );
+        merge_chunk(vec![base.item(base_idx)], vec![ours.item(our_idx)], vec![theirs.item(their_idx)], &mut pieces);
+
+        (base_from, ours_from, theirs_from) = (base_idx + 1, our_idx + 1, their_idx + 1);
+    }
+
+    merge_chunk(
+        (base_from..base.script.ops.len()).map(|idx| base.item(idx)).collect(),
+        (ours_from..ours.script.ops.len()).map(|idx| ours.item(idx)).collect(),
+        (theirs_from..theirs.script.ops.len()).map(|idx| theirs.item(idx)).collect(),
+        &mut pieces,
+    );
+
+    Merge { pieces }
+}
+
+
+fn merge_chunk(base: Vec<Item>, ours: Vec<Item>, theirs: Vec<Item>, pieces: &mut Vec<Piece>) {
+    let same = |a: &[Item], b: &[Item]| a.iter().map(|item| &item.shown).eq(b.iter().map(|item| &item.shown));
+
+    // Both made the same opcode changes, so only their comments can still differ
+    if same(&ours, &theirs) {
+        merge_comments(&base, ours, theirs, pieces);
+        return;
+    }
+
+    let merged = if same(&ours, &base) {
+        theirs
+    }
+    else if same(&theirs, &base) {
+        ours
+    }
+    else {
+        pieces.push(Piece::Conflict { ours, theirs });
+        return;
+    };
+
+    pieces.extend(merged.into_iter().map(Piece::Merged));
+}
+
+
+// Merges the comments on each pair of matching items like the opcodes themselves: a side that
+// left them as they were in base gives way to one that didn't. Items the two sides added alike
+// have nothing in base, so count as having had no comments.
+fn merge_comments(base: &[Item], ours: Vec<Item>, theirs: Vec<Item>, pieces: &mut Vec<Piece>) {
+    let shown = |items: &[Item]| -> Vec<String> { items.iter().map(|item| item.shown.clone()).collect() };
+
+    let mut base_at: Vec<Option<&Item>> = vec![None; ours.len()];
+    for edit in align(&shown(base), &shown(&ours)) {
+        if let Edit::Same(base_idx, our_idx) = edit {
+            base_at[our_idx] = Some(&base[base_idx]);
+        }
+    }
+
+    fn pick<T: PartialEq + Clone>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+        if ours == theirs || theirs == base {
+            Some(ours.clone())
+        }
+        else if ours == base {
+            Some(theirs.clone())
+        }
+        else {
+            None
+        }
+    }
+
+    for ((ours, theirs), base) in ours.into_iter().zip(theirs).zip(base_at) {
+        let (leading, trailing) = base.map_or((Vec::new(), None), |base| (base.leading.clone(), base.trailing.clone()));
+
+        match (pick(&leading, &ours.leading, &theirs.leading), pick(&trailing, &ours.trailing, &theirs.trailing)) {
+            (Some(leading), Some(trailing)) => { pieces.push(Piece::Merged(Item { leading, trailing, ..ours })); }
+            _ => { pieces.push(Piece::Conflict { ours: vec![ours], theirs: vec![theirs] }); }
+        }
+    }
+}
+
+
+/// Merges three versions of a script file and writes the result over `output`, the way git expects
+/// from a merge driver. A .txt is written with its conflicts marked, a .lin with conflicts is left alone.
+/// Returns how many conflicts there were.
+pub fn merge_files(base: &str, ours: &str, theirs: &str, output: &str) -> eyre::Result<usize> {
+    let (base, ours, theirs) = (Side::open(base)?, Side::open(ours)?, Side::open(theirs)?);
+    let merged = merge(&base, &ours, &theirs);
+
+    if ours.is_source {
+        write(output, merged.to_source()?).wrap_err("Could not write to file (Unknown issue)")?;
+    }
+    else if let Some(script) = merged.to_script() {
+        write(output, compiler::encode(&script)).wrap_err("Could not write to file (Unknown issue)")?;
+    }
+
+    Ok(merged.conflicts())
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::compiler::parse;
+
+    use super::{merge, Side};
+
+    fn side(source: &str) -> Side {
+        let parsed = parse(source).unwrap();
+        Side { script: parsed.script, comments: parsed.comments, is_source: true }
+    }
+
+    const BASE: &str = "Speaker(0)\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n";
+
+    #[test]
+    fn test_merge_independent_edits() {
+        let ours = side("Speaker(0)\nText(\"One!\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+        let theirs = side("Speaker(0)\nText(\"One\")\nWaitInput()\nSpeaker(8) // Kyoko\nSprite(0, 8, 2, 0, 0)\nText(\"Two\")\nWaitInput()\n");
+
+        let merged = merge(&side(BASE), &ours, &theirs);
+
+        assert_that!(merged.conflicts(), equal_to(0));
+        assert_that!(merged.to_source().unwrap().as_str(), equal_to(
+            "Speaker(0)\nText(\"One!\")\nWaitInput()\nSpeaker(8) // Kyoko\nSprite(0, 8, 2, 0, 0)\nText(\"Two\")\nWaitInput()\n"
+        ));
+        assert_that!(merged.to_script().unwrap().text_entries, equal_to(vec!["One!".to_string(), "Two".to_string()]));
+    }
+
+    #[test]
+    fn test_merge_marks_conflicts() {
+        let ours = side("Speaker(0)\nText(\"Uno\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+        let theirs = side("Speaker(0)\nText(\"Ichi\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+
+        let merged = merge(&side(BASE), &ours, &theirs);
+
+        assert_that!(merged.conflicts(), equal_to(1));
+        assert_that!(merged.to_script(), equal_to(None));
+        assert_that!(merged.to_source().unwrap().as_str(), equal_to(
+            "Speaker(0)\n<<<<<<< ours\nText(\"Uno\")\n=======\nText(\"Ichi\")\n>>>>>>> theirs\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n"
+        ));
+        assert_that!(parse(&merged.to_source().unwrap()).is_err(), is(true));
+    }
+    #[test]
+    fn test_merge_comments_on_the_same_change() {
+        // Both bumped the sprite, each with their own note on it
+        let ours = side("Speaker(0)\nSprite(0, 0, 2, 0, 0) // smiling\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+        let theirs = side("Speaker(0)\nSprite(0, 0, 2, 0, 0) // grinning\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+
+        let merged = merge(&side(BASE), &ours, &theirs);
+
+        assert_that!(merged.conflicts(), equal_to(1));
+        assert_that!(merged.to_source().unwrap().as_str(), equal_to(
+            "Speaker(0)\n<<<<<<< ours\nSprite(0, 0, 2, 0, 0) // smiling\n=======\nSprite(0, 0, 2, 0, 0) // grinning\n>>>>>>> theirs\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n"
+        ));
+
+        // Only one of them noted it, and the other commented on a different opcode
+        let ours = side("Speaker(0)\nSprite(0, 0, 2, 0, 0) // smiling\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+        let theirs = side("Speaker(0) // Makoto\nSprite(0, 0, 2, 0, 0)\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+
+        let merged = merge(&side(BASE), &ours, &theirs);
+
+        assert_that!(merged.conflicts(), equal_to(0));
+        assert_that!(merged.to_source().unwrap().as_str(), equal_to(
+            "Speaker(0) // Makoto\nSprite(0, 0, 2, 0, 0) // smiling\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n"
+        ));
+    }
+}
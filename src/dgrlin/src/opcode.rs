@@ -4,7 +4,7 @@ use log;
 
 use byteorder::{BigEndian, WriteBytesExt};
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Opcode {
     pub name: String,
     pub hexcode: Vec<u8>,
@@ -17,18 +17,31 @@ impl Opcode {
     }
 
 
+    /// The bytes after the 0x70 and the opcode's own byte.
+    /// Raw has neither, so its args are every byte it holds.
+    pub fn args(&self) -> &[u8] {
+        if self.name == "Raw" {
+            return &self.hexcode;
+        }
+        self.hexcode.get(2..).unwrap_or(&[])
+    }
+
+
     pub fn try_from_string(raw_value: String, text_id: u32) -> (Option<Self>, Option<String>) {
-        let value = raw_value.trim();
+        let value = strip_comment(&raw_value).trim();
 
-        // Brackets and that newline at the end are to be thrown away
-        if value.contains("{") || value.contains("}") || value.len() < 2 {
+        // Brackets and that newline at the end are to be thrown away.
+        // Only a line starting with one, since dialog can have braces in it.
+        if value.starts_with('{') || value.starts_with('}') || value.len() < 2 {
             return (None, None);
         }
 
-        let mut split_string = value.split("(");
-        let opcode_text = split_string.next().unwrap();
+        // Only the first '(' counts, dialog can have its own
+        let Some((opcode_text, args)) = value.split_once("(") else {
+            return (None, None);
+        };
 
-        let mut args = split_string.next().unwrap().chars();
+        let mut args = args.chars();
         args.next_back(); // Popping off the ending ')'
 
 
@@ -48,7 +61,7 @@ impl Opcode {
 
             return (Some(Opcode {
                 name: "Text".to_string(),
-                hexcode: hexcode,
+                hexcode,
                 text_id: None
             }), Some(args.collect()))
         }
@@ -60,47 +73,18 @@ impl Opcode {
                 .collect::<Vec<u8>>()
         };
         
-        let opcode: u8 = match opcode_text {
-            "0x00"             => 0x00,
-            "TextBoxFormat"    => 0x03,
-            "PostProcessingFilter" => 0x04,
-            "Movie"            => 0x05,
-            "Animation"        => 0x06,
-            "Voice"            => 0x08,
-            "Music"            => 0x09,
-            "Sound"            => 0x0A,
-            "SoundB"           => 0x0B,
-            "AddTruthBullets"  => 0x0C,
-            "AddPresents"      => 0x0D,
-            "UnlockSkill"      => 0x0E,
-            "StudentTitleEntry"=> 0x0F,
-            "TrialCamera"      => 0x14,
-            "LoadMap"          => 21u8,
-            "LoadScript"       => 0x19,
-            "StopScript"       => 0x1A,
-            "RunScript"        => 0x1B,
-            "0x1C"             => 0x1C,
-            "Sprite"           => 30u8,
-            "ScreenFlash"      => 31u8,
-            "SpriteFlash"      => 32u8,
-            "Speaker"          => 33u8,
-            "ScreenFade"       => 34u8,
-            "ChangeUi"         => 37u8,
-            "SetFlag"          => 38u8,
-            "CheckCharacter"   => 39u8,
-            "CheckObject"      => 41u8,
-            "SetLabel"         => 42u8,
-            "SetChoiceText"    => 43u8,
-            "CameraShake"      => 0x2E,
-            "ShowBackground"   => 48u8,
-            "0x33"             => 51u8,
-            "GoToLabel"        => 52u8,
-            "CheckFlagA"       => 0x35,
-            "CheckFlagB"       => 0x36,
-            "WaitInput"        => 58u8,
-            "WaitFrame"        => 59u8,
-            "IfFlagCheck"      => 60u8,
-            _badop             => {
+        // Bytes the lenient decompiler couldn't read, kept exactly as they were
+        if opcode_text == "Raw" {
+            return (Some(Opcode {
+                name: "Raw".to_string(),
+                hexcode: args,
+                text_id: None
+            }), None)
+        }
+
+        let opcode: u8 = match info(opcode_text) {
+            Some(info) => { info.code }
+            None => {
                 log::error!("INVALID OPCODE - {}", value);
                 254u8
             }
@@ -113,32 +97,148 @@ impl Opcode {
 
         (Some(Opcode {
             name: opcode_text.to_string(),
-            hexcode: hexcode,
+            hexcode,
             text_id: None
         }), None)
     }
 }
 
+/// Cuts a trailing `// comment` off of a line.
+/// A `//` only counts when it starts the line or follows the closing ')',
+/// and nothing after it is a double quote, so dialogue containing "//" survives.
+pub fn strip_comment(line: &str) -> &str {
+    for (idx, _) in line.rmatch_indices("//") {
+        let before = line[..idx].trim_end();
+        let after = &line[idx..];
+
+        if (before.is_empty() || before.ends_with(')') || before.ends_with('{') || before.ends_with('}'))
+            && !after.contains('"') {
+            return before;
+        }
+    }
+
+    line
+}
+
+
+/// What's known about one opcode.
+#[derive(Debug)]
+pub struct OpcodeInfo {
+    pub name: &'static str,
+    /// The byte after 0x70.
+    pub code: u8,
+    /// How many argument bytes follow, 255 for CheckFlagA/B which run up to the next opcode.
+    pub arg_count: u8,
+    /// What each argument byte means, "?" where nobody knows yet.
+    pub args: &'static [&'static str],
+    pub doc: &'static str,
+}
+
+impl OpcodeInfo {
+    /// `Sprite(position, character, sprite, state, transition)`
+    pub fn signature(&self) -> String {
+        let mut args = self.args.join(", ");
+        if self.arg_count == 255 {
+            args += ", [join, ...]";
+        }
+        format!("{}({})", self.name, args)
+    }
+
+
+    /// What's wrong with giving this opcode `given` argument bytes, if anything.
+    /// The game reads a set number of bytes after each opcode, so a wrong count swallows the next
+    /// one. Only CheckFlagA and B (255) run up to the next 0x70.
+    pub fn check_arg_count(&self, given: usize) -> Option<String> {
+        if self.arg_count == 255 || given == self.arg_count as usize {
+            return None;
+        }
+        Some(format!(
+            "{} takes {} argument{}, not {}",
+            self.name, self.arg_count, if self.arg_count == 1 { "" } else { "s" }, given
+        ))
+    }
+}
+
+
+const fn op(name: &'static str, code: u8, arg_count: u8, args: &'static [&'static str], doc: &'static str) -> OpcodeInfo {
+    OpcodeInfo { name, code, arg_count, args, doc }
+}
+
+/// Every opcode the compiler and decompiler know about.
+pub const OPCODES: [OpcodeInfo; 40] = [
+    op("0x00",                 0x00, 2, &["?", "?"], "Unknown."),
+    op("Text",                 0x02, 2, &["text id (high)", "text id (low)"], "Shows a line of dialog. Written with the line itself in quotes, the compiler numbers them."),
+    op("TextBoxFormat",        0x03, 1, &["format"], "Picks the style of text box for the Text that follows."),
+    op("PostProcessingFilter", 0x04, 4, &["filter", "?", "?", "?"], "Applies a screen filter."),
+    op("Movie",                0x05, 2, &["movie", "?"], "Plays a movie."),
+    op("Animation",            0x06, 8, &["?", "?", "?", "?", "?", "?", "?", "?"], "Plays an animation."),
+    op("Voice",                0x08, 5, &["character", "chapter", "voice (high)", "voice (low)", "volume"], "Plays a voice clip, which goes with the next Text. Character 255 plays nothing."),
+    op("Music",                0x09, 3, &["bgm", "volume", "?"], "Plays background music. 255 stops it."),
+    op("Sound",                0x0A, 3, &["sfx (high)", "sfx (low)", "volume"], "Plays a sound effect."),
+    op("SoundB",               0x0B, 2, &["sfx (high)", "sfx (low)"], "Plays a sound effect."),
+    op("AddTruthBullets",      0x0C, 2, &["bullet", "?"], "Gives the player a truth bullet."),
+    op("AddPresents",          0x0D, 3, &["present", "?", "?"], "Gives the player a present."),
+    op("UnlockSkill",          0x0E, 2, &["skill", "?"], "Unlocks a skill."),
+    op("StudentTitleEntry",    0x0F, 3, &["?", "?", "?"], "Updates a student's report card entry."),
+    op("TrialCamera",          0x14, 3, &["?", "?", "?"], "Moves the class trial camera."),
+    op("LoadMap",              0x15, 3, &["room", "?", "?"], "Loads a map. Room 255 unloads it."),
+    op("LoadScript",           0x19, 3, &["chapter", "scene", "variant"], "Switches to the script e{chapter}_{scene}_{variant}."),
+    op("StopScript",           0x1A, 0, &[], "Stops this script."),
+    op("RunScript",            0x1B, 3, &["chapter", "scene", "variant"], "Runs the script e{chapter}_{scene}_{variant}."),
+    op("0x1C",                 0x1C, 0, &[], "Unknown."),
+    op("Sprite",               0x1E, 5, &["position", "character", "sprite", "state", "transition"], "Shows a character sprite."),
+    op("ScreenFlash",          0x1F, 7, &["?", "?", "?", "?", "?", "?", "?"], "Flashes the screen."),
+    op("SpriteFlash",          0x20, 5, &["?", "?", "?", "?", "?"], "Flashes a sprite."),
+    op("Speaker",              0x21, 1, &["character"], "Sets whose name is shown for the Text that follows."),
+    op("ScreenFade",           0x22, 3, &["fade in/out", "colour", "frames"], "Fades the screen."),
+    op("ChangeUi",             0x25, 2, &["element", "state"], "Shows or hides part of the UI."),
+    op("SetFlag",              0x26, 3, &["group", "flag", "value"], "Sets a game flag."),
+    op("CheckCharacter",       0x27, 1, &["character"], "Starts the section run when talking to this character."),
+    op("CheckObject",          0x29, 1, &["object"], "Starts the section run when examining this object."),
+    op("SetLabel",             0x2A, 2, &["label (high)", "label (low)"], "Marks a place GoToLabel can jump to."),
+    op("SetChoiceText",        0x2B, 1, &["option"], "Starts one option of a choice menu. 255 ends the menu."),
+    op("CameraShake",          0x2E, 2, &["?", "?"], "Shakes the camera."),
+    op("ShowBackground",       0x30, 3, &["background (high)", "background (low)", "state"], "Shows a background image."),
+    op("0x33",                 0x33, 4, &["?", "?", "?", "?"], "Unknown."),
+    op("GoToLabel",            0x34, 2, &["label (high)", "label (low)"], "Jumps to the SetLabel with the same label."),
+    op("CheckFlagA",           0x35, 255, &["group", "flag", "comparison", "value"], "Tests flags for the IfFlagCheck that follows. Comparisons are 0 ==, 1 !=, 2 <, 3 >, 4 <=, 5 >=, and tests are joined by 6 (and) or 7 (or)."),
+    op("CheckFlagB",           0x36, 255, &["group", "flag", "comparison", "value (high)", "value (low)"], "CheckFlagA with two byte values."),
+    op("WaitInput",            0x3A, 0, &[], "Waits for the player to press a button."),
+    op("WaitFrame",            0x3B, 0, &[], "Waits a frame."),
+    op("IfFlagCheck",          0x3C, 0, &[], "Runs the next opcode only if the CheckFlagA/B before it passed."),
+];
+
+
+pub fn info(name: &str) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.name == name)
+}
+
+
+pub fn info_for_code(code: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.code == code)
+}
+
+
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let _ = write!(f, "{}(", self.name);
 
-        if self.hexcode.len() == 2 {
-            let _ = write!(f, ")");
-            return Ok(());
+        // Every byte, the 0x70 too if there is one
+        if self.name == "Raw" {
+            let bytes: Vec<String> = self.hexcode.iter().map(u8::to_string).collect();
+            return write!(f, "{})", bytes.join(", "));
         }
 
+        // Text shows its id, the rest every argument byte
         if self.name == "Text" {
-            let _ = write!(f, "{})", self.text_id.unwrap());
-            return Ok(());
-        }
-
-        for idx in 2..(self.hexcode.len()-1) {
-            let _ = write!(f, "{}, ", self.hexcode.get(idx).unwrap());
+            let text_id = self.text_id.or_else(|| Some(u16::from_be_bytes(self.hexcode.get(2..4)?.try_into().ok()?)));
+            if let Some(text_id) = text_id {
+                return write!(f, "{})", text_id);
+            }
         }
 
-        let _ = write!(f, "{})", self.hexcode.last().unwrap());
-        Ok(())
+        let args: Vec<String> = self.hexcode.iter().skip(2).map(u8::to_string).collect();
+        write!(f, "{})", args.join(", "))
     }
 }
 
@@ -206,11 +306,11 @@ mod tests {
     
 
     #[test]
-    #[should_panic]
     fn test_try_from_string_broken_1() {
-        #[allow(unused_variables)]
         let opcode_1: (Option<Opcode>, Option<String>) 
             = Opcode::try_from_string("WaitFrame".to_string(), 0);
+
+        assert_that!(opcode_1, equal_to((None, None)));
     }
 
     #[test]
@@ -237,5 +337,18 @@ mod tests {
             assert_that!(opcode_1.0.unwrap().hexcode, equal_to(vec![0x70, 0xFE, 0x01, 0x03]));
     }
 
+    #[test]
+    fn test_try_from_string_comments() {
+        let opcode_1: (Option<Opcode>, Option<String>)
+            = Opcode::try_from_string("    Text(\"Who // what?\") // Makoto Naegi [sprite 2]".to_string(), 3);
+
+        assert_that!(opcode_1.1, equal_to(Some("Who // what?".to_string())));
+
+        let opcode_2: (Option<Opcode>, Option<String>)
+            = Opcode::try_from_string("// Just a note".to_string(), 0);
+
+        assert_that!(opcode_2.0, equal_to(None));
+    }
+
     
 }
\ No newline at end of file
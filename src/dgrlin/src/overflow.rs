@@ -0,0 +1,424 @@
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::read_to_string;
+
+use crate::markup::{tokenize, Token};
+use crate::project::Project;
+use crate::script::LinScript;
+
+
+/// Glyph widths for one font, in whatever unit the boxes are measured in.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct Font {
+    pub default_width: u32,
+    #[serde(default)]
+    pub widths: HashMap<char, u32>,
+}
+
+/// How much text fits in one style of text box.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct TextBox {
+    pub max_width: u32,
+    pub max_lines: usize,
+    pub font: String,
+}
+
+
+/// Fonts and boxes to measure against, usually loaded from JSON:
+///
+/// ```json
+/// {
+///     "fonts": { "main": { "default_width": 2, "widths": { "i": 1, "W": 3 } } },
+///     "boxes": {
+///         "default": { "max_width": 120, "max_lines": 3, "font": "main" },
+///         "4":       { "max_width": 110, "max_lines": 3, "font": "main" }
+///     }
+/// }
+/// ```
+///
+/// Box keys are TextBoxFormat ids, with `default` used for any format not listed.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct OverflowConfig {
+    pub fonts: HashMap<String, Font>,
+    pub boxes: HashMap<String, TextBox>,
+}
+
+impl Default for OverflowConfig {
+    /// Every glyph is 1 wide, and a box holds 3 lines of 64.
+    /// Close enough for the English script, but real glyph tables do better.
+    fn default() -> Self {
+        OverflowConfig {
+            fonts: HashMap::from([
+                ("default".to_string(), Font { default_width: 1, widths: HashMap::new() }),
+            ]),
+            boxes: HashMap::from([
+                ("default".to_string(), TextBox { max_width: 64, max_lines: 3, font: "default".to_string() }),
+            ]),
+        }
+    }
+}
+
+impl OverflowConfig {
+    pub fn load(filename: &str) -> eyre::Result<Self> {
+        let source = read_to_string(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+        let config: OverflowConfig = serde_json::from_str(&source).wrap_err(format!("Bad overflow config \"{}\"", filename))?;
+
+        for (format, text_box) in &config.boxes {
+            if !config.fonts.contains_key(&text_box.font) {
+                eyre::bail!("Box '{}' uses unknown font '{}'", format, text_box.font);
+            }
+        }
+        if !config.boxes.contains_key("default") {
+            eyre::bail!("Overflow config needs a 'default' box");
+        }
+
+        Ok(config)
+    }
+
+
+    pub fn text_box(&self, format: u8) -> &TextBox {
+        self.boxes
+            .get(&format.to_string())
+            .unwrap_or_else(|| &self.boxes["default"])
+    }
+
+
+    /// How wide a single line of text is.
+    /// Markup tags take no room, and a `%VARIABLE%` button prompt takes one glyph.
+    pub fn measure(&self, text_box: &TextBox, line: &str) -> u32 {
+        let font = &self.fonts[&text_box.font];
+
+        tokenize(line)
+            .iter()
+            .map(|token| match token {
+                Token::Text { text } => {
+                    text.chars().map(|c| *font.widths.get(&c).unwrap_or(&font.default_width)).sum()
+                }
+                Token::Variable { .. } => { font.default_width }
+                Token::Tag { .. } => { 0 }
+            })
+            .sum()
+    }
+}
+
+
+// Splits a line at its spaces, keeping tags and variables whole inside whichever word they touch.
+fn words(line: &str) -> Vec<String> {
+    let mut words: Vec<String> = vec![String::new()];
+
+    for token in tokenize(line) {
+        match token {
+            Token::Text { text } => {
+                let mut pieces = text.split(' ');
+                if let (Some(first), Some(word)) = (pieces.next(), words.last_mut()) {
+                    *word += first;
+                }
+                words.extend(pieces.map(str::to_string));
+            }
+            _ => {
+                if let Some(word) = words.last_mut() {
+                    *word += &token.to_string();
+                }
+            }
+        }
+    }
+
+    words
+}
+
+
+// Text entries keep newlines as the two characters `\n`.
+// The trailing one most lines end with doesn't start a new line on screen.
+fn box_lines(text: &str) -> Vec<&str> {
+    text.strip_suffix("\\n").unwrap_or(text).split("\\n").collect()
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Overflow {
+    pub script: String,
+    pub op_index: usize,
+    pub text_id: u16,
+    pub format: u8,
+    /// Width of the widest line.
+    pub width: u32,
+    pub max_width: u32,
+    pub lines: usize,
+    pub max_lines: usize,
+    pub text: String,
+}
+
+
+/// Every Text that doesn't fit the box set up by the TextBoxFormat before it.
+pub fn check(name: &str, script: &LinScript, config: &OverflowConfig) -> Vec<Overflow> {
+    let mut format = 0u8;
+    let mut overflows: Vec<Overflow> = Vec::new();
+
+    for (op_index, op) in script.ops.iter().enumerate() {
+        match (op.name.as_str(), op.args()) {
+            ("TextBoxFormat", [new_format]) => {
+                format = *new_format;
+            }
+            ("Text", _) => {
+                let text = match script.text_for(op) {
+                    Some(text) => { text }
+                    None => { continue; }
+                };
+
+                let text_box = config.text_box(format);
+                let lines = box_lines(text);
+                let width = lines.iter().map(|line| config.measure(text_box, line)).max().unwrap_or(0);
+
+                if width > text_box.max_width || lines.len() > text_box.max_lines {
+                    overflows.push(Overflow {
+                        script: name.to_string(),
+                        op_index,
+                        text_id: op.text_id.unwrap_or(0),
+                        format,
+                        width,
+                        max_width: text_box.max_width,
+                        lines: lines.len(),
+                        max_lines: text_box.max_lines,
+                        text: text.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    overflows
+}
+
+
+pub fn check_project(project: &Project, config: &OverflowConfig) -> Vec<Overflow> {
+    project.scripts
+        .iter()
+        .flat_map(|(name, script)| check(name, script, config))
+        .collect()
+}
+
+
+/// Re-breaks a line of text so every line fits `text_box`, packing words greedily.
+/// Existing breaks are treated as spaces. A word too wide for the box gets a line to itself.
+/// Only spaces in the text itself are broken at, never one inside a tag.
+pub fn wrap(text: &str, text_box: &TextBox, config: &OverflowConfig) -> String {
+    let trailing = text.ends_with("\\n");
+    let space = config.measure(text_box, " ");
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut current_width = 0u32;
+
+    for word in words(&box_lines(text).join(" ")).iter().filter(|word| !word.is_empty()) {
+        let word_width = config.measure(text_box, word);
+
+        if !current.is_empty() && current_width + space + word_width > text_box.max_width {
+            lines.push(std::mem::take(&mut current));
+            current_width = 0;
+        }
+
+        if !current.is_empty() {
+            current.push(' ');
+            current_width += space;
+        }
+        current += word;
+        current_width += word_width;
+    }
+    lines.push(current);
+
+    let mut wrapped = lines.join("\\n");
+    if trailing {
+        wrapped += "\\n";
+    }
+    wrapped
+}
+
+
+/// Wraps every overflowing line in place, where wrapping makes it fit.
+/// A line shown in more than one box is wrapped once, for the narrowest, and has to fit them all.
+/// Returns how many lines were changed, and the overflows that still need splitting or shortening
+/// by hand, which are left as they were.
+pub fn fix(name: &str, script: &mut LinScript, config: &OverflowConfig) -> (usize, Vec<Overflow>) {
+    let mut overflows: BTreeMap<u16, Vec<Overflow>> = BTreeMap::new();
+    for overflow in check(name, script, config) {
+        overflows.entry(overflow.text_id).or_default().push(overflow);
+    }
+    let shown_in = text_boxes(script, config);
+
+    let mut fixed = 0usize;
+    let mut unfixed: Vec<Overflow> = Vec::new();
+
+    for (text_id, found) in overflows {
+        let boxes = &shown_in[&text_id];
+        let Some(narrowest) = boxes.iter().min_by_key(|text_box| text_box.max_width) else {
+            continue;
+        };
+        let wrapped = wrap(&found[0].text, narrowest, config);
+
+        // Wrapping trades width for lines, so it can still be too long, or a word too wide
+        let lines = box_lines(&wrapped);
+        let fits = boxes.iter().all(|text_box| {
+            let width = lines.iter().map(|line| config.measure(text_box, line)).max().unwrap_or(0);
+            width <= text_box.max_width && lines.len() <= text_box.max_lines
+        });
+        if !fits {
+            unfixed.extend(found);
+            continue;
+        }
+
+        script.text_entries[text_id as usize] = wrapped;
+        fixed += 1;
+    }
+
+    (fixed, unfixed)
+}
+
+
+// Every box each line of text is shown in, by text_id.
+fn text_boxes<'a>(script: &LinScript, config: &'a OverflowConfig) -> HashMap<u16, Vec<&'a TextBox>> {
+    let mut format = 0u8;
+    let mut shown_in: HashMap<u16, Vec<&TextBox>> = HashMap::new();
+
+    for op in &script.ops {
+        match (op.name.as_str(), op.args()) {
+            ("TextBoxFormat", [new_format]) => { format = *new_format; }
+            ("Text", _) => {
+                if let Some(text_id) = op.text_id {
+                    shown_in.entry(text_id).or_default().push(config.text_box(format));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    shown_in
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use std::collections::HashMap;
+
+    use crate::opcode::Opcode;
+    use crate::script::LinScript;
+
+    use super::{check, fix, wrap, Font, OverflowConfig, TextBox};
+
+    fn config() -> OverflowConfig {
+        OverflowConfig {
+            fonts: HashMap::from([
+                ("main".to_string(), Font { default_width: 1, widths: HashMap::from([('W', 2)]) }),
+            ]),
+            boxes: HashMap::from([
+                ("default".to_string(), TextBox { max_width: 10, max_lines: 2, font: "main".to_string() }),
+                ("4".to_string(), TextBox { max_width: 20, max_lines: 2, font: "main".to_string() }),
+            ]),
+        }
+    }
+
+    fn op(line: &str) -> Opcode {
+        Opcode::try_from_string(line.to_string(), 0).0.unwrap()
+    }
+
+    fn text(text_id: u16) -> Opcode {
+        Opcode { name: "Text".to_string(), hexcode: vec![0x70, 0x02, 0x00, text_id as u8], text_id: Some(text_id) }
+    }
+
+    #[test]
+    fn test_check_uses_text_box_format() {
+        let script = LinScript {
+            ops: vec![
+                text(0),
+                op("TextBoxFormat(4)"),
+                text(0),
+                op("TextBoxFormat(0)"),
+                text(1),
+            ],
+            text_entries: vec![
+                "<CLT 4>Twelve chars<CLT>\\n".to_string(),
+                "WWWWW\\n".to_string(),
+            ],
+        };
+
+        let overflows = check("e01_001_000", &script, &config());
+
+        // Fits in format 4's wider box, and 'W' is double width but five of them still fit
+        assert_that!(overflows.len(), equal_to(1));
+        assert_that!(overflows[0].op_index, equal_to(0));
+        assert_that!(overflows[0].width, equal_to(12));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let config = config();
+        let text_box = &config.boxes["default"];
+
+        assert_that!(wrap("one two three\\nfour\\n", text_box, &config).as_str(), equal_to("one two\\nthree four\\n"));
+    }
+
+    #[test]
+    fn test_wrap_keeps_tags_whole() {
+        let mut config = config();
+        config.boxes.get_mut("default").unwrap().max_width = 12;
+        let text_box = &config.boxes["default"];
+
+        assert_that!(
+            wrap("aaaaaaa <CLT 4>bbbb<CLT> cc", text_box, &config).as_str(),
+            equal_to("aaaaaaa <CLT 4>bbbb<CLT>\\ncc")
+        );
+    }
+
+    #[test]
+    fn test_fix() {
+        let mut script = LinScript {
+            ops: vec![text(0)],
+            text_entries: vec!["a bb ccc dddd".to_string()],
+        };
+
+        assert_that!(fix("", &mut script, &config()), equal_to((1, Vec::new())));
+        assert_that!(script.text_entries[0].as_str(), equal_to("a bb ccc\\ndddd"));
+        assert_that!(check("", &script, &config()).len(), equal_to(0));
+    }
+
+    #[test]
+    fn test_fix_wraps_for_the_narrowest_box() {
+        // The same line in format 4's box and then the narrower default one
+        let mut script = LinScript {
+            ops: vec![op("TextBoxFormat(4)"), text(0), op("TextBoxFormat(0)"), text(0)],
+            text_entries: vec!["aaaaa bbbb ccccc dddd".to_string()],
+        };
+
+        // Wrapped for format 4 it would be "aaaaa bbbb ccccc", still too wide for the default box
+        assert_that!(fix("", &mut script, &config()), equal_to((1, Vec::new())));
+        assert_that!(script.text_entries[0].as_str(), equal_to("aaaaa bbbb\\nccccc dddd"));
+        assert_that!(check("", &script, &config()).len(), equal_to(0));
+    }
+
+    #[test]
+    fn test_fix_reports_what_wont_wrap() {
+        let mut script = LinScript {
+            ops: vec![text(0), text(1)],
+            text_entries: vec![
+                "one two three four five six".to_string(),
+                "Wonderwall".to_string(),
+            ],
+        };
+
+        // Three lines in a box of two, and one word wider than the box
+        let (fixed, unfixed) = fix("e01_001_000", &mut script, &config());
+
+        assert_that!(fixed, equal_to(0));
+        assert_that!(unfixed.iter().map(|overflow| overflow.text_id).collect::<Vec<_>>(), equal_to(vec![0, 1]));
+        assert_that!(unfixed[0].script.as_str(), equal_to("e01_001_000"));
+        assert_that!(script.text_entries[0].as_str(), equal_to("one two three four five six"));
+    }
+}
@@ -0,0 +1,368 @@
+use byteorder::{ByteOrder, LittleEndian};
+use eyre::Context;
+use log;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::compiler;
+use crate::decompiler::{self, write_script, DecompileOptions};
+use crate::error::{InFile, LinError};
+
+
+/// One of the game's .pak archives: a u32 count, that many u32 offsets, then the files back to back.
+/// The files have no names, so they're known by their index, and a file that is itself a .pak is
+/// opened up as one.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Pak {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Entry {
+    File(Vec<u8>),
+    Pak(Pak),
+}
+
+
+/// A line of `Pak::list`.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Listing {
+    /// Indexes from the outermost .pak in, like `3/0`.
+    pub path: String,
+    /// Where it starts in the .pak holding it.
+    pub offset: usize,
+    pub size: usize,
+    pub kind: &'static str,
+}
+
+
+impl Pak {
+    pub fn from_bytes(bytes: &[u8]) -> eyre::Result<Self> {
+        if bytes.len() < 4 {
+            return Err(LinError::Truncated { offset: bytes.len(), message: "End of file found prematurly (.pak header)".to_string() }.into());
+        }
+
+        let offsets = match offsets(bytes) {
+            Some(offsets) => { offsets }
+            None => { return Err(LinError::Format { offset: 0, message: "Not a valid .pak file (Offset Table Inconsistency)".to_string() }.into()); }
+        };
+
+        let entries = offsets
+            .iter()
+            .enumerate()
+            .map(|(idx, &start)| {
+                let end = offsets.get(idx + 1).copied().unwrap_or(bytes.len());
+                let data = &bytes[start..end];
+
+                if is_nested_pak(data) {
+                    Ok(Entry::Pak(Pak::from_bytes(data)?))
+                } else {
+                    Ok(Entry::File(data.to_vec()))
+                }
+            })
+            .collect::<eyre::Result<Vec<Entry>>>()?;
+
+        Ok(Pak { entries })
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data: Vec<Vec<u8>> = self.entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::File(bytes) => { bytes.clone() }
+                Entry::Pak(pak) => { pak.to_bytes() }
+            })
+            .collect();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend((data.len() as u32).to_le_bytes());
+
+        let mut offset = 4 + 4 * data.len();
+        for entry in &data {
+            bytes.extend((offset as u32).to_le_bytes());
+            offset += entry.len();
+        }
+        for entry in data {
+            bytes.extend(entry);
+        }
+
+        bytes
+    }
+
+
+    /// Every entry, nested ones included, in order.
+    pub fn list(&self) -> Vec<Listing> {
+        let mut listing: Vec<Listing> = Vec::new();
+        self.list_into("", &mut listing);
+        listing
+    }
+
+
+    fn list_into(&self, prefix: &str, listing: &mut Vec<Listing>) {
+        let mut offset = 4 + 4 * self.entries.len();
+
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let path = format!("{}{}", prefix, idx);
+
+            match entry {
+                Entry::File(bytes) => {
+                    let kind = if is_lin(bytes) { "lin" } else { "data" };
+                    listing.push(Listing { path, offset, size: bytes.len(), kind });
+                    offset += bytes.len();
+                }
+                Entry::Pak(pak) => {
+                    let size = pak.to_bytes().len();
+                    listing.push(Listing { path: path.clone(), offset, size, kind: "pak" });
+                    pak.list_into(&format!("{}/", path), listing);
+                    offset += size;
+                }
+            }
+        }
+    }
+}
+
+
+/// A .lin's header holds its own size, which tells it apart from a .pak with the same first bytes.
+pub fn is_lin(bytes: &[u8]) -> bool {
+    let header = |idx: usize| bytes.get(idx * 4..idx * 4 + 4).map(LittleEndian::read_u32);
+
+    match (header(0), header(1)) {
+        (Some(1), Some(0x0C)) => { header(2) == Some(bytes.len() as u32) }
+        (Some(2), Some(0x10)) => { header(3) == Some(bytes.len() as u32) }
+        _ => { false }
+    }
+}
+
+
+// Only a table that starts the data straight after itself counts,
+// so a nested .pak always packs back up to the same bytes.
+fn is_nested_pak(bytes: &[u8]) -> bool {
+    if is_lin(bytes) {
+        return false;
+    }
+    match offsets(bytes) {
+        Some(offsets) => { offsets.first() == Some(&(4 + 4 * offsets.len())) }
+        None => { false }
+    }
+}
+
+
+// The offset table, if it fits the file and runs in order.
+fn offsets(bytes: &[u8]) -> Option<Vec<usize>> {
+    let count = LittleEndian::read_u32(bytes.get(0..4)?) as usize;
+    let table_end = count.checked_mul(4)?.checked_add(4)?;
+    if table_end > bytes.len() {
+        return None;
+    }
+
+    let offsets: Vec<usize> = bytes[4..table_end]
+        .chunks(4)
+        .map(|offset| LittleEndian::read_u32(offset) as usize)
+        .collect();
+
+    let in_order = offsets.windows(2).all(|pair| pair[0] <= pair[1]);
+    let in_file = offsets.iter().all(|&offset| offset >= table_end && offset <= bytes.len());
+
+    (count > 0 && in_order && in_file).then_some(offsets)
+}
+
+
+// Zero padded, so the folder sorts back into the same order.
+fn entry_name(idx: usize, entry: &Entry, script_extension: &str) -> String {
+    match entry {
+        Entry::Pak(_) => { format!("{:04}", idx) }
+        Entry::File(bytes) if is_lin(bytes) => { format!("{:04}.{}", idx, script_extension) }
+        Entry::File(_) => { format!("{:04}.dat", idx) }
+    }
+}
+
+
+pub fn read_pak(filename: &str) -> eyre::Result<Pak> {
+    let bytes = fs::read(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+    Pak::from_bytes(&bytes).wrap_err(InFile(filename.to_string()))
+}
+
+
+/// Writes every entry into `folder` as it is, nested .pak's as folders. Returns the files written.
+pub fn extract(pak: &Pak, folder: &Path) -> eyre::Result<Vec<PathBuf>> {
+    write_entries(pak, folder, "", &mut |_, bytes| Some(bytes.to_vec()), "lin")
+}
+
+
+/// Like `extract`, but every .lin is decompiled into a .txt. A script that won't decode is logged,
+/// as `name:1/0` for the archive's entry, and kept as its .lin, which packs back up as it is.
+pub fn decompile(pak: &Pak, name: &str, folder: &Path, options: &DecompileOptions) -> eyre::Result<Vec<PathBuf>> {
+    let mut decompile = |entry: &str, bytes: &[u8]| -> Option<Vec<u8>> {
+        let decompiled = decompiler::decode(bytes).and_then(|script| {
+            let mut source: Vec<u8> = Vec::new();
+            write_script(&mut source, &script, options)?;
+            Ok(source)
+        });
+
+        match decompiled {
+            Ok(source) => { Some(source) }
+            Err(error) => {
+                log::warn!("{:#}, kept as a .lin", error.wrap_err(InFile(format!("{}:{}", name, entry))));
+                None
+            }
+        }
+    };
+    write_entries(pak, folder, "", &mut decompile, "txt")
+}
+
+
+// `convert` gets each script with its index path in the archive, and None keeps it as a .lin.
+fn write_entries<F>(pak: &Pak, folder: &Path, prefix: &str, convert: &mut F, script_extension: &str) -> eyre::Result<Vec<PathBuf>>
+where
+    F: FnMut(&str, &[u8]) -> Option<Vec<u8>>,
+{
+    fs::create_dir_all(folder).wrap_err("Output Directory not found")?;
+    let mut written: Vec<PathBuf> = Vec::new();
+
+    for (idx, entry) in pak.entries.iter().enumerate() {
+        let path = folder.join(entry_name(idx, entry, script_extension));
+        let entry_path = format!("{}{}", prefix, idx);
+
+        match entry {
+            Entry::Pak(nested) => {
+                written.extend(write_entries(nested, &path, &format!("{}/", entry_path), convert, script_extension)?);
+            }
+            Entry::File(bytes) if is_lin(bytes) => {
+                let (path, converted) = match convert(&entry_path, bytes) {
+                    Some(converted) => { (path, converted) }
+                    None => { (path.with_extension("lin"), bytes.clone()) }
+                };
+                fs::write(&path, converted).wrap_err("Could not write to file (Unknown issue)")?;
+                written.push(path);
+            }
+            Entry::File(bytes) => {
+                fs::write(&path, bytes).wrap_err("Could not write to file (Unknown issue)")?;
+                written.push(path);
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+
+/// Packs a folder back up, in file name order: folders become nested .pak's, .txt's are compiled,
+/// and anything else goes in as it is. Files starting with a '.' are left out.
+pub fn pack_folder(folder: &Path) -> eyre::Result<Pak> {
+    let mut entries: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+    for dir_entry in fs::read_dir(folder).wrap_err(format!("Folder \"{}\" could not be opened.", folder.display()))? {
+        let path = dir_entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let stem = if path.is_dir() { name } else { path.file_stem().unwrap_or_default().to_string_lossy().to_string() };
+        if let Some(other) = entries.insert(stem.clone(), path.clone()) {
+            eyre::bail!("entry {} is both \"{}\" and \"{}\"", stem, other.display(), path.display());
+        }
+    }
+
+    let entries = entries
+        .into_values()
+        .map(|path| {
+            if path.is_dir() {
+                return Ok(Entry::Pak(pack_folder(&path)?));
+            }
+            if path.extension().is_some_and(|extension| extension == "txt") {
+                let source = fs::read_to_string(&path).wrap_err(format!("File \"{}\" could not be opened.", path.display()))?;
+                let parsed = compiler::parse(&source).wrap_err(InFile(path.to_string_lossy().to_string()))?;
+                return Ok(Entry::File(compiler::encode(&parsed.script)));
+            }
+            Ok(Entry::File(fs::read(&path).wrap_err(format!("File \"{}\" could not be opened.", path.display()))?))
+        })
+        .collect::<eyre::Result<Vec<Entry>>>()?;
+
+    Ok(Pak { entries })
+}
+
+
+/// Decompiles every script in a .pak into a folder named after it, inside `output_folder`.
+pub fn decompile_pak(filename: &str, output_folder: &str, options: &DecompileOptions) -> eyre::Result<Vec<PathBuf>> {
+    log::info!("decompiling archive {}", filename);
+
+    let pak = read_pak(filename)?;
+    let name = Path::new(filename).file_name().unwrap_or_default().to_string_lossy();
+    let stem = Path::new(filename).file_stem().unwrap_or_default();
+    decompile(&pak, &name, &Path::new(output_folder).join(stem), options)
+}
+
+
+/// Compiles a folder (as `decompile_pak` leaves it) back into a .pak ready for the game.
+pub fn compile_pak(folder: &str, output_file: &str) -> eyre::Result<()> {
+    log::info!("packing {}", folder);
+
+    let pak = pack_folder(Path::new(folder))?;
+    fs::write(output_file, pak.to_bytes()).wrap_err("Could not write to file (Unknown issue)")
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    use crate::decompiler::DecompileOptions;
+
+    use super::{decompile, is_lin, pack_folder, Entry, Pak};
+
+    fn sample(name: &str) -> Vec<u8> {
+        fs::read(format!("{}/../data/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn test_pak_round_trip() {
+        let nested = Pak { entries: vec![Entry::File(sample("e01_102_000.lin")), Entry::File(vec![1, 2, 3])] };
+        let pak = Pak { entries: vec![Entry::File(sample("e01_100_000.lin")), Entry::Pak(nested)] };
+        let bytes = pak.to_bytes();
+
+        // Two entries, the first straight after the table
+        assert_that!(bytes[0..8].to_vec(), equal_to(vec![2, 0, 0, 0, 12, 0, 0, 0]));
+        assert_that!(Pak::from_bytes(&bytes).unwrap(), equal_to(pak.clone()));
+        assert_that!(is_lin(&sample("e01_100_000.lin")), is(true));
+
+        let listing: Vec<(String, &str)> = pak.list().into_iter().map(|entry| (entry.path, entry.kind)).collect();
+        assert_that!(listing, equal_to(vec![
+            ("0".to_string(), "lin"), ("1".to_string(), "pak"), ("1/0".to_string(), "lin"), ("1/1".to_string(), "data"),
+        ]));
+
+        // Decompiled and packed back up again
+        let folder = std::env::temp_dir().join(format!("dgrlin-pak-{}", std::process::id()));
+        let written = decompile(&pak, "x.pak", &folder, &DecompileOptions::default()).unwrap();
+        assert_that!(written[1].clone(), equal_to(folder.join("0001").join("0000.txt")));
+        fs::write(folder.join(".dgrlin-cache.json"), "{}").unwrap();
+
+        assert_that!(pack_folder(Path::new(&folder)).unwrap().to_bytes(), equal_to(bytes));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_pak_keeps_undecodable_scripts() {
+        // A type 1 .lin, with no text, is a script but not one the decompiler reads
+        let textless = vec![0x01, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x70, 0x3A, 0x00, 0x00];
+        let pak = Pak { entries: vec![Entry::File(sample("e01_100_000.lin")), Entry::File(textless)] };
+
+        let folder = std::env::temp_dir().join(format!("dgrlin-pak-textless-{}", std::process::id()));
+        let written = decompile(&pak, "x.pak", &folder, &DecompileOptions::default()).unwrap();
+        assert_that!(written, equal_to(vec![folder.join("0000.txt"), folder.join("0001.lin")]));
+
+        assert_that!(pack_folder(Path::new(&folder)).unwrap(), equal_to(pak));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
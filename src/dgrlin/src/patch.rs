@@ -0,0 +1,343 @@
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use crate::batch::content_hash;
+use crate::compiler;
+use crate::diff::{align, describe, key, Edit};
+use crate::opcode::{self, Opcode};
+use crate::script::LinScript;
+
+
+pub const PATCH_VERSION: u32 = 1;
+
+
+/// A mod's edits to one script, recorded against the script they were made to.
+/// Several mods' patches for the same script can then be applied together,
+/// instead of each shipping a whole .lin that overwrites the others.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Patch {
+    pub version: u32,
+    /// Which script it's for, like `e01_101_000`. Only used in messages.
+    pub script: String,
+    /// `script_hash` of the script it was made against.
+    pub base_hash: String,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Hunk {
+    /// Replaces the base script's opcodes `start..end` with `ops`, written the way a .txt has them.
+    /// `start == end` only inserts.
+    Ops { start: usize, end: usize, ops: Vec<String> },
+    /// Rewords the line shown by the Text at `op_index` in the base script.
+    Text { op_index: usize, old: String, new: String },
+}
+
+impl Hunk {
+    // The base opcodes it touches
+    fn span(&self) -> (usize, usize) {
+        match self {
+            Hunk::Ops { start, end, .. } => { (*start, *end) }
+            Hunk::Text { op_index, .. } => { (*op_index, op_index + 1) }
+        }
+    }
+
+
+    fn overlaps(&self, other: &Hunk) -> bool {
+        if self == other {
+            return false;
+        }
+
+        let ((a_start, a_end), (b_start, b_end)) = (self.span(), other.span());
+        match (a_start == a_end, b_start == b_end) {
+            // Two insertions only clash at the same spot, and an insertion only inside what the other replaces
+            (true, true) => { a_start == b_start }
+            (true, false) => { b_start < a_start && a_start < b_end }
+            (false, true) => { a_start < b_start && b_start < a_end }
+            (false, false) => { a_start < b_end && b_start < a_end }
+        }
+    }
+}
+
+impl std::fmt::Display for Hunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Hunk::Ops { start, end, ops } if start == end => { write!(f, "inserts {} opcodes before opcode {}", ops.len(), start) }
+            Hunk::Ops { start, end, ops } => { write!(f, "replaces opcodes {}..{} with {}", start, end, ops.len()) }
+            Hunk::Text { op_index, new, .. } => { write!(f, "rewords the Text at opcode {} to \"{}\"", op_index, new) }
+        }
+    }
+}
+
+
+/// Two patches touching the same opcodes. The later patch's hunk is the one applied.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Overlap {
+    pub dropped_patch: String,
+    pub dropped: Hunk,
+    pub applied_patch: String,
+    pub applied: Hunk,
+}
+
+impl std::fmt::Display for Overlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}) overrides {} ({})", self.applied_patch, self.applied, self.dropped_patch, self.dropped)
+    }
+}
+
+
+/// What a patch's `base_hash` is checked against.
+/// Hashed after encoding, so a script decoded and written back out still matches.
+pub fn script_hash(script: &LinScript) -> String {
+    content_hash(&compiler::encode(script))
+}
+
+
+/// The edits that turn `base` into `modded`. Opcodes are lined up as `diff` does, and a Text
+/// that stays put but is reworded becomes a Text hunk, so rewording and restaging the same scene
+/// in two mods don't collide.
+pub fn create(script: &str, base: &LinScript, modded: &LinScript) -> Patch {
+    let keys_base: Vec<String> = base.ops.iter().map(key).collect();
+    let keys_modded: Vec<String> = modded.ops.iter().map(key).collect();
+
+    let mut hunks: Vec<Hunk> = Vec::new();
+    // Removed and inserted since the last opcode both share
+    let mut start: Option<usize> = None;
+    let mut ops: Vec<String> = Vec::new();
+    let mut base_idx = 0usize;
+
+    let flush = |start: &mut Option<usize>, ops: &mut Vec<String>, end: usize, hunks: &mut Vec<Hunk>| {
+        if let Some(start) = start.take() {
+            hunks.push(Hunk::Ops { start, end, ops: std::mem::take(ops) });
+        }
+    };
+
+    for edit in align(&keys_base, &keys_modded) {
+        match edit {
+            Edit::Same(a, b) => {
+                flush(&mut start, &mut ops, base_idx, &mut hunks);
+
+                if let (Some(old), Some(new)) = (base.text_for(&base.ops[a]), modded.text_for(&modded.ops[b]))
+                    && old != new
+                {
+                    hunks.push(Hunk::Text { op_index: a, old: old.clone(), new: new.clone() });
+                }
+                base_idx = a + 1;
+            }
+            Edit::Removed(a) => {
+                start.get_or_insert(a);
+                base_idx = a + 1;
+            }
+            Edit::Inserted(b) => {
+                start.get_or_insert(base_idx);
+                ops.push(describe(modded, &modded.ops[b]));
+            }
+        }
+    }
+    flush(&mut start, &mut ops, base_idx, &mut hunks);
+
+    Patch { version: PATCH_VERSION, script: script.to_string(), base_hash: script_hash(base), hunks }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Applied {
+    pub script: LinScript,
+    pub overlaps: Vec<Overlap>,
+}
+
+
+/// Applies `patches` to `base` in order. They must all have been made against `base`.
+///
+/// Where two patches touch the same opcodes, the later one wins and the overlap is reported,
+/// the way a later mod in a load order overrides an earlier one.
+pub fn apply(base: &LinScript, patches: &[(String, Patch)]) -> eyre::Result<Applied> {
+    let hash = script_hash(base);
+    let mut accepted: Vec<(usize, &Hunk)> = Vec::new();
+    let mut overlaps: Vec<Overlap> = Vec::new();
+
+    for (patch_idx, (name, patch)) in patches.iter().enumerate() {
+        if patch.version > PATCH_VERSION {
+            eyre::bail!("{} is a version {} patch, this dgrlin only reads up to version {}", name, patch.version, PATCH_VERSION);
+        }
+        if patch.base_hash != hash {
+            eyre::bail!("{} was made against a different {} (hash {}, this one is {})", name, patch.script, patch.base_hash, hash);
+        }
+
+        for hunk in &patch.hunks {
+            let (start, end) = hunk.span();
+            if start > end || end > base.ops.len() {
+                eyre::bail!("{} {}, past the end of {}", name, hunk, patch.script);
+            }
+
+            accepted.retain(|(earlier_idx, earlier)| {
+                let clashes = *earlier_idx != patch_idx && earlier.overlaps(hunk);
+                if clashes {
+                    overlaps.push(Overlap {
+                        dropped_patch: patches[*earlier_idx].0.clone(),
+                        dropped: (*earlier).clone(),
+                        applied_patch: name.clone(),
+                        applied: hunk.clone(),
+                    });
+                }
+                !clashes
+            });
+            if !accepted.iter().any(|(_, earlier)| *earlier == hunk) {
+                accepted.push((patch_idx, hunk));
+            }
+        }
+    }
+
+    let mut rewords: BTreeMap<usize, &String> = BTreeMap::new();
+    let mut replacements: Vec<(usize, usize, &Vec<String>)> = Vec::new();
+    for (_, hunk) in accepted {
+        match hunk {
+            Hunk::Ops { start, end, ops } => { replacements.push((*start, *end, ops)); }
+            Hunk::Text { op_index, new, .. } => { rewords.insert(*op_index, new); }
+        }
+    }
+    // An insertion goes before whatever replaces the opcodes after it
+    replacements.sort_by_key(|(start, end, _)| (*start, *end));
+
+    let mut items: Vec<(Opcode, Option<String>)> = Vec::new();
+    let mut replacements = replacements.into_iter().peekable();
+    let mut idx = 0usize;
+
+    loop {
+        while let Some((_, end, ops)) = replacements.next_if(|(start, _, _)| *start == idx) {
+            for line in ops {
+                items.push(parse_op(line)?);
+            }
+            idx = idx.max(end);
+        }
+
+        let Some(op) = base.ops.get(idx) else { break };
+        let text = rewords.get(&idx).map(|text| text.to_string()).or_else(|| base.text_for(op).cloned());
+        items.push((op.clone(), text));
+        idx += 1;
+    }
+
+    Ok(Applied { script: build(items), overlaps })
+}
+
+
+fn parse_op(line: &str) -> eyre::Result<(Opcode, Option<String>)> {
+    match Opcode::try_from_string(line.to_string(), 0) {
+        (Some(op), text) if op.name == "Raw" || opcode::info(&op.name).is_some() => { Ok((op, text)) }
+        (Some(op), _) => { eyre::bail!("unknown opcode '{}' in patch", op.name) }
+        (None, _) => { eyre::bail!("could not read '{}' in patch", line) }
+    }
+}
+
+
+// Lines of text are numbered afresh in the order they're shown.
+fn build(items: Vec<(Opcode, Option<String>)>) -> LinScript {
+    let mut script = LinScript::default();
+
+    for (mut op, text) in items {
+        if let Some(text) = text {
+            let text_id = script.text_entries.len() as u16;
+            op.text_id = Some(text_id);
+            op.hexcode.splice(2.., text_id.to_be_bytes());
+            script.text_entries.push(text);
+        }
+        script.ops.push(op);
+    }
+
+    script
+}
+
+
+/// Writes a patch from a base .lin and a modded .lin or .txt.
+pub fn create_file(base: &str, modded: &str, output: &str) -> eyre::Result<Patch> {
+    let name = Path::new(base).file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let patch = create(&name, &LinScript::open(base)?, &LinScript::open(modded)?);
+
+    write(output, serde_json::to_string_pretty(&patch)?).wrap_err("Could not write to file (Unknown issue)")?;
+    Ok(patch)
+}
+
+
+/// Applies patch files to a base .lin in order and writes the result, returning any overlaps.
+pub fn apply_files(base: &str, patches: &[String], output: &str) -> eyre::Result<Vec<Overlap>> {
+    let patches = patches
+        .iter()
+        .map(|filename| {
+            let json = read_to_string(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+            let patch: Patch = serde_json::from_str(&json).wrap_err(format!("\"{}\" is not a patch", filename))?;
+            Ok((filename.clone(), patch))
+        })
+        .collect::<eyre::Result<Vec<(String, Patch)>>>()?;
+
+    let applied = apply(&LinScript::open(base)?, &patches)?;
+
+    write(output, compiler::encode(&applied.script)).wrap_err("Could not write to file (Unknown issue)")?;
+    Ok(applied.overlaps)
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::compiler::parse;
+    use crate::diff::describe;
+    use crate::script::LinScript;
+
+    use super::{apply, create, Hunk};
+
+    fn script(source: &str) -> LinScript {
+        parse(source).unwrap().script
+    }
+
+    fn lines(script: &LinScript) -> Vec<String> {
+        script.ops.iter().map(|op| describe(script, op)).collect()
+    }
+
+    const BASE: &str = "Speaker(0)\nText(\"One\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n";
+
+    #[test]
+    fn test_patches_apply_together() {
+        let base = script(BASE);
+        let reworded = script("Speaker(0)\nText(\"One!\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n");
+        let restaged = script("Speaker(0)\nText(\"One\")\nWaitInput()\nSpeaker(8)\nSprite(0, 8, 2, 0, 0)\nText(\"Two\")\nWaitInput()\nText(\"Three\")\n");
+
+        let first = create("e01_101_000", &base, &reworded);
+        let second = create("e01_101_000", &base, &restaged);
+        assert_that!(first.hunks.clone(), equal_to(vec![Hunk::Text { op_index: 1, old: "One".to_string(), new: "One!".to_string() }]));
+        assert_that!(second.hunks.len(), equal_to(2));
+
+        let applied = apply(&base, &[("reworded".to_string(), first), ("restaged".to_string(), second)]).unwrap();
+        assert_that!(applied.overlaps.len(), equal_to(0));
+        assert_that!(lines(&applied.script), equal_to(vec![
+            "Speaker(0)", "Text(\"One!\")", "WaitInput()", "Speaker(8)", "Sprite(0, 8, 2, 0, 0)", "Text(\"Two\")", "WaitInput()", "Text(\"Three\")",
+        ].into_iter().map(String::from).collect::<Vec<String>>()));
+    }
+
+    #[test]
+    fn test_overlapping_patches_later_wins() {
+        let base = script(BASE);
+        let first = create("e01_101_000", &base, &script("Speaker(0)\nText(\"Uno\")\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n"));
+        let second = create("e01_101_000", &base, &script("Speaker(1)\nWaitInput()\nSpeaker(8)\nText(\"Two\")\nWaitInput()\n"));
+
+        let applied = apply(&base, &[("first".to_string(), first), ("second".to_string(), second)]).unwrap();
+
+        assert_that!(applied.overlaps.len(), equal_to(1));
+        assert_that!(applied.overlaps[0].to_string().as_str(), equal_to(
+            "second (replaces opcodes 0..2 with 1) overrides first (rewords the Text at opcode 1 to \"Uno\")"
+        ));
+        assert_that!(lines(&applied.script)[0..2].to_vec(), equal_to(vec!["Speaker(1)".to_string(), "WaitInput()".to_string()]));
+
+        let stale = create("e01_101_000", &script("WaitInput()\n"), &base);
+        assert_that!(apply(&base, &[("stale".to_string(), stale)]).is_err(), is(true));
+    }
+}
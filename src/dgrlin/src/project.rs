@@ -0,0 +1,245 @@
+use eyre::Context;
+use log;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::fs::{read_dir, File};
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::decompiler::read_lin;
+use crate::script::LinScript;
+
+
+/// Every .lin in a folder, keyed by file name without the extension (`e01_102_001`).
+#[derive(Debug, Default)]
+pub struct Project {
+    pub scripts: BTreeMap<String, LinScript>,
+    /// Files that couldn't be decoded, with the reason why.
+    pub failed: Vec<(String, String)>,
+}
+
+impl Project {
+    pub fn load(folder: &str) -> eyre::Result<Self> {
+        log::info!("loading project {}", folder);
+
+        let mut project = Project::default();
+
+        let entries = read_dir(folder).wrap_err(format!("Folder \"{}\" could not be opened.", folder))?;
+        let mut paths: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "lin"))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let name = match path.file_stem() {
+                Some(stem) => { stem.to_string_lossy().to_string() }
+                None => { continue; }
+            };
+
+            match read_lin(&path.to_string_lossy()) {
+                Ok(script) => {
+                    project.scripts.insert(name, script);
+                }
+                Err(err) => {
+                    log::warn!("skipping {}: {}", name, err);
+                    project.failed.push((name, err.to_string()));
+                }
+            }
+        }
+
+        log::info!("loaded {} scripts", project.scripts.len());
+
+        Ok(project)
+    }
+
+
+    /// A folder, or a single .lin treated as a project of one.
+    pub fn load_path(path: &str) -> eyre::Result<Self> {
+        if Path::new(path).is_dir() {
+            return Project::load(path);
+        }
+
+        let name = match Path::new(path).file_stem() {
+            Some(stem) => { stem.to_string_lossy().to_string() }
+            None => { eyre::bail!("File \"{}\" could not be opened.", path) }
+        };
+
+        let mut project = Project::default();
+        project.scripts.insert(name, read_lin(path)?);
+
+        Ok(project)
+    }
+}
+
+
+/// The file name the game uses for a chapter/scene/variant triple.
+pub fn script_name(chapter: u8, scene: u8, variant: u8) -> String {
+    format!("e{:02}_{:03}_{:03}", chapter, scene, variant)
+}
+
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct ScriptCall {
+    pub from: String,
+    pub op_index: usize,
+    /// "LoadScript" or "RunScript"
+    pub kind: String,
+    pub target: String,
+    /// Whether the target is in the project.
+    pub found: bool,
+}
+
+
+/// Which scripts load or run which, across a whole project.
+#[derive(Serialize, PartialEq, Debug, Clone, Default)]
+pub struct CallGraph {
+    pub scripts: Vec<String>,
+    pub calls: Vec<ScriptCall>,
+    /// Scripts containing a StopScript, with the opcode index.
+    pub stops: Vec<(String, usize)>,
+}
+
+impl CallGraph {
+    pub fn build(project: &Project) -> Self {
+        let mut graph = CallGraph {
+            scripts: project.scripts.keys().cloned().collect(),
+            ..Default::default()
+        };
+
+        for (name, script) in &project.scripts {
+            for (op_index, op) in script.ops.iter().enumerate() {
+                match (op.name.as_str(), op.args()) {
+                    // 255, 255, 255 clears the script instead of naming one
+                    ("LoadScript" | "RunScript", [255, 255, 255]) => {}
+                    ("LoadScript" | "RunScript", [chapter, scene, variant]) => {
+                        let target = script_name(*chapter, *scene, *variant);
+
+                        graph.calls.push(ScriptCall {
+                            from: name.clone(),
+                            op_index,
+                            kind: op.name.clone(),
+                            found: project.scripts.contains_key(&target),
+                            target,
+                        });
+                    }
+                    ("StopScript", _) => {
+                        graph.stops.push((name.clone(), op_index));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        graph
+    }
+
+
+    /// Calls whose target script isn't in the project.
+    pub fn missing(&self) -> Vec<&ScriptCall> {
+        self.calls.iter().filter(|call| !call.found).collect()
+    }
+
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph scripts {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for script in &self.scripts {
+            out += &format!("    \"{}\";\n", script);
+        }
+        for call in self.missing() {
+            out += &format!("    \"{}\" [style=dashed, color=red];\n", call.target);
+        }
+
+        // One arrow per pair, no matter how many times it's called
+        let mut seen: Vec<(&str, &str, &str)> = Vec::new();
+        for call in &self.calls {
+            let key = (call.from.as_str(), call.target.as_str(), call.kind.as_str());
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key);
+
+            let style = if call.kind == "LoadScript" { ", style=dotted" } else { "" };
+            out += &format!("    \"{}\" -> \"{}\" [label=\"{}\"{}];\n", call.from, call.target, call.kind, style);
+        }
+
+        out += "}\n";
+        out
+    }
+}
+
+
+/// Loads every .lin in `folder` and writes `call_graph.dot` and `call_graph.json`
+/// into `output_folder`. Returns a message for each call that lands nowhere.
+pub fn export_call_graph(folder: String, output_folder: String) -> eyre::Result<Vec<String>> {
+    let project = Project::load(&folder)?;
+    let graph = CallGraph::build(&project);
+
+    let missing: Vec<String> = graph
+        .missing()
+        .iter()
+        .map(|call| format!("{} opcode {}: {} target {} not found", call.from, call.op_index, call.kind, call.target))
+        .collect();
+
+    for message in &missing {
+        log::warn!("{}", message);
+    }
+
+    let mut file = File::create(output_folder.clone() + "/call_graph.dot")
+        .wrap_err("Output Directory not found")?;
+    file.write_all(graph.to_dot().as_bytes()).wrap_err("Could not write to file (Unknown issue)")?;
+
+    let mut file = File::create(output_folder + "/call_graph.json")
+        .wrap_err("Output Directory not found")?;
+    file.write_all(serde_json::to_string_pretty(&graph)?.as_bytes()).wrap_err("Could not write to file (Unknown issue)")?;
+
+    log::info!("wrote call graph");
+
+    Ok(missing)
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use super::{script_name, CallGraph, Project};
+
+    fn data_project() -> Project {
+        Project::load(&format!("{}/../data", env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    #[test]
+    fn test_script_name() {
+        assert_that!(script_name(1, 102, 1).as_str(), equal_to("e01_102_001"));
+    }
+
+    #[test]
+    fn test_load_skips_broken_files() {
+        let project = data_project();
+
+        assert_that!(project.scripts.contains_key("e01_102_000"), is(true));
+        assert_that!(project.failed.len(), equal_to(1));
+        assert_that!(project.failed[0].0.as_str(), equal_to("BROKENe01_100_000"));
+    }
+
+    #[test]
+    fn test_call_graph_resolves_run_script() {
+        let graph = CallGraph::build(&data_project());
+
+        let call = graph.calls
+            .iter()
+            .find(|call| call.from == "e01_102_000" && call.target == "e01_102_001")
+            .unwrap();
+
+        assert_that!(call.kind.as_str(), equal_to("RunScript"));
+        assert_that!(call.found, is(true));
+    }
+}
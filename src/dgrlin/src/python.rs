@@ -0,0 +1,583 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyIndexError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyIterator, PyList, PyTuple, PyType};
+
+use crate::batch;
+use crate::compiler;
+use crate::decompiler::{self, read_lin, write_script, DecompileOptions};
+use crate::error::{InFile, LinError};
+use crate::opcode::{self, Opcode};
+use crate::script::LinScript;
+
+
+create_exception!(dgrlin, DgrlinError, PyRuntimeError, "Base of every error dgrlin raises itself.");
+create_exception!(dgrlin, LinFormatError, DgrlinError, "A .lin that isn't laid out like one.");
+create_exception!(dgrlin, TruncatedFileError, LinFormatError, "A .lin that ends partway through something.");
+create_exception!(dgrlin, UnknownOpcodeError, LinFormatError, "A .lin with an opcode byte dgrlin doesn't know.");
+create_exception!(dgrlin, ScriptSyntaxError, DgrlinError, "A .txt that won't compile.");
+
+
+/// What the Python functions return, so a `LinError` reaches Python as its own exception
+/// with `file`, `offset`, `line` and `column` attributes (None where they don't apply).
+/// Anything else is still a RuntimeError.
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Error(eyre::Report);
+
+impl<E: Into<eyre::Report>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Error(err.into())
+    }
+}
+
+impl From<Error> for PyErr {
+    fn from(Error(report): Error) -> Self {
+        let report = match report.downcast::<PyErr>() {
+            Ok(err) => { return err; }
+            Err(report) => { report }
+        };
+
+        let lin_error = match report.downcast_ref::<LinError>() {
+            Some(lin_error) => { lin_error }
+            None => { return PyRuntimeError::new_err(format!("{:?}", report)); }
+        };
+        let file = report.downcast_ref::<InFile>().map(|InFile(file)| file.clone());
+
+        let (err, offset, line, column) = match lin_error {
+            LinError::Format { offset, .. } => { (LinFormatError::new_err(lin_error.to_string()), Some(*offset), None, None) }
+            LinError::Truncated { offset, .. } => { (TruncatedFileError::new_err(lin_error.to_string()), Some(*offset), None, None) }
+            LinError::UnknownOpcode { offset, .. } => { (UnknownOpcodeError::new_err(lin_error.to_string()), Some(*offset), None, None) }
+            LinError::Syntax { line, column, .. } => { (ScriptSyntaxError::new_err(lin_error.to_string()), None, Some(*line), Some(*column)) }
+        };
+
+        Python::with_gil(|py| {
+            let value = err.value(py);
+            let attributes = value.setattr("file", file)
+                .and_then(|_| value.setattr("offset", offset))
+                .and_then(|_| value.setattr("line", line))
+                .and_then(|_| value.setattr("column", column))
+                .and_then(|_| match lin_error {
+                    LinError::UnknownOpcode { code, .. } => { value.setattr("opcode", code) }
+                    LinError::Syntax { diagnostics, .. } => {
+                        let diagnostics: Vec<(usize, &str)> = diagnostics
+                            .iter()
+                            .map(|diagnostic| (diagnostic.line, diagnostic.message.as_str()))
+                            .collect();
+                        value.setattr("diagnostics", diagnostics)
+                    }
+                    _ => { Ok(()) }
+                });
+
+            match attributes {
+                Ok(()) => { err }
+                Err(attribute_err) => { attribute_err }
+            }
+        })
+    }
+}
+
+
+// Python style indexing, negatives count from the end.
+fn index(idx: isize, len: usize) -> PyResult<usize> {
+    let resolved = if idx < 0 { idx + len as isize } else { idx };
+    if resolved < 0 || resolved as usize >= len {
+        return Err(PyIndexError::new_err("index out of range"));
+    }
+    Ok(resolved as usize)
+}
+
+
+/// One opcode. `args` are the bytes after the 0x70 and the opcode's own byte,
+/// a Text's args are its text_id, and a Raw's are all of its bytes.
+#[pyclass(name = "Instruction", module = "dgrlin", eq)]
+#[derive(PartialEq, Debug, Clone)]
+pub struct PyInstruction {
+    pub op: Opcode,
+}
+
+#[pymethods]
+impl PyInstruction {
+    #[new]
+    #[pyo3(signature = (name, args=Vec::new(), text_id=None))]
+    fn new(name: &str, args: Vec<u8>, text_id: Option<u16>) -> PyResult<Self> {
+        if name == "Text" {
+            let text_id = match (text_id, args.as_slice()) {
+                (Some(text_id), _) => { text_id }
+                (None, [high, low]) => { u16::from_be_bytes([*high, *low]) }
+                _ => { return Err(PyValueError::new_err("Text needs a text_id")); }
+            };
+
+            let mut hexcode = vec![0x70, 0x02];
+            hexcode.extend(text_id.to_be_bytes());
+            return Ok(PyInstruction { op: Opcode { name: "Text".to_string(), hexcode, text_id: Some(text_id) } });
+        }
+
+        // Bytes a lenient decode couldn't read, with no 0x70 or opcode byte of their own
+        if name == "Raw" {
+            return Ok(PyInstruction { op: Opcode { name: "Raw".to_string(), hexcode: args, text_id: None } });
+        }
+
+        let info = opcode::info(name).ok_or_else(|| PyValueError::new_err(format!("unknown opcode '{}'", name)))?;
+        if let Some(problem) = info.check_arg_count(args.len()) {
+            return Err(PyValueError::new_err(problem));
+        }
+
+        let mut hexcode = vec![0x70, info.code];
+        hexcode.extend(args);
+        Ok(PyInstruction { op: Opcode { name: name.to_string(), hexcode, text_id: None } })
+    }
+
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.op.name
+    }
+
+
+    #[getter]
+    fn code(&self) -> u8 {
+        self.op.hexcode.get(1).copied().unwrap_or(0)
+    }
+
+
+    #[getter]
+    fn args<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.op.args())
+    }
+
+
+    #[setter]
+    fn set_args(&mut self, args: Vec<u8>) -> PyResult<()> {
+        if self.op.name == "Text" {
+            let [high, low] = args[..] else {
+                return Err(PyValueError::new_err("Text takes 2 bytes of args"));
+            };
+            self.op.text_id = Some(u16::from_be_bytes([high, low]));
+        } else if let Some(problem) = opcode::info(&self.op.name).and_then(|info| info.check_arg_count(args.len())) {
+            return Err(PyValueError::new_err(problem));
+        }
+
+        self.op.hexcode.truncate(if self.op.name == "Raw" { 0 } else { 2 });
+        self.op.hexcode.extend(args);
+        Ok(())
+    }
+
+
+    #[getter]
+    fn text_id(&self) -> Option<u16> {
+        self.op.text_id
+    }
+
+
+    #[setter]
+    fn set_text_id(&mut self, text_id: u16) -> PyResult<()> {
+        self.set_args(text_id.to_be_bytes().to_vec())
+    }
+
+
+    /// The opcode as it sits in a .lin.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.op.hexcode)
+    }
+
+
+    fn __repr__(&self) -> String {
+        format!("<Instruction {}>", self.op)
+    }
+
+
+    fn __reduce__<'py>(slf: &Bound<'py, Self>) -> PyResult<(Bound<'py, PyType>, Bound<'py, PyTuple>)> {
+        let py = slf.py();
+        let this = slf.borrow();
+        let args = (this.name().to_string(), this.args(py), this.op.text_id).into_pyobject(py)?;
+        Ok((py.get_type::<Self>(), args))
+    }
+}
+
+
+/// A whole .lin. Indexing and iterating hand out copies of its instructions,
+/// so edits go back in by assigning to `script[idx]`.
+#[pyclass(name = "LinScript", module = "dgrlin", eq)]
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct PyLinScript {
+    pub script: LinScript,
+}
+
+#[pymethods]
+impl PyLinScript {
+    #[new]
+    #[pyo3(signature = (instructions=Vec::new(), strings=Vec::new()))]
+    fn new(instructions: Vec<PyInstruction>, strings: Vec<String>) -> Self {
+        PyLinScript {
+            script: LinScript {
+                ops: instructions.into_iter().map(|instruction| instruction.op).collect(),
+                text_entries: strings,
+            },
+        }
+    }
+
+
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(PyLinScript { script: LinScript::from_bytes(data)? })
+    }
+
+
+    /// Salvages what it can from a damaged .lin, with a line for each problem it got past.
+    #[staticmethod]
+    fn recover(data: &[u8]) -> (Self, Vec<String>) {
+        let recovered = decompiler::decode_lenient(data);
+        let problems = recovered.problems.iter().map(decompiler::problem_line).collect();
+        (PyLinScript { script: recovered.script }, problems)
+    }
+
+
+    /// A .txt is compiled, anything else is read as a .lin.
+    #[staticmethod]
+    fn open(filename: &str) -> Result<Self> {
+        let script = if filename.ends_with(".txt") { LinScript::open(filename)? } else { read_lin(filename)? };
+        Ok(PyLinScript { script })
+    }
+
+
+    #[staticmethod]
+    fn from_source(source: &str) -> Result<Self> {
+        Ok(PyLinScript { script: compiler::parse(source)?.script })
+    }
+
+
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &compiler::encode(&self.script))
+    }
+
+
+    /// The script as the decompiler would write it.
+    #[pyo3(signature = (annotate_speakers=false))]
+    fn to_source(&self, annotate_speakers: bool) -> Result<String> {
+        let mut written: Vec<u8> = Vec::new();
+        write_script(&mut written, &self.script, &DecompileOptions { annotate_speakers, ..Default::default() })?;
+        Ok(String::from_utf8(written)?)
+    }
+
+
+    #[getter]
+    fn instructions(&self) -> Vec<PyInstruction> {
+        self.script.ops.iter().map(|op| PyInstruction { op: op.clone() }).collect()
+    }
+
+
+    #[setter]
+    fn set_instructions(&mut self, instructions: Vec<PyInstruction>) {
+        self.script.ops = instructions.into_iter().map(|instruction| instruction.op).collect();
+    }
+
+
+    /// Edits through the table change this script.
+    #[getter]
+    fn strings(slf: Py<Self>) -> StringTable {
+        StringTable { script: slf }
+    }
+
+
+    #[setter]
+    fn set_strings(&mut self, strings: Vec<String>) {
+        self.script.text_entries = strings;
+    }
+
+
+    /// The line of text a Text instruction shows.
+    fn text_for(&self, instruction: &PyInstruction) -> Option<String> {
+        self.script.text_for(&instruction.op).cloned()
+    }
+
+
+    fn append(&mut self, instruction: PyInstruction) {
+        self.script.ops.push(instruction.op);
+    }
+
+
+    fn __len__(&self) -> usize {
+        self.script.ops.len()
+    }
+
+
+    fn __getitem__(&self, idx: isize) -> PyResult<PyInstruction> {
+        let idx = index(idx, self.script.ops.len())?;
+        Ok(PyInstruction { op: self.script.ops[idx].clone() })
+    }
+
+
+    fn __setitem__(&mut self, idx: isize, instruction: PyInstruction) -> PyResult<()> {
+        let idx = index(idx, self.script.ops.len())?;
+        self.script.ops[idx] = instruction.op;
+        Ok(())
+    }
+
+
+    fn __delitem__(&mut self, idx: isize) -> PyResult<()> {
+        let idx = index(idx, self.script.ops.len())?;
+        self.script.ops.remove(idx);
+        Ok(())
+    }
+
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        PyList::new(py, self.instructions())?.try_iter()
+    }
+
+
+    fn __repr__(&self) -> String {
+        format!("<LinScript {} instructions, {} strings>", self.script.ops.len(), self.script.text_entries.len())
+    }
+
+
+    fn __reduce__<'py>(slf: &Bound<'py, Self>) -> PyResult<(Bound<'py, PyType>, Bound<'py, PyTuple>)> {
+        let py = slf.py();
+        let this = slf.borrow();
+        let args = (this.instructions(), this.script.text_entries.clone()).into_pyobject(py)?;
+        Ok((py.get_type::<Self>(), args))
+    }
+}
+
+
+/// A LinScript's lines of text, indexed by text_id.
+/// It pickles as a plain list, since it's only a view of its script.
+#[pyclass(name = "StringTable", module = "dgrlin")]
+pub struct StringTable {
+    script: Py<PyLinScript>,
+}
+
+#[pymethods]
+impl StringTable {
+    fn append(&self, py: Python<'_>, text: String) {
+        self.script.borrow_mut(py).script.text_entries.push(text);
+    }
+
+
+    fn __len__(&self, py: Python<'_>) -> usize {
+        self.script.borrow(py).script.text_entries.len()
+    }
+
+
+    fn __getitem__(&self, py: Python<'_>, idx: isize) -> PyResult<String> {
+        let script = self.script.borrow(py);
+        let idx = index(idx, script.script.text_entries.len())?;
+        Ok(script.script.text_entries[idx].clone())
+    }
+
+
+    fn __setitem__(&self, py: Python<'_>, idx: isize, text: String) -> PyResult<()> {
+        let mut script = self.script.borrow_mut(py);
+        let idx = index(idx, script.script.text_entries.len())?;
+        script.script.text_entries[idx] = text;
+        Ok(())
+    }
+
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        PyList::new(py, &self.script.borrow(py).script.text_entries)?.try_iter()
+    }
+
+
+    /// Equal to another table or a list with the same lines.
+    fn __eq__(&self, py: Python<'_>, other: &Bound<'_, PyAny>) -> bool {
+        let entries = &self.script.borrow(py).script.text_entries;
+
+        if let Ok(other) = other.downcast::<StringTable>() {
+            return entries == &other.borrow().script.borrow(py).script.text_entries;
+        }
+        other.extract::<Vec<String>>().is_ok_and(|other| entries == &other)
+    }
+
+
+    fn __repr__(&self, py: Python<'_>) -> String {
+        format!("StringTable({:?})", self.script.borrow(py).script.text_entries)
+    }
+
+
+    fn __reduce__<'py>(&self, py: Python<'py>) -> PyResult<(Bound<'py, PyType>, Bound<'py, PyTuple>)> {
+        let entries = self.script.borrow(py).script.text_entries.clone();
+        Ok((py.get_type::<PyList>(), (entries,).into_pyobject(py)?))
+    }
+}
+
+
+/// Handed to a batch function to stop it between files from another thread, say a Cancel button.
+#[pyclass(name = "CancelToken", module = "dgrlin")]
+#[derive(Default, Debug, Clone)]
+pub struct PyCancelToken {
+    pub token: batch::CancelToken,
+}
+
+#[pymethods]
+impl PyCancelToken {
+    #[new]
+    fn new() -> Self {
+        PyCancelToken::default()
+    }
+
+
+    fn cancel(&self) {
+        self.token.cancel();
+    }
+
+
+    #[getter]
+    fn cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+
+    fn __repr__(&self) -> String {
+        format!("<CancelToken cancelled={}>", if self.cancelled() { "True" } else { "False" })
+    }
+}
+
+
+pub fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("DgrlinError", py.get_type::<DgrlinError>())?;
+    m.add("LinFormatError", py.get_type::<LinFormatError>())?;
+    m.add("TruncatedFileError", py.get_type::<TruncatedFileError>())?;
+    m.add("UnknownOpcodeError", py.get_type::<UnknownOpcodeError>())?;
+    m.add("ScriptSyntaxError", py.get_type::<ScriptSyntaxError>())?;
+
+    m.add_class::<PyInstruction>()?;
+    m.add_class::<PyLinScript>()?;
+    m.add_class::<StringTable>()?;
+    m.add_class::<PyCancelToken>()?;
+    Ok(())
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+
+    use super::register;
+
+    // Runs Python against a stand-in dgrlin module holding just the classes
+    fn run(code: &std::ffi::CStr) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "dgrlin").unwrap();
+            register(&module).unwrap();
+            py.import("sys").unwrap().getattr("modules").unwrap().set_item("dgrlin", &module).unwrap();
+
+            let globals = PyDict::new(py);
+            globals.set_item("dgrlin", &module).unwrap();
+            py.run(code, Some(&globals), None).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_script_objects() {
+        run(c"
+import pickle
+from dgrlin import Instruction, LinScript
+
+script = LinScript.from_source('Speaker(8)\\nText(\"Hello\")\\nWaitInput()\\n')
+assert repr(script) == '<LinScript 3 instructions, 1 strings>'
+assert [repr(op) for op in script] == ['<Instruction Speaker(8)>', '<Instruction Text(0)>', '<Instruction WaitInput()>']
+assert script.text_for(script[1]) == 'Hello'
+
+script.strings[0] = 'Bye'
+script[0] = Instruction('Speaker', b'\\x01')
+script.append(Instruction('Text', text_id=1))
+script.strings.append('Again')
+assert script.strings == ['Bye', 'Again']
+assert script[-1].args == b'\\x00\\x01'
+
+again = LinScript.from_bytes(script.to_bytes())
+assert again == script
+assert pickle.loads(pickle.dumps(script)) == script
+assert pickle.loads(pickle.dumps(script[0])) == script[0]
+assert pickle.loads(pickle.dumps(script.strings)) == ['Bye', 'Again']
+
+# A wrong count would swallow the next opcode, except in CheckFlagA/B which run on to it
+for make in [lambda: Instruction('GoToLabel', b'\\x01'), lambda: setattr(script[0], 'args', b'')]:
+    try:
+        make()
+    except ValueError as err:
+        assert 'takes' in str(err), err
+    else:
+        raise AssertionError('no error')
+assert Instruction('CheckFlagA', bytes([0, 7, 0, 1, 6, 0, 8, 0, 1])).args[-1] == 1
+
+# A stray byte comes back from recover() as a Raw, with no 0x70 or opcode byte of its own
+recovered, problems = LinScript.recover(bytes([2, 0, 0, 0, 16, 0, 0, 0, 24, 0, 0, 0, 32, 0, 0, 0, 0xAB, 0x70, 0x1B, 0xAB, 0x70, 0x1A, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0]))
+assert problems and recovered[0].name == 'Raw' and recovered[0].args == b'\\xab'
+assert pickle.loads(pickle.dumps(recovered)) == recovered
+raw = recovered[0]
+raw.args = b'\\x01\\x02'
+assert raw.to_bytes() == b'\\x01\\x02' and raw == Instruction('Raw', b'\\x01\\x02')
+");
+    }
+
+    #[test]
+    fn test_typed_exceptions() {
+        run(c"
+from dgrlin import LinScript, DgrlinError, LinFormatError, TruncatedFileError, ScriptSyntaxError
+
+try:
+    LinScript.from_source('Speaker(8)\\n    Sprite(0, 8\\n')
+except ScriptSyntaxError as err:
+    assert (err.file, err.offset, err.line, err.column) == (None, None, 2, 5)
+    assert err.diagnostics == [(2, \"could not read 'Sprite(0, 8'\")]
+else:
+    raise AssertionError('no error')
+
+try:
+    LinScript.from_bytes(bytes([2, 0, 0, 0, 16] + [0] * 11 + [0x70, 0x21]))
+except LinFormatError as err:
+    assert isinstance(err, TruncatedFileError) and isinstance(err, RuntimeError)
+    assert err.offset == 18
+else:
+    raise AssertionError('no error')
+");
+    }
+
+    // Everything dgrlin exports, and every public member of its classes, needs a stub
+    #[test]
+    fn test_stubs_cover_module() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = pyo3::wrap_pymodule!(crate::dgrlin)(py);
+            let stubs = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/dgrlin.pyi")).unwrap();
+
+            let globals = PyDict::new(py);
+            globals.set_item("module", module).unwrap();
+            globals.set_item("stubs", stubs).unwrap();
+            py.run(c"
+import ast
+
+stubbed = {}
+for node in ast.parse(stubs).body:
+    if isinstance(node, ast.ClassDef):
+        stubbed[node.name] = {item.name for item in node.body if isinstance(item, ast.FunctionDef)}
+        stubbed[node.name] |= {item.target.id for item in node.body if isinstance(item, ast.AnnAssign)}
+    elif isinstance(node, ast.FunctionDef):
+        stubbed[node.name] = set()
+
+missing = []
+for name in dir(module):
+    if name.startswith('__'):
+        continue
+    if name not in stubbed:
+        missing.append(name)
+        continue
+    value = getattr(module, name)
+    if isinstance(value, type) and not issubclass(value, BaseException):
+        missing += [name + '.' + member for member in vars(value) if not member.startswith('_') and member not in stubbed[name]]
+
+assert not missing, missing
+", Some(&globals), None).unwrap();
+        });
+    }
+}
@@ -0,0 +1,263 @@
+use eyre::Context;
+use serde::Serialize;
+
+use crate::error::InFile;
+use crate::markup::{self, StyledSpan, Token};
+use crate::opcode::Opcode;
+use crate::speaker::{self, SpeakerInfo};
+
+
+/// A .lin file held in memory.
+/// `text_entries` is the string table that Text opcodes index into,
+/// with newlines kept as the two characters `\n` like the .txt uses.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct LinScript {
+    pub ops: Vec<Opcode>,
+    pub text_entries: Vec<String>,
+}
+
+impl LinScript {
+    pub fn from_bytes(bytes: &[u8]) -> eyre::Result<Self> {
+        crate::decompiler::decode(bytes)
+    }
+
+
+    /// Reads a .txt script, or decodes anything else as a .lin.
+    pub fn open(filename: &str) -> eyre::Result<Self> {
+        if filename.ends_with(".txt") {
+            let source = std::fs::read_to_string(filename)
+                .map_err(|_| eyre::eyre!("File \"{}\" could not be opened.", filename))?;
+            let parsed = crate::compiler::parse(&source).wrap_err(InFile(filename.to_string()))?;
+            return Ok(parsed.script);
+        }
+
+        crate::decompiler::read_lin(filename)
+    }
+
+
+    /// The line of text a Text opcode points at.
+    pub fn text_for(&self, op: &Opcode) -> Option<&String> {
+        self.text_entries.get(op.text_id? as usize)
+    }
+
+
+    /// The markup tokens of the line a Text opcode points at.
+    pub fn markup_for(&self, op: &Opcode) -> Option<Vec<Token>> {
+        Some(markup::tokenize(self.text_for(op)?))
+    }
+
+
+    /// Who is talking for every Text opcode, in order.
+    pub fn speakers(&self) -> Vec<SpeakerInfo> {
+        speaker::attribute(self)
+    }
+
+
+    pub fn to_json(&self) -> eyre::Result<String> {
+        self.to_json_with(false)
+    }
+
+
+    /// With `styled_spans` every line also carries its text as coloured runs,
+    /// ready for a front end to draw without knowing about `<CLT>` tags.
+    pub fn to_json_with(&self, styled_spans: bool) -> eyre::Result<String> {
+        let export = JsonScript {
+            instructions: self.ops
+                .iter()
+                .map(|op| JsonInstruction {
+                    name: &op.name,
+                    args: op.args(),
+                    text_id: op.text_id,
+                })
+                .collect(),
+            text_entries: &self.text_entries,
+            lines: self.speakers()
+                .into_iter()
+                .map(|info| {
+                    let text = info.text_id.and_then(|id| self.text_entries.get(id as usize));
+                    JsonLine {
+                        spans: text.filter(|_| styled_spans).map(|text| markup::styled_spans(text)),
+                        text,
+                        info,
+                    }
+                })
+                .collect(),
+        };
+
+        Ok(serde_json::to_string_pretty(&export)?)
+    }
+}
+
+
+#[derive(Serialize)]
+struct JsonScript<'a> {
+    instructions: Vec<JsonInstruction<'a>>,
+    text_entries: &'a Vec<String>,
+    lines: Vec<JsonLine<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonInstruction<'a> {
+    name: &'a str,
+    args: &'a [u8],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text_id: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    #[serde(flatten)]
+    info: SpeakerInfo,
+    text: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spans: Option<Vec<StyledSpan>>,
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+    use proptest::prelude::*;
+
+    use crate::compiler::{encode, parse};
+    use crate::decompiler::{decode, decode_lenient, write_script, DecompileOptions};
+    use crate::opcode::{self, Opcode, OpcodeInfo, OPCODES};
+
+    use super::LinScript;
+
+    // Opcodes the .txt puts in or around a `{ }` block
+    const BLOCKS: [&str; 4] = ["IfFlagCheck", "SetChoiceText", "CheckCharacter", "CheckObject"];
+
+    #[derive(Debug, Clone)]
+    enum Item {
+        Op(Opcode),
+        Text(String),
+    }
+
+    fn script_of(items: Vec<Item>) -> LinScript {
+        let mut script = LinScript::default();
+
+        for item in items {
+            match item {
+                Item::Op(op) => { script.ops.push(op); }
+                Item::Text(text) => {
+                    let text_id = script.text_entries.len() as u16;
+                    script.ops.push(Opcode { name: "Text".to_string(), hexcode: [vec![0x70, 0x02], text_id.to_be_bytes().to_vec()].concat(), text_id: Some(text_id) });
+                    script.text_entries.push(text);
+                }
+            }
+        }
+
+        script
+    }
+
+    fn op_of(info: &'static OpcodeInfo) -> impl Strategy<Value = Opcode> {
+        // CheckFlagA/B run up to the next 0x70, so theirs can't hold one
+        let args = if info.arg_count == 255 {
+            prop::collection::vec(any::<u8>().prop_filter("ends CheckFlag", |arg| *arg != 0x70), 1..8).boxed()
+        } else {
+            prop::collection::vec(any::<u8>(), info.arg_count as usize).boxed()
+        };
+
+        args.prop_map(move |args| Opcode { name: info.name.to_string(), hexcode: [vec![0x70, info.code], args].concat(), text_id: None })
+    }
+
+    fn named(name: &str) -> impl Strategy<Value = Opcode> {
+        op_of(opcode::info(name).unwrap())
+    }
+
+    // Anything but Text and the block openers
+    fn plain() -> impl Strategy<Value = Opcode> {
+        let infos: Vec<&'static OpcodeInfo> = OPCODES.iter().filter(|info| info.name != "Text" && !BLOCKS.contains(&info.name)).collect();
+        prop::sample::select(infos).prop_flat_map(op_of)
+    }
+
+    // Whatever a .txt can hold between its quotes, `\n` included, but not a lone backslash
+    fn line() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            6 => prop::char::range(' ', '[').prop_map(String::from),
+            6 => prop::char::range(']', '~').prop_map(String::from),
+            1 => prop::char::range('À', 'ÿ').prop_map(String::from),
+            1 => prop::char::range('ぁ', 'ゖ').prop_map(String::from),
+            1 => Just("😀".to_string()),
+            1 => Just("\\n".to_string()),
+        ];
+        prop::collection::vec(piece, 0..24).prop_map(|pieces| pieces.concat())
+    }
+
+    // Opcodes and lines, any IfFlagCheck guarding one of them
+    fn items() -> impl Strategy<Value = Vec<Item>> {
+        let single = prop_oneof![plain().prop_map(Item::Op), line().prop_map(Item::Text)];
+        let run = prop_oneof![
+            4 => single.clone().prop_map(|item| vec![item]),
+            1 => (named("IfFlagCheck"), single).prop_map(|(check, item)| vec![Item::Op(check), item]),
+        ];
+        prop::collection::vec(run, 0..8).prop_map(|runs| runs.concat())
+    }
+
+    // Options only end at the next option or section, so they come last
+    fn choices() -> impl Strategy<Value = Vec<Item>> {
+        prop::collection::vec((named("SetChoiceText"), items()), 0..3)
+            .prop_map(|options| options.into_iter().flat_map(|(option, items)| [vec![Item::Op(option)], items].concat()).collect())
+    }
+
+    /// Scripts laid out the way the decompiler writes them and the compiler reads them:
+    /// opcodes and lines, then choices, then investigation sections of the same.
+    /// Never ending on a CheckFlagA/B, see `any_script`.
+    fn script() -> impl Strategy<Value = LinScript> {
+        let section = (prop_oneof![named("CheckCharacter"), named("CheckObject")], items(), choices())
+            .prop_map(|(check, items, choices)| [vec![Item::Op(check)], items, choices].concat());
+
+        (items(), choices(), prop::collection::vec(section, 0..3))
+            .prop_map(|(items, choices, sections)| script_of([items, choices, sections.concat()].concat()))
+            .prop_filter("ends on a CheckFlagA/B", |script| !script.ops.last().is_some_and(|op| op.name.starts_with("CheckFlag")))
+    }
+
+    /// Any opcodes in any order, as long as it doesn't end on a CheckFlagA/B, which would run into the padding.
+    fn any_script() -> impl Strategy<Value = LinScript> {
+        let item = prop_oneof![
+            4 => prop::sample::select(OPCODES.iter().filter(|info| info.name != "Text").collect::<Vec<_>>()).prop_flat_map(op_of).prop_map(Item::Op),
+            1 => line().prop_map(Item::Text),
+        ];
+        (prop::collection::vec(item, 0..32), named("WaitInput"))
+            .prop_map(|(items, last)| script_of([items, vec![Item::Op(last)]].concat()))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_decode_encode(script in any_script()) {
+            prop_assert_eq!(decode(&encode(&script)).unwrap(), script);
+        }
+
+        // compile(decompile(x)) == x
+        #[test]
+        fn prop_compile_decompile(script in script()) {
+            let bytes = encode(&script);
+
+            let mut source: Vec<u8> = Vec::new();
+            write_script(&mut source, &decode(&bytes).unwrap(), &DecompileOptions::default()).unwrap();
+            let source = String::from_utf8(source).unwrap();
+
+            prop_assert_eq!(encode(&parse(&source).unwrap().script), bytes);
+        }
+
+        // Damaged files are errors, never panics
+        #[test]
+        fn prop_decode_garbage(tail in prop::collection::vec(any::<u8>(), 0..256)) {
+            let bytes = [&encode(&LinScript::default())[..12], &tail[..]].concat();
+            let _ = decode(&bytes);
+            let _ = decode_lenient(&bytes);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_112_lines() {
+        // The string table starts with its count, and 112 is 0x70
+        let script = script_of((0..112).map(|idx| Item::Text(idx.to_string())).collect());
+        assert_that!(decode(&encode(&script)).unwrap(), equal_to(script.clone()));
+    }
+}
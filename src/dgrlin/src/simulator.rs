@@ -0,0 +1,480 @@
+use serde::Serialize;
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::assets::{asset_of, AssetRef};
+use crate::cfg::label_of;
+use crate::flags::{conditions, Condition, FlagId};
+use crate::project::script_name;
+use crate::script::LinScript;
+
+
+/// Something the player would see or the game would do.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Line {
+        op_index: usize,
+        text_id: u16,
+        text: Option<String>,
+        speaker: Option<u8>,
+    },
+    Asset {
+        op_index: usize,
+        asset: AssetRef,
+    },
+    SetFlag {
+        op_index: usize,
+        flag: FlagId,
+        value: u16,
+    },
+    Choice {
+        op_index: usize,
+        picked: u8,
+    },
+    /// LoadScript or RunScript, which the simulator does not follow.
+    Script {
+        op_index: usize,
+        opcode: String,
+        target: String,
+    },
+}
+
+/// Why a run stopped.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Halt {
+    /// Ran off the end of the script.
+    End,
+    StopScript { op_index: usize },
+    /// The character/object section that was running reached the next one.
+    SectionEnd { op_index: usize },
+    MissingLabel { op_index: usize, label: u16 },
+    /// A choice menu came up with nothing left in the choice stream.
+    OutOfChoices { op_index: usize },
+    /// The next choice in the stream isn't one of the menu's options.
+    InvalidChoice { op_index: usize, picked: u8 },
+    StepLimit,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Run {
+    pub events: Vec<Event>,
+    pub halt: Halt,
+}
+
+impl Run {
+    /// Just the text of every line shown, in order.
+    pub fn lines(&self) -> Vec<&str> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Line { text: Some(text), .. } => { Some(text.as_str()) }
+                _ => { None }
+            })
+            .collect()
+    }
+}
+
+
+/// Plays a script through without the game.
+///
+/// ```ignore
+/// let run = Simulator::new(&script)
+///     .flag(FlagId { group: 2, id: 15 }, 1)
+///     .choices(&[1])
+///     .run();
+/// ```
+pub struct Simulator<'a> {
+    script: &'a LinScript,
+    labels: HashMap<u16, usize>,
+    pub flags: HashMap<FlagId, u16>,
+    pub choices: VecDeque<u8>,
+    /// Who is being talked to, for CheckCharacter.
+    pub character: Option<u8>,
+    /// What is being examined, for CheckObject.
+    pub object: Option<u8>,
+    pub max_steps: usize,
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(script: &'a LinScript) -> Self {
+        let labels = script.ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.name == "SetLabel")
+            .filter_map(|(idx, op)| Some((label_of(op)?, idx)))
+            .collect();
+
+        Simulator {
+            script,
+            labels,
+            flags: HashMap::new(),
+            choices: VecDeque::new(),
+            character: None,
+            object: None,
+            max_steps: 100_000,
+        }
+    }
+
+
+    pub fn flag(mut self, flag: FlagId, value: u16) -> Self {
+        self.flags.insert(flag, value);
+        self
+    }
+
+
+    pub fn choices(mut self, choices: &[u8]) -> Self {
+        self.choices.extend(choices);
+        self
+    }
+
+
+    pub fn character(mut self, character: u8) -> Self {
+        self.character = Some(character);
+        self
+    }
+
+
+    pub fn object(mut self, object: u8) -> Self {
+        self.object = Some(object);
+        self
+    }
+
+
+    pub fn run(&mut self) -> Run {
+        let ops = &self.script.ops;
+        let mut events: Vec<Event> = Vec::new();
+
+        let mut pc = 0usize;
+        let mut steps = 0usize;
+        let mut speaker: Option<u8> = None;
+        let mut check_passed = false;
+        let mut in_section = false;
+        // Whether an option of the current choice menu has already run
+        let mut in_menu = false;
+        // Where the menu being searched for the picked option starts
+        let mut menu_start: Option<usize> = None;
+
+        let halt = loop {
+            if pc >= ops.len() {
+                match menu_start {
+                    Some(op_index) => { break Halt::InvalidChoice { op_index, picked: self.choices[0] }; }
+                    None => { break Halt::End; }
+                }
+            }
+            if steps >= self.max_steps {
+                break Halt::StepLimit;
+            }
+            steps += 1;
+
+            let op = &ops[pc];
+            let args = op.args();
+
+            if let Some(asset) = asset_of(op) {
+                events.push(Event::Asset { op_index: pc, asset });
+            }
+
+            match (op.name.as_str(), args) {
+                ("Speaker", [character]) => {
+                    speaker = Some(*character);
+                }
+                ("Text", _) => {
+                    let text_id = op.text_id.unwrap_or(0);
+                    events.push(Event::Line {
+                        op_index: pc,
+                        text_id,
+                        text: self.script.text_entries.get(text_id as usize).cloned(),
+                        speaker,
+                    });
+                }
+                ("SetFlag", [group, id, value]) => {
+                    let flag = FlagId { group: *group, id: *id };
+                    self.flags.insert(flag, *value as u16);
+                    events.push(Event::SetFlag { op_index: pc, flag, value: *value as u16 });
+                }
+                ("CheckFlagA" | "CheckFlagB", _) => {
+                    check_passed = self.evaluate(&conditions(op));
+                }
+                // Skip the guarded opcode. Each check is used up by the IfFlagCheck after it
+                ("IfFlagCheck", _) => {
+                    if !check_passed {
+                        pc += 1;
+                    }
+                    check_passed = false;
+                }
+                ("GoToLabel", _) => {
+                    let label = label_of(op).unwrap_or(0);
+                    match self.labels.get(&label) {
+                        // Options usually leave their menu this way, never reaching its SetChoiceText(255)
+                        Some(target) => {
+                            pc = *target;
+                            in_menu = false;
+                            check_passed = false;
+                            continue;
+                        }
+                        None => { break Halt::MissingLabel { op_index: pc, label }; }
+                    }
+                }
+                ("LoadScript" | "RunScript", [255, 255, 255]) => {}
+                ("LoadScript" | "RunScript", [chapter, scene, variant]) => {
+                    events.push(Event::Script {
+                        op_index: pc,
+                        opcode: op.name.clone(),
+                        target: script_name(*chapter, *scene, *variant),
+                    });
+                }
+                ("StopScript", _) => {
+                    break Halt::StopScript { op_index: pc };
+                }
+                ("CheckCharacter" | "CheckObject", [target]) => {
+                    if in_section {
+                        break Halt::SectionEnd { op_index: pc };
+                    }
+
+                    let wanted = if op.name == "CheckCharacter" { self.character } else { self.object };
+                    if wanted == Some(*target) {
+                        in_section = true;
+                    }
+                    else {
+                        pc = self.next_header(pc, &["CheckCharacter", "CheckObject"]);
+                        continue;
+                    }
+                }
+                // 255 closes the menu, and always runs
+                ("SetChoiceText", [255]) => {
+                    // Left over, it would be picked from the next menu instead
+                    if let Some(op_index) = menu_start {
+                        break Halt::InvalidChoice { op_index, picked: self.choices[0] };
+                    }
+                    in_menu = false;
+                }
+                ("SetChoiceText", [option]) => {
+                    if in_menu {
+                        pc = self.next_header(pc, &["SetChoiceText"]);
+                        continue;
+                    }
+
+                    // First option of a new menu, so this is where the player picks
+                    let picked = match self.choices.front() {
+                        Some(picked) => { *picked }
+                        None => { break Halt::OutOfChoices { op_index: pc }; }
+                    };
+
+                    if picked == *option {
+                        self.choices.pop_front();
+                        events.push(Event::Choice { op_index: pc, picked });
+                        in_menu = true;
+                        menu_start = None;
+                    }
+                    else {
+                        menu_start.get_or_insert(pc);
+                        pc = self.next_header(pc, &["SetChoiceText"]);
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            pc += 1;
+        };
+
+        Run { events, halt }
+    }
+
+
+    // Joins are applied left to right, 6 being "and" and 7 being "or".
+    fn evaluate(&self, (tests, joins): &(Vec<Condition>, Vec<u8>)) -> bool {
+        let mut results = tests.iter().map(|test| {
+            let value = *self.flags.get(&test.flag).unwrap_or(&0);
+            match test.comparison {
+                0 => { value == test.value }
+                1 => { value != test.value }
+                2 => { value <  test.value }
+                3 => { value >  test.value }
+                4 => { value <= test.value }
+                5 => { value >= test.value }
+                _ => { false }
+            }
+        });
+
+        let mut passed = results.next().unwrap_or(false);
+        for (join, result) in joins.iter().zip(results) {
+            passed = match join {
+                7 => { passed || result }
+                _ => { passed && result }
+            };
+        }
+
+        passed
+    }
+
+
+    // The index of the next section header after `pc`, or the end of the script.
+    fn next_header(&self, pc: usize, names: &[&str]) -> usize {
+        self.script.ops[pc + 1..]
+            .iter()
+            .position(|op| names.contains(&op.name.as_str()))
+            .map(|offset| pc + 1 + offset)
+            .unwrap_or(self.script.ops.len())
+    }
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::flags::FlagId;
+    use crate::opcode::Opcode;
+    use crate::script::LinScript;
+
+    use super::{Halt, Simulator};
+
+    fn script(lines: &[&str]) -> LinScript {
+        let mut text_entries: Vec<String> = Vec::new();
+        let ops = lines
+            .iter()
+            .map(|line| {
+                let (op, text) = Opcode::try_from_string(line.to_string(), text_entries.len() as u32);
+                let mut op = op.unwrap();
+                if let Some(text) = text {
+                    op.text_id = Some(text_entries.len() as u16);
+                    text_entries.push(text);
+                }
+                op
+            })
+            .collect();
+
+        LinScript { ops, text_entries }
+    }
+
+    #[test]
+    fn test_run_flag_check() {
+        let script = script(&[
+            "CheckFlagA(2, 15, 0, 1)",
+            "IfFlagCheck()",
+            "GoToLabel(0, 1)",
+            "Text(\"Flag is off\")",
+            "StopScript()",
+            "SetLabel(0, 1)",
+            "Text(\"Flag is on\")",
+        ]);
+
+        let run = Simulator::new(&script).run();
+        assert_that!(run.lines(), equal_to(vec!["Flag is off"]));
+        assert_that!(run.halt, equal_to(Halt::StopScript { op_index: 4 }));
+
+        let run = Simulator::new(&script).flag(FlagId { group: 2, id: 15 }, 1).run();
+        assert_that!(run.lines(), equal_to(vec!["Flag is on"]));
+        assert_that!(run.halt, equal_to(Halt::End));
+    }
+
+    #[test]
+    fn test_run_choices() {
+        let script = script(&[
+            "SetChoiceText(1)",
+            "Text(\"Picked one\")",
+            "SetChoiceText(2)",
+            "Text(\"Picked two\")",
+            "SetChoiceText(255)",
+            "Text(\"Either way\")",
+        ]);
+
+        let run = Simulator::new(&script).choices(&[2]).run();
+        assert_that!(run.lines(), equal_to(vec!["Picked two", "Either way"]));
+
+        let run = Simulator::new(&script).choices(&[1]).run();
+        assert_that!(run.lines(), equal_to(vec!["Picked one", "Either way"]));
+
+        let run = Simulator::new(&script).run();
+        assert_that!(run.halt, equal_to(Halt::OutOfChoices { op_index: 0 }));
+    }
+
+    #[test]
+    fn test_run_menus_left_by_labels() {
+        let script = script(&[
+            "SetChoiceText(1)",
+            "Text(\"First, one\")",
+            "GoToLabel(0, 1)",
+            "SetChoiceText(2)",
+            "Text(\"First, two\")",
+            "GoToLabel(0, 1)",
+            "SetChoiceText(255)",
+            "SetLabel(0, 1)",
+            "SetChoiceText(1)",
+            "Text(\"Second, one\")",
+            "SetChoiceText(2)",
+            "Text(\"Second, two\")",
+            "SetChoiceText(255)",
+        ]);
+
+        let run = Simulator::new(&script).choices(&[1, 1]).run();
+        assert_that!(run.lines(), equal_to(vec!["First, one", "Second, one"]));
+
+        let run = Simulator::new(&script).choices(&[2, 2]).run();
+        assert_that!(run.lines(), equal_to(vec!["First, two", "Second, two"]));
+        assert_that!(run.halt, equal_to(Halt::End));
+    }
+
+    #[test]
+    fn test_run_flag_check_used_once() {
+        let script = script(&[
+            "CheckFlagA(2, 15, 0, 1)",
+            "IfFlagCheck()",
+            "Text(\"Flag is on\")",
+            "IfFlagCheck()",
+            "Text(\"No check of its own\")",
+        ]);
+
+        let run = Simulator::new(&script).flag(FlagId { group: 2, id: 15 }, 1).run();
+        assert_that!(run.lines(), equal_to(vec!["Flag is on"]));
+    }
+
+    #[test]
+    fn test_run_invalid_choice() {
+        let script = script(&[
+            "SetChoiceText(1)",
+            "SetChoiceText(2)",
+            "SetChoiceText(255)",
+            "SetChoiceText(3)",
+            "Text(\"Picked three\")",
+            "SetChoiceText(255)",
+        ]);
+        // Not an option in the first menu, so it isn't carried over to the second
+        let run = Simulator::new(&script).choices(&[3]).run();
+        assert_that!(run.lines().len(), equal_to(0));
+        assert_that!(run.halt, equal_to(Halt::InvalidChoice { op_index: 0, picked: 3 }));
+
+        let run = Simulator::new(&script).choices(&[2, 3]).run();
+        assert_that!(run.lines(), equal_to(vec!["Picked three"]));
+    }
+
+    #[test]
+    fn test_run_character_sections() {
+        let script = script(&[
+            "CheckCharacter(3)",
+            "Text(\"Mondo\")",
+            "CheckCharacter(8)",
+            "Text(\"Kyoko\")",
+        ]);
+
+        let run = Simulator::new(&script).character(3).run();
+        assert_that!(run.lines(), equal_to(vec!["Mondo"]));
+        assert_that!(run.halt, equal_to(Halt::SectionEnd { op_index: 2 }));
+    }
+
+    #[test]
+    fn test_run_step_limit() {
+        let script = script(&["SetLabel(0, 1)", "GoToLabel(0, 1)"]);
+
+        let mut simulator = Simulator::new(&script);
+        simulator.max_steps = 50;
+
+        assert_that!(simulator.run().halt, equal_to(Halt::StepLimit));
+    }
+}
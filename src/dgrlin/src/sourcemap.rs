@@ -0,0 +1,200 @@
+use eyre::Context;
+use log;
+use serde::{Deserialize, Serialize};
+
+use std::fs::{read, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use crate::batch::content_hash;
+use crate::decompiler::{decode, write_script, DecompileOptions};
+use crate::error::InFile;
+use crate::script::LinScript;
+
+
+/// Where in the .txt an opcode is written. Both are 1-based, the column counting characters.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// The position of the first non-blank character on `line`.
+    pub fn of_line(line_number: usize, line: &str) -> Self {
+        let column = line.chars().take_while(|c| c.is_whitespace()).count() + 1;
+        Position { line: line_number, column }
+    }
+}
+
+
+/// One opcode's bytes in the .lin, `start..end`, and where it is in the .txt.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Mapping {
+    pub op_index: usize,
+    pub opcode: String,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+
+/// Links each opcode's byte range in a .lin to its line in the .txt it was compiled from or decompiled to.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct SourceMap {
+    /// File names only, as the map sits beside them.
+    pub lin: String,
+    pub source: String,
+    /// `content_hash` of the .lin the map was written for, so a map left behind by an older build is noticed.
+    #[serde(default)]
+    pub content_hash: String,
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// `positions` holds one entry per opcode in `script`.
+    pub fn new(lin: &str, source: &str, script: &LinScript, positions: &[Position]) -> Self {
+        // After the 16 byte header, opcodes are back to back
+        let mut offset = 16usize;
+        let mappings = script.ops
+            .iter()
+            .zip(positions)
+            .enumerate()
+            .map(|(op_index, (op, position))| {
+                let start = offset;
+                offset += op.hexcode.len();
+                Mapping { op_index, opcode: op.name.clone(), start, end: offset, line: position.line, column: position.column }
+            })
+            .collect();
+
+        SourceMap { lin: lin.to_string(), source: source.to_string(), content_hash: String::new(), mappings }
+    }
+
+
+    /// The opcode whose bytes hold `offset`, or None for the header, padding and text table.
+    pub fn locate(&self, offset: usize) -> Option<&Mapping> {
+        let idx = self.mappings.partition_point(|mapping| mapping.end <= offset);
+        self.mappings.get(idx).filter(|mapping| mapping.start <= offset)
+    }
+
+
+    /// The opcodes written on `line` of the .txt.
+    pub fn at_line(&self, line: usize) -> Vec<&Mapping> {
+        self.mappings.iter().filter(|mapping| mapping.line == line).collect()
+    }
+
+
+    pub fn load(filename: &str) -> eyre::Result<Self> {
+        let json = read_to_string(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+        serde_json::from_str(&json).wrap_err(format!("\"{}\" is not a source map", filename))
+    }
+
+
+    pub fn save(&self, filename: &Path) -> eyre::Result<()> {
+        write(filename, serde_json::to_string_pretty(self)?).wrap_err("Could not write to file (Unknown issue)")
+    }
+}
+
+
+/// `e01_101_000.lin` -> `e01_101_000.map.json`, in the same folder.
+pub fn map_path(filename: &Path) -> PathBuf {
+    let stem = filename.file_stem().unwrap_or_default().to_string_lossy();
+    filename.with_file_name(format!("{}.map.json", stem))
+}
+
+
+fn file_name(filename: &Path) -> String {
+    filename.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+
+/// The source map for a .lin. The one written beside it is used if it was written for this .lin,
+/// otherwise the .lin is decompiled and lines are those of the .txt the decompiler would write.
+pub fn for_lin(filename: &str) -> eyre::Result<SourceMap> {
+    let bytes = read(filename).wrap_err(format!("File \"{}\" could not be opened.", filename))?;
+    let hash = content_hash(&bytes);
+
+    let map = map_path(Path::new(filename));
+    if map.exists() {
+        let loaded = SourceMap::load(&map.to_string_lossy())?;
+        if loaded.content_hash == hash {
+            return Ok(loaded);
+        }
+        log::warn!("{} is out of date with {}, ignoring it", map.display(), filename);
+    }
+
+    let script = decode(&bytes).wrap_err(InFile(filename.to_string()))?;
+    let positions = write_script(&mut std::io::sink(), &script, &DecompileOptions::default())?;
+    let source = Path::new(filename).with_extension("txt");
+
+    let mut decompiled = SourceMap::new(&file_name(Path::new(filename)), &file_name(&source), &script, &positions);
+    decompiled.content_hash = hash;
+    Ok(decompiled)
+}
+
+
+/// Writes the map for `lin` and `source` beside `output`, named after it.
+/// `lin` has to be on disk already, to hash.
+pub(crate) fn write_map(output: &Path, lin: &Path, source: &Path, script: &LinScript, positions: &[Position]) -> eyre::Result<()> {
+    let bytes = read(lin).wrap_err(format!("File \"{}\" could not be opened.", lin.display()))?;
+
+    let mut map = SourceMap::new(&file_name(lin), &file_name(source), script, positions);
+    map.content_hash = content_hash(&bytes);
+    map.save(&map_path(output))
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::compiler::{encode, parse};
+    use crate::decompiler::{decode, write_script, DecompileOptions};
+
+    use super::{for_lin, map_path, SourceMap};
+
+    #[test]
+    fn test_maps_both_ways() {
+        let source = "Speaker(0)\nIfFlagCheck()\n{\n    Text(\"Hi\")\n}\nWaitInput()\n";
+        let parsed = parse(source).unwrap();
+        let bytes = encode(&parsed.script);
+
+        let compiled = SourceMap::new("a.lin", "a.txt", &parsed.script, &parsed.positions());
+        let text = compiled.locate(0x16).unwrap();
+        assert_that!(text.opcode.as_str(), equal_to("Text"));
+        assert_that!((text.line, text.column), equal_to((4, 5)));
+        assert_that!(compiled.locate(4).is_none(), is(true));
+        assert_that!(compiled.locate(bytes.len() - 1).is_none(), is(true));
+
+        // The decompiler writes the same layout, so its map agrees
+        let script = decode(&bytes).unwrap();
+        let positions = write_script(&mut std::io::sink(), &script, &DecompileOptions::default()).unwrap();
+        let decompiled = SourceMap::new("a.lin", "a.txt", &script, &positions);
+        assert_that!(decompiled.mappings.clone(), equal_to(compiled.mappings.clone()));
+        assert_that!(decompiled.at_line(6)[0].start, equal_to(decompiled.mappings[1].end + 4));
+    }
+
+    #[test]
+    fn test_ignores_stale_map() {
+        let root = std::env::temp_dir().join(format!("dgrlin-sourcemap-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let lin = root.join("e01_001_000.lin");
+
+        let parsed = parse("Speaker(0)\n\n\nText(\"Hi\")\n").unwrap();
+        std::fs::write(&lin, encode(&parsed.script)).unwrap();
+        super::write_map(&lin, &lin, &root.join("e01_001_000.txt"), &parsed.script, &parsed.positions()).unwrap();
+        assert_that!(for_lin(&lin.to_string_lossy()).unwrap().mappings[1].line, equal_to(4));
+
+        // Rebuilt without a map, so the old one no longer lines up
+        std::fs::write(&lin, encode(&parse("Text(\"Hi\")\n").unwrap().script)).unwrap();
+        assert_that!(map_path(&lin).exists(), is(true));
+        let map = for_lin(&lin.to_string_lossy()).unwrap();
+        assert_that!((map.mappings.len(), map.mappings[0].line), equal_to((1, 1)));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use serde::Serialize;
+
+use crate::script::LinScript;
+
+
+/// What the game shows alongside one Text opcode.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct SpeakerInfo {
+    /// Position of the Text opcode in `LinScript::ops`.
+    pub op_index: usize,
+    pub text_id: Option<u16>,
+    /// Character id from the last Speaker opcode.
+    pub speaker: Option<u8>,
+    pub speaker_name: Option<&'static str>,
+    /// Sprite last shown for the speaking character.
+    pub sprite: Option<u8>,
+    /// Voice clip queued since the previous line of text.
+    pub voice: Option<VoiceClip>,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct VoiceClip {
+    pub character: u8,
+    pub chapter: u8,
+    pub id: u16,
+    pub volume: u8,
+}
+
+
+/// Walks the opcodes top to bottom, keeping track of the current Speaker,
+/// each character's Sprite and any pending Voice.
+/// Jumps are not followed, so lines after a label get whatever state came
+/// right before them in the file.
+pub fn attribute(script: &LinScript) -> Vec<SpeakerInfo> {
+    let mut speaker: Option<u8> = None;
+    let mut sprites: [Option<u8>; 256] = [None; 256];
+    let mut voice: Option<VoiceClip> = None;
+
+    let mut lines: Vec<SpeakerInfo> = Vec::new();
+
+    for (op_index, op) in script.ops.iter().enumerate() {
+        let args = op.args();
+
+        match (op.name.as_str(), args) {
+            ("Speaker", [character]) => {
+                speaker = Some(*character);
+            }
+            // Sprite(object, character, sprite, state, type)
+            ("Sprite", [_, character, sprite, _, _]) => {
+                sprites[*character as usize] = Some(*sprite);
+            }
+            // Voice(character, chapter, id, id, volume)
+            ("Voice", [character, chapter, id_high, id_low, volume]) => {
+                voice = Some(VoiceClip {
+                    character: *character,
+                    chapter: *chapter,
+                    id: u16::from_be_bytes([*id_high, *id_low]),
+                    volume: *volume,
+                });
+            }
+            ("Text", _) => {
+                lines.push(SpeakerInfo {
+                    op_index,
+                    text_id: op.text_id,
+                    speaker,
+                    speaker_name: speaker.and_then(crate::characters::name),
+                    sprite: speaker.and_then(|character| sprites[character as usize]),
+                    voice: voice.take(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    lines
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use crate::opcode::Opcode;
+    use crate::script::LinScript;
+
+    use super::{attribute, VoiceClip};
+
+    fn op(name: &str, args: &[u8]) -> Opcode {
+        let mut hexcode = vec![0x70, 0x00];
+        hexcode.extend_from_slice(args);
+
+        Opcode {
+            name: name.to_string(),
+            text_id: if name == "Text" { Some(args[1] as u16) } else { None },
+            hexcode,
+        }
+    }
+
+    #[test]
+    fn test_attribute_follows_speaker_sprite_and_voice() {
+        let script = LinScript {
+            ops: vec![
+                op("Sprite", &[8, 8, 3, 0, 0]),
+                op("Speaker", &[8]),
+                op("Voice", &[8, 1, 0, 2, 100]),
+                op("Text", &[0, 0]),
+                op("WaitInput", &[]),
+                op("Text", &[0, 1]),
+            ],
+            text_entries: vec!["One".to_string(), "Two".to_string()],
+        };
+
+        let lines = attribute(&script);
+
+        assert_that!(lines.len(), equal_to(2));
+        assert_that!(lines[0].speaker_name, equal_to(Some("Kyoko Kirigiri")));
+        assert_that!(lines[0].sprite, equal_to(Some(3)));
+        assert_that!(lines[0].voice.clone(), equal_to(Some(VoiceClip {
+            character: 8, chapter: 1, id: 2, volume: 100
+        })));
+
+        // Voices only belong to the line right after them
+        assert_that!(lines[1].op_index, equal_to(5));
+        assert_that!(lines[1].speaker, equal_to(Some(8)));
+        assert_that!(lines[1].voice.clone(), equal_to(None));
+    }
+
+    #[test]
+    fn test_attribute_without_speaker() {
+        let script = LinScript {
+            ops: vec![op("Text", &[0, 0])],
+            text_entries: vec!["Hm?".to_string()],
+        };
+
+        let lines = attribute(&script);
+
+        assert_that!(lines[0].speaker, equal_to(None));
+        assert_that!(lines[0].sprite, equal_to(None));
+    }
+}
@@ -0,0 +1,212 @@
+use eyre::Context;
+use log;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::compiler;
+use crate::error::InFile;
+
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Also copy every rebuilt .lin into this folder, like the game's script folder.
+    pub mirror: Option<String>,
+    /// How long a script has to go without changing before it's rebuilt.
+    pub debounce: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions { mirror: None, debounce: Duration::from_millis(200) }
+    }
+}
+
+
+/// Collects changed paths and hands each one back once it has been quiet for `delay`,
+/// so an editor writing a file in several goes only triggers one rebuild.
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    delay: Duration,
+    pending: BTreeMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Self {
+        Debouncer { delay, pending: BTreeMap::new() }
+    }
+
+
+    pub fn add(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+
+    /// Takes out every path that hasn't changed for `delay`.
+    pub fn ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let ready: Vec<PathBuf> = self.pending
+            .iter()
+            .filter(|(_, changed)| now.duration_since(**changed) >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in &ready {
+            self.pending.remove(path);
+        }
+        ready
+    }
+
+
+    /// How long until the next path is ready, if any are waiting.
+    pub fn next_wait(&self, now: Instant) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|changed| self.delay.saturating_sub(now.duration_since(*changed)))
+            .min()
+    }
+}
+
+
+/// Compiles one script from the watched folder into the same place in `output_folder`,
+/// then copies it into the mirror. Returns where the .lin went.
+pub fn rebuild(source_folder: &Path, output_folder: &Path, path: &Path, mirror: Option<&Path>) -> eyre::Result<PathBuf> {
+    let relative = path.strip_prefix(source_folder).unwrap_or(path);
+    let output = output_folder.join(relative).with_extension("lin");
+    let output_dir = output.parent().unwrap_or(output_folder);
+
+    let source = fs::read_to_string(path).wrap_err(format!("File \"{}\" could not be opened.", path.display()))?;
+    let parsed = compiler::parse(&source).wrap_err(InFile(path.display().to_string()))?;
+    for warning in compiler::markup_warnings(&parsed) {
+        log::warn!("{}", warning);
+    }
+
+    // Written straight to `output`, so the name is kept whole however the path is spelled
+    fs::create_dir_all(output_dir).wrap_err("Output Directory not found")?;
+    fs::write(&output, compiler::encode(&parsed.script)).wrap_err(format!("Could not write to \"{}\"", output.display()))?;
+
+    if let Some(mirror) = mirror {
+        let copy = mirror.join(relative).with_extension("lin");
+        if let Some(copy_dir) = copy.parent() {
+            fs::create_dir_all(copy_dir).wrap_err("Mirror Directory not found")?;
+        }
+        fs::copy(&output, &copy).wrap_err(format!("Could not copy to \"{}\"", copy.display()))?;
+    }
+
+    Ok(output)
+}
+
+
+/// Rebuilds every .txt under `source_folder` as it's saved, until the process is stopped.
+/// Problems are printed and watching carries on.
+pub fn watch(source_folder: &str, output_folder: &str, options: &WatchOptions) -> eyre::Result<()> {
+    let source_folder = fs::canonicalize(source_folder).wrap_err(format!("Folder \"{}\" could not be opened.", source_folder))?;
+    let output_folder = Path::new(output_folder);
+    let mirror = options.mirror.as_deref().map(Path::new);
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&source_folder, RecursiveMode::Recursive)?;
+
+    println!("watching {}", source_folder.display());
+
+    let mut debouncer = Debouncer::new(options.debounce);
+
+    loop {
+        let event = match debouncer.next_wait(Instant::now()) {
+            Some(wait) => { receiver.recv_timeout(wait).ok() }
+            None => { Some(receiver.recv()?) }
+        };
+
+        match event {
+            Some(Ok(event)) => {
+                // Editors that save by renaming show up as creates and renames
+                if matches!(event.kind, EventKind::Access(_) | EventKind::Remove(_)) {
+                    continue;
+                }
+                for path in event.paths {
+                    if path.extension().is_some_and(|extension| extension == "txt") {
+                        debouncer.add(path, Instant::now());
+                    }
+                }
+            }
+            Some(Err(err)) => { log::warn!("watch error: {}", err); }
+            None => {}
+        }
+
+        for path in debouncer.ready(Instant::now()) {
+            if !path.exists() {
+                continue;
+            }
+
+            match rebuild(&source_folder, output_folder, &path, mirror) {
+                Ok(output) => { println!("compiled {}", output.display()); }
+                Err(err) => { println!("failed {}\n{}", path.display(), err); }
+            }
+        }
+    }
+}
+
+
+
+
+
+//// [ TESTS ] ////
+#[cfg(test)]
+mod tests {
+    use hamcrest2::prelude::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use super::{rebuild, Debouncer};
+
+    #[test]
+    fn test_debouncer_waits_for_quiet() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+
+        debouncer.add(PathBuf::from("a.txt"), start);
+        debouncer.add(PathBuf::from("a.txt"), start + Duration::from_millis(80));
+
+        assert_that!(debouncer.ready(start + Duration::from_millis(120)).len(), equal_to(0));
+        assert_that!(debouncer.next_wait(start + Duration::from_millis(120)), equal_to(Some(Duration::from_millis(60))));
+        assert_that!(debouncer.ready(start + Duration::from_millis(180)), equal_to(vec![PathBuf::from("a.txt")]));
+        assert_that!(debouncer.next_wait(start + Duration::from_millis(180)), equal_to(None));
+    }
+
+    #[test]
+    fn test_rebuild_mirrors_output() {
+        let root = std::env::temp_dir().join(format!("dgrlin-watch-{}", std::process::id()));
+        let source = root.join("src/ch1/e01_001_000.txt");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "Text(\"Hello\")\n").unwrap();
+
+        let output = rebuild(&root.join("src"), &root.join("out"), &source, Some(&root.join("game"))).unwrap();
+
+        assert_that!(output.clone(), equal_to(root.join("out/ch1/e01_001_000.lin")));
+        assert_that!(fs::read(root.join("game/ch1/e01_001_000.lin")).unwrap(), equal_to(fs::read(&output).unwrap()));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_keeps_dotted_names() {
+        let root = std::env::temp_dir().join(format!("dgrlin-watch-dotted-{}", std::process::id()));
+        let source = root.join("src/e01_001_000.v2.txt");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "Text(\"Hello\")\n").unwrap();
+
+        let output = rebuild(&root.join("src"), &root.join("out"), &source, None).unwrap();
+
+        assert_that!(output.clone(), equal_to(root.join("out/e01_001_000.v2.lin")));
+        assert_that!(output.exists(), is(true));
+        assert_that!(root.join("out/e01_001_000.lin").exists(), is(false));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -84,6 +84,9 @@ class Window:
             try:
                 dgrlin.compile(input_filename, output_folder)
                 self.update_log(f"File compiled: {input_filename}")
+            except dgrlin.ScriptSyntaxError as err:
+                for line, message in err.diagnostics:
+                    self.update_log(f"{input_filename} line {line}: {message}")
             except RuntimeError as err:
                 self.update_log(err.__str__().split("\n")[0])
             except:
@@ -123,6 +126,9 @@ class Window:
             try:
                 dgrlin.decompile(input_filename, output_folder)
                 self.update_log(f"File decompiled: {input_filename}")
+            except dgrlin.LinFormatError as err:
+                self.update_log(f"{input_filename} at byte {err.offset:#x}: {err}")
+                return
             except RuntimeError as err:
                 self.update_log(err.__str__().split("\n")[0])
                 return